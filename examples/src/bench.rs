@@ -0,0 +1,127 @@
+use std::time::{Duration, Instant};
+
+use fusen_net::{
+    buffer::TcpBuffer, connection, connection::Meter, quic, quic::support::make_server_endpoint,
+    tls::Verify,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::oneshot,
+};
+
+const QUIC_TOTAL: usize = 64 * 1024 * 1024;
+
+#[derive(Clone, Copy, Debug)]
+enum Pump {
+    Legacy,
+    Current,
+}
+
+#[tokio::main]
+async fn main() {
+    for pump in [Pump::Legacy, Pump::Current] {
+        let elapsed = bench_tcp_to_quic(pump).await;
+        report("tcp -> quic", pump, QUIC_TOTAL, elapsed);
+    }
+}
+
+fn report(name: &str, pump: Pump, total: usize, elapsed: Duration) {
+    println!(
+        "{:<12} {:<8} {:>8.1} MiB/s  ({:?})",
+        name,
+        format!("{:?}", pump),
+        total as f64 / 1024.0 / 1024.0 / elapsed.as_secs_f64(),
+        elapsed
+    );
+}
+
+async fn bench_tcp_to_quic(pump: Pump) -> Duration {
+    let endpoint = make_server_endpoint("127.0.0.1:0".parse().unwrap())
+        .unwrap()
+        .0;
+    let sink_addr = endpoint.local_addr().unwrap();
+    let (done_tx, done_rx) = oneshot::channel();
+    tokio::spawn(async move {
+        let connection = endpoint.accept().await.unwrap().await.unwrap();
+        let (send_stream, recv_stream) = connection.accept_bi().await.unwrap();
+        let _ = done_tx.send(drain(tokio::io::join(recv_stream, send_stream)).await);
+    });
+    let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let proxy_addr = proxy.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (source, _) = proxy.accept().await.unwrap();
+        let (quic_buffer, _) = quic::connect(sink_addr, &Verify::insecure()).await.unwrap();
+        match pump {
+            Pump::Legacy => {
+                let (_, recv_stream, send_stream) = quic_buffer.into_split();
+                legacy_pump(source, tokio::io::join(recv_stream, send_stream)).await
+            }
+            Pump::Current => {
+                let _ = connection::connect_tcp_to_stream(
+                    TcpBuffer::new(source),
+                    quic_buffer,
+                    Meter::default(),
+                )
+                .await;
+            }
+        }
+    });
+    run_source(proxy_addr, QUIC_TOTAL, done_rx).await
+}
+
+async fn run_source(
+    addr: std::net::SocketAddr,
+    total: usize,
+    done: oneshot::Receiver<usize>,
+) -> Duration {
+    let start = Instant::now();
+    let mut source = TcpStream::connect(addr).await.unwrap();
+    let chunk = vec![7u8; 64 * 1024];
+    let mut sent = 0;
+    while sent < total {
+        source.write_all(&chunk).await.unwrap();
+        sent += chunk.len();
+    }
+    source.shutdown().await.unwrap();
+    let received = done.await.unwrap();
+    assert_eq!(received, total);
+    start.elapsed()
+}
+
+async fn drain<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S) -> usize {
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut total = 0;
+    loop {
+        match stream.read(&mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(len) => total += len,
+        }
+    }
+    let _ = stream.shutdown().await;
+    total
+}
+
+/// The pump as it was before: a single 4 KiB buffer per side, alternating
+/// directions through `select!`.
+async fn legacy_pump<A, B>(mut a: A, mut b: B)
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut buf1 = vec![0u8; 4 * 1024];
+    let mut buf2 = vec![0u8; 4 * 1024];
+    loop {
+        tokio::select! {
+            res = a.read(&mut buf1) => match res {
+                Ok(0) | Err(_) => break,
+                Ok(len) => if b.write_all(&buf1[..len]).await.is_err() { return },
+            },
+            res = b.read(&mut buf2) => match res {
+                Ok(0) | Err(_) => break,
+                Ok(len) => if a.write_all(&buf2[..len]).await.is_err() { return },
+            },
+        }
+    }
+    let _ = b.shutdown().await;
+}
@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use fusen_net::server::cache::AsyncCache;
+use tokio::sync::{mpsc, oneshot};
+
+const TASKS: usize = 64;
+const OPS_PER_TASK: usize = 20_000;
+const KEYS: usize = 1024;
+
+#[derive(Clone, Copy, Debug)]
+enum Cache {
+    Legacy,
+    Current,
+}
+
+#[tokio::main]
+async fn main() {
+    for (name, writes) in [("read", 0), ("mixed", 10), ("write", 100)] {
+        for cache in [Cache::Legacy, Cache::Current] {
+            let elapsed = match cache {
+                Cache::Legacy => bench(LegacyCache::new(), writes).await,
+                Cache::Current => bench(AsyncCache::new(), writes).await,
+            };
+            report(name, cache, TASKS * OPS_PER_TASK, elapsed);
+        }
+    }
+}
+
+fn report(name: &str, cache: Cache, total: usize, elapsed: Duration) {
+    println!(
+        "{:<6} {:<8} {:>10.0} ops/s  ({:?})",
+        name,
+        format!("{:?}", cache),
+        total as f64 / elapsed.as_secs_f64(),
+        elapsed
+    );
+}
+
+trait Bench: Clone + Send + Sync + 'static {
+    fn get(&self, key: String) -> impl std::future::Future<Output = Option<Arc<String>>> + Send;
+    fn insert(
+        &self,
+        key: String,
+        value: Arc<String>,
+    ) -> impl std::future::Future<Output = ()> + Send;
+}
+
+impl Bench for AsyncCache<String, Arc<String>> {
+    async fn get(&self, key: String) -> Option<Arc<String>> {
+        AsyncCache::get(self, key).await.unwrap()
+    }
+
+    async fn insert(&self, key: String, value: Arc<String>) {
+        AsyncCache::insert(self, key, value).await.unwrap();
+    }
+}
+
+/// `writes` percent of the operations are inserts, the rest lookups, spread
+/// over `TASKS` tasks on the multi-threaded runtime.
+async fn bench<C: Bench>(cache: C, writes: usize) -> Duration {
+    let keys: Arc<Vec<String>> = Arc::new((0..KEYS).map(|i| format!("agent{}", i)).collect());
+    let value = Arc::new("127.0.0.1:8081".to_owned());
+    for key in keys.iter() {
+        cache.insert(key.clone(), value.clone()).await;
+    }
+    let start = Instant::now();
+    let tasks: Vec<_> = (0..TASKS)
+        .map(|task| {
+            let cache = cache.clone();
+            let keys = keys.clone();
+            let value = value.clone();
+            tokio::spawn(async move {
+                for op in 0..OPS_PER_TASK {
+                    let key = keys[(task * 7919 + op) % KEYS].clone();
+                    if op % 100 < writes {
+                        cache.insert(key, value.clone()).await;
+                    } else {
+                        assert!(cache.get(key).await.is_some());
+                    }
+                }
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await.unwrap();
+    }
+    start.elapsed()
+}
+
+enum Request {
+    Get(String, oneshot::Sender<Option<Arc<String>>>),
+    Insert(String, Arc<String>, oneshot::Sender<Option<Arc<String>>>),
+}
+
+/// The cache as it was before sharding: one task owning the map, serving
+/// requests over a channel.
+#[derive(Clone)]
+struct LegacyCache {
+    sender: mpsc::UnboundedSender<Request>,
+}
+
+impl LegacyCache {
+    fn new() -> Self {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut map = HashMap::new();
+            while let Some(request) = receiver.recv().await {
+                match request {
+                    Request::Get(key, reply) => {
+                        let _ = reply.send(map.get(&key).cloned());
+                    }
+                    Request::Insert(key, value, reply) => {
+                        let _ = reply.send(map.insert(key, value));
+                    }
+                }
+            }
+        });
+        LegacyCache { sender }
+    }
+}
+
+impl Bench for LegacyCache {
+    async fn get(&self, key: String) -> Option<Arc<String>> {
+        let (reply, receiver) = oneshot::channel();
+        self.sender.send(Request::Get(key, reply)).unwrap();
+        receiver.await.unwrap()
+    }
+
+    async fn insert(&self, key: String, value: Arc<String>) {
+        let (reply, receiver) = oneshot::channel();
+        self.sender
+            .send(Request::Insert(key, value, reply))
+            .unwrap();
+        receiver.await.unwrap();
+    }
+}
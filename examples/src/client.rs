@@ -1,10 +1,9 @@
-use std::time::Duration;
-
+use examples::config::{self, AgentConfig, ClientConfig, ConfigError};
 use examples::init_log;
-use fusen_net::{
-    client::{self, AgentInfo},
-    shutdown::ShutdownV2,
-};
+use fusen_net::client::{self, Agents, Servers};
+use fusen_net::transport::DefaultTransport;
+use fusen_net::{metrics, shutdown::ShutdownV2};
+use std::sync::Arc;
 use structopt::StructOpt;
 use tokio::sync::mpsc;
 use tracing::{debug, error, info};
@@ -13,54 +12,205 @@ use tracing::{debug, error, info};
 async fn main() {
     init_log();
     let cli = Cli::from_args();
-    let Some(server_host) = cli.server_host else {
-        error!("server_host must set");
-        return;
-    };
-    let Some(tag) = cli.tag else {
-        error!("tag must set");
-        return;
-    };
-    let (send, mut recv) = mpsc::channel(1);
-    let server_host_clone = server_host.clone();
+    if let Err(error) = run(cli).await {
+        error!("{}", error);
+    }
+}
+
+async fn run(cli: Cli) -> Result<(), ConfigError> {
+    let config = client_config(cli.clone())?;
+    let servers = Servers::from(config.server_host()?);
+    let tag = config.tag()?.to_owned();
+    let agent_infos = config.agents()?;
+    let agent_config = client::Config::new(config.settings()?);
+    let verify = config.verify()?;
+    if let Some(metrics_addr) = config.metrics_addr()?.map(str::to_owned) {
+        tokio::spawn(async move {
+            let res = metrics::serve(&metrics_addr).await;
+            error!("metrics end : {:?}", res);
+        });
+    }
+    let (send, mut recv) = mpsc::channel::<()>(1);
+    let servers_clone = servers.clone();
+    let tag_clone = tag.clone();
+    let register_config = agent_config.clone();
     let mut shutdown = ShutdownV2::default();
-    let send_clone = send.clone();
+    let transport = Arc::new(DefaultTransport::default().with_verify(verify.clone()));
     tokio::spawn(async move {
-        loop {
-            let res = tokio::select! {
-                res = client::register(server_host_clone.clone(), tag.clone()) => res,
-                _ = shutdown.recv() => {
-                    debug!("shutdown");
-                    break;
-                }
-            };
-            let _ = tokio::time::sleep(Duration::from_secs(10)).await;
-            info!("error : {:?} , try register again", res);
-        }
-        drop(send_clone);
+        tokio::select! {
+            res = client::register_with(transport, register_config, servers_clone, tag_clone) => {
+                info!("register end : {:?}", res)
+            }
+            _ = shutdown.recv() => debug!("shutdown"),
+        };
+        drop(send);
     });
-    for item in cli.agent {
-        let server_host = server_host.clone();
-        tokio::spawn(async move {
-            let agent_info: Vec<&str> = item.split('-').collect();
-            info!(
-                "start agent mode {} target_tag : {}  target_host : {} , local_port : {}",
-                agent_info[0], agent_info[1], agent_info[2], agent_info[3]
-            );
-            let err = client::agent(server_host, AgentInfo::from(item.as_str())).await;
-            info!("{:?}", err);
-        });
+    let transport = Arc::new(DefaultTransport::default().with_verify(verify));
+    let mut agents = Agents::with_transport(transport, servers).with_config(agent_config.clone());
+    agents.update(agent_infos).await;
+    let (reload_send, mut reload_recv) = mpsc::channel(1);
+    tokio::spawn(hangups(reload_send));
+    loop {
+        tokio::select! {
+            _ = recv.recv() => return Ok(()),
+            _ = reload_recv.recv() => match reload(&cli, &config, &agent_config, &mut agents).await {
+                Ok(()) => info!("config reloaded"),
+                Err(error) => error!("reload err : {}", error),
+            },
+        }
+    }
+}
+
+/// Applies the config again: agents, services, bandwidth and the tunnel timeout
+/// change in place, the token and labels on the next registration, the server
+/// host, tag, namespace and tls only after a restart.
+async fn reload(
+    cli: &Cli,
+    running: &ClientConfig,
+    agent_config: &client::Config,
+    agents: &mut Agents,
+) -> Result<(), ConfigError> {
+    let config = client_config(cli.clone())?;
+    if config.server_host()? != running.server_host()?
+        || config.tag()? != running.tag()?
+        || config.namespace != running.namespace
+        || config.tls != running.tls
+    {
+        info!("server_host, tag, namespace and tls changes apply after a restart");
+    }
+    let agent_infos = config.agents()?;
+    let mut settings = config.settings()?;
+    settings.namespace = running.namespace.clone();
+    agent_config.reload(settings);
+    agents.update(agent_infos).await;
+    Ok(())
+}
+
+/// Asks for a reload every time the process gets SIGHUP.
+#[cfg(unix)]
+async fn hangups(reload: mpsc::Sender<()>) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let Ok(mut hangup) = signal(SignalKind::hangup()) else {
+        return;
+    };
+    while hangup.recv().await.is_some() {
+        if reload.send(()).await.is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn hangups(_reload: mpsc::Sender<()>) {}
+
+/// The config file overridden by the environment and flags.
+fn client_config(cli: Cli) -> Result<ClientConfig, ConfigError> {
+    let mut config: ClientConfig = config::load(cli.config.as_deref())?;
+    overlay(&mut config.server_host, cli.server_host);
+    overlay(&mut config.tag, cli.tag);
+    overlay(&mut config.token, cli.token);
+    overlay(&mut config.namespace, cli.namespace);
+    overlay(&mut config.metrics_addr, cli.metrics_addr);
+    overlay(&mut config.bandwidth, cli.bandwidth);
+    overlay(&mut config.tunnel_timeout, cli.tunnel_timeout);
+    overlay(&mut config.tls.ca, cli.tls_ca);
+    if !cli.tls_pins.is_empty() {
+        config.tls.pins = cli.tls_pins;
+    }
+    config.tls.insecure |= cli.tls_insecure;
+    if !cli.services.is_empty() {
+        config.services = cli.services;
+    }
+    if !cli.labels.is_empty() {
+        config.labels = cli
+            .labels
+            .iter()
+            .map(|label| config::parse_label("label", label))
+            .collect::<Result<_, _>>()?;
+    }
+    if !cli.agent.is_empty() {
+        config.agents = split_agents(&cli.agent)
+            .iter()
+            .enumerate()
+            .map(|(index, agent)| AgentConfig::parse(&format!("agent[{}]", index), agent))
+            .collect::<Result<_, _>>()?;
+    }
+    Ok(config)
+}
+
+/// Splits comma separated agents, keeping the commas of label selectors: a
+/// piece that doesn't start with a mode belongs to the agent before it.
+fn split_agents(values: &[String]) -> Vec<String> {
+    let mut agents: Vec<String> = vec![];
+    for piece in values.iter().flat_map(|value| value.split(',')) {
+        let mode = piece.get(..5).unwrap_or(piece).to_ascii_lowercase();
+        let starts_agent = ["rm://", "dm://", "rm-", "dm-"]
+            .iter()
+            .any(|prefix| mode.starts_with(prefix));
+        match agents.last_mut() {
+            Some(agent) if !starts_agent => {
+                agent.push(',');
+                agent.push_str(piece);
+            }
+            _ => agents.push(piece.to_owned()),
+        }
+    }
+    agents
+}
+
+fn overlay<T>(value: &mut Option<T>, cli: Option<T>) {
+    if cli.is_some() {
+        *value = cli;
     }
-    drop(send);
-    let _: Option<i32> = recv.recv().await;
 }
 
-#[derive(StructOpt)]
+#[derive(Clone, StructOpt)]
 struct Cli {
-    #[structopt(short = "s", long = "server_host")]
+    /// TOML config file, overridden by environment variables and flags.
+    #[structopt(short = "c", long = "config", env = "CONFIG")]
+    config: Option<String>,
+    /// Server address, or several separated by `,` to register with all of
+    /// them and fail over tunnels to the next when one is unreachable.
+    #[structopt(short = "s", long = "server_host", env = "SERVER_HOST")]
     server_host: Option<String>,
-    #[structopt(short = "t", long = "tag")]
+    #[structopt(short = "t", long = "tag", env = "TAG")]
     tag: Option<String>,
-    #[structopt(short = "a", long = "agent")]
+    /// Token to register with, when the server defines this agent or its
+    /// namespace with one.
+    #[structopt(long = "token", env = "TOKEN")]
+    token: Option<String>,
+    /// Namespace the tag lives in, the default one when unset.
+    #[structopt(long = "namespace", env = "NAMESPACE")]
+    namespace: Option<String>,
+    /// `MODE-target_tag-target_host-port`, may be repeated or comma separated.
+    /// The target tag may be a label selector such as `env=prod,site=sh`.
+    #[structopt(short = "a", long = "agent", env = "AGENTS")]
     agent: Vec<String>,
+    /// `key=value` label other agents may select this client by, may be
+    /// repeated or comma separated.
+    #[structopt(long = "label", env = "LABELS", use_delimiter = true)]
+    labels: Vec<String>,
+    /// Target hosts other agents may reach through this client, may be repeated
+    /// or comma separated.
+    #[structopt(long = "service", env = "SERVICES", use_delimiter = true)]
+    services: Vec<String>,
+    #[structopt(long = "metrics_addr", env = "METRICS_ADDR")]
+    metrics_addr: Option<String>,
+    /// Bandwidth limit of all tunnels in bytes per second.
+    #[structopt(long = "bandwidth", env = "BANDWIDTH")]
+    bandwidth: Option<u64>,
+    /// Seconds to wait for the server to set an RM tunnel up, at least the
+    /// server's heartbeat_interval times heartbeat_missed, 10 by default.
+    #[structopt(long = "tunnel_timeout", env = "TUNNEL_TIMEOUT")]
+    tunnel_timeout: Option<u64>,
+    /// PEM file of the CAs to verify the servers with instead of the system's.
+    #[structopt(long = "tls_ca", env = "TLS_CA")]
+    tls_ca: Option<String>,
+    /// SHA-256 fingerprint of a server certificate to accept, as the server
+    /// logs it, may be repeated or comma separated.
+    #[structopt(long = "tls_pin", env = "TLS_PINS", use_delimiter = true)]
+    tls_pins: Vec<String>,
+    /// Accepts any server certificate, for self-signed servers on trusted networks.
+    #[structopt(long = "tls_insecure")]
+    tls_insecure: bool,
 }
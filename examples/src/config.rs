@@ -0,0 +1,593 @@
+//! TOML configuration of the server and client binaries. Values come from the
+//! file given with `--config`, then environment variables, then flags, each
+//! overriding the one before.
+
+use fusen_net::client::{self, AgentInfo, AgentMode, ParseAgentError};
+use fusen_net::selector::is_label;
+use fusen_net::server::namespace::DEFAULT_NAMESPACE;
+use fusen_net::server::registry::AgentDefinition;
+use fusen_net::server::{
+    Acl, Balance, BandwidthLimits, Cluster, ConnectionLimits, Heartbeat, Namespaces, Settings,
+};
+use fusen_net::tls::{Identity, Verify};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+/// A bad configuration value, with the key it was set under.
+#[derive(Debug)]
+pub struct ConfigError {
+    pub key: String,
+    pub message: String,
+}
+
+impl ConfigError {
+    fn new(key: impl Into<String>, message: impl fmt::Display) -> Self {
+        ConfigError {
+            key: key.into(),
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid config `{}` : {}", self.key, self.message)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Reads the config file at `path`, or the defaults without one.
+pub fn load<T: for<'de> Deserialize<'de> + Default>(path: Option<&str>) -> Result<T, ConfigError> {
+    let Some(path) = path else {
+        return Ok(T::default());
+    };
+    let content =
+        std::fs::read_to_string(path).map_err(|error| ConfigError::new("config", error))?;
+    toml::from_str(&content).map_err(|error| {
+        let key = error
+            .span()
+            .map(|span| key_at(&content, span.start))
+            .unwrap_or_else(|| "config".to_owned());
+        ConfigError::new(key, error.message())
+    })
+}
+
+/// The key on the line holding byte `offset`, qualified by its table.
+fn key_at(content: &str, offset: usize) -> String {
+    let mut table = String::new();
+    let mut key = String::new();
+    let mut position = 0;
+    for line in content.split_inclusive('\n') {
+        let trimmed = line.trim();
+        if let Some(name) = trimmed.strip_prefix('[') {
+            table = name
+                .trim_matches(|c| c == '[' || c == ']')
+                .trim()
+                .to_owned();
+            key.clear();
+        } else if let Some((name, _)) = trimmed.split_once('=') {
+            key = name.trim().to_owned();
+        }
+        position += line.len();
+        if position > offset {
+            break;
+        }
+    }
+    match (table.is_empty(), key.is_empty()) {
+        (true, _) => key,
+        (false, true) => table,
+        (false, false) => format!("{}.{}", table, key),
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub port: Option<String>,
+    /// Address the server port and the WebSocket port bind on.
+    pub bind: Option<String>,
+    pub tcp: bool,
+    pub ws_port: Option<String>,
+    pub heartbeat_interval: Option<u64>,
+    pub heartbeat_missed: Option<u32>,
+    /// Seconds to wait for open tunnels on shutdown before closing them.
+    pub drain_timeout: Option<u64>,
+    pub metrics_addr: Option<String>,
+    pub session_log: Option<String>,
+    pub tls: TlsConfig,
+    pub admin: AdminConfig,
+    pub limits: LimitsConfig,
+    /// Source tags allowed to reach each target tag, tags left out are open.
+    pub acl: HashMap<String, Vec<String>>,
+    /// `round_robin`, `least_connections` or `latency_weighted`, for tags
+    /// several agents register under.
+    pub balance: Option<String>,
+    /// SQLite database keeping the agents across restarts, in memory when unset.
+    pub registry: Option<String>,
+    /// Agents defined ahead of time, by tag, `namespace/tag` outside the
+    /// default namespace.
+    pub agents: HashMap<String, DefinitionConfig>,
+    /// Tenants, by name.
+    pub namespaces: HashMap<String, NamespaceConfig>,
+    pub cluster: ClusterConfig,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClusterConfig {
+    /// Address the other servers reach this one at.
+    pub advertise: Option<String>,
+    /// The other servers of the cluster.
+    pub peers: Vec<String>,
+    /// Secret every server of the cluster shares.
+    pub token: Option<String>,
+    /// How the peers are verified, trusting those sharing `tls.cert` or its CA
+    /// when unset.
+    pub tls: VerifyConfig,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NamespaceConfig {
+    /// Token agents join the namespace with, required but in `default`.
+    pub token: Option<String>,
+    /// Other namespaces allowed to reach the agents of this one.
+    pub allow: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DefinitionConfig {
+    /// Token the agent has to register with.
+    pub token: Option<String>,
+    /// Target hosts other agents may reach through it, any when empty.
+    pub services: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM certificate chain.
+    pub cert: Option<String>,
+    /// PEM private key.
+    pub key: Option<String>,
+}
+
+/// How a client verifies the certificate of the server it dials, against the
+/// system's CAs when nothing is set.
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VerifyConfig {
+    /// PEM file of the CAs to trust instead of the system's.
+    pub ca: Option<String>,
+    /// SHA-256 fingerprints of certificates to accept, as logged by the server.
+    pub pins: Vec<String>,
+    /// Accepts any certificate, for self-signed servers on trusted networks.
+    pub insecure: bool,
+}
+
+impl VerifyConfig {
+    /// The verification, `None` when nothing is set.
+    pub fn verify(&self, table: &str) -> Result<Option<Verify>, ConfigError> {
+        if self.insecure {
+            if self.ca.is_some() || !self.pins.is_empty() {
+                return Err(ConfigError::new(
+                    format!("{}.insecure", table),
+                    "must not be set with ca or pins",
+                ));
+            }
+            return Ok(Some(Verify::insecure()));
+        }
+        if self.ca.is_none() && self.pins.is_empty() {
+            return Ok(None);
+        }
+        let mut verify = Verify::default();
+        if let Some(ca) = &self.ca {
+            verify = verify
+                .with_ca_file(ca)
+                .map_err(|error| ConfigError::new(format!("{}.ca", table), error))?;
+        }
+        for pin in &self.pins {
+            verify = verify
+                .with_pin(pin)
+                .map_err(|error| ConfigError::new(format!("{}.pins", table), error))?;
+        }
+        Ok(Some(verify))
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    pub addr: Option<String>,
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub bandwidth_global: Option<u64>,
+    pub bandwidth_tunnel: Option<u64>,
+    /// Bandwidth limits by tag.
+    pub bandwidth_tags: HashMap<String, u64>,
+    pub max_tunnels: Option<usize>,
+    pub max_tunnels_per_source: Option<usize>,
+    pub max_tunnels_per_target: Option<usize>,
+    pub max_connections_per_ip: Option<u32>,
+}
+
+impl ServerConfig {
+    pub fn port(&self) -> Result<&str, ConfigError> {
+        let port = self.port.as_deref().unwrap_or("8089");
+        parse_port("port", port)?;
+        Ok(port)
+    }
+
+    pub fn bind(&self) -> Result<&str, ConfigError> {
+        let bind = self.bind.as_deref().unwrap_or("::");
+        bind.parse::<IpAddr>()
+            .map_err(|error| ConfigError::new("bind", error))?;
+        Ok(bind)
+    }
+
+    pub fn ws_port(&self) -> Result<Option<&str>, ConfigError> {
+        if let Some(ws_port) = &self.ws_port {
+            parse_port("ws_port", ws_port)?;
+        }
+        Ok(self.ws_port.as_deref())
+    }
+
+    pub fn heartbeat(&self) -> Result<Heartbeat, ConfigError> {
+        let mut heartbeat = Heartbeat::default();
+        if let Some(interval) = self.heartbeat_interval {
+            if interval == 0 {
+                return Err(ConfigError::new("heartbeat_interval", "must be positive"));
+            }
+            heartbeat.interval = Duration::from_secs(interval);
+        }
+        if let Some(max_missed) = self.heartbeat_missed {
+            if max_missed == 0 {
+                return Err(ConfigError::new("heartbeat_missed", "must be positive"));
+            }
+            heartbeat.max_missed = max_missed;
+        }
+        Ok(heartbeat)
+    }
+
+    pub fn drain_timeout(&self) -> Option<Duration> {
+        self.drain_timeout.map(Duration::from_secs)
+    }
+
+    pub fn metrics_addr(&self) -> Result<Option<&str>, ConfigError> {
+        parse_addr("metrics_addr", self.metrics_addr.as_deref())
+    }
+
+    pub fn identity(&self) -> Result<Option<Identity>, ConfigError> {
+        match (&self.tls.cert, &self.tls.key) {
+            (Some(cert), Some(key)) => Identity::from_pem_files(cert, key)
+                .map(Some)
+                .map_err(|error| ConfigError::new("tls", error)),
+            (Some(_), None) => Err(ConfigError::new("tls.key", "must be set with tls.cert")),
+            (None, Some(_)) => Err(ConfigError::new("tls.cert", "must be set with tls.key")),
+            (None, None) => Ok(None),
+        }
+    }
+
+    /// The cluster, when the server has peers.
+    pub fn cluster(&self) -> Result<Option<Cluster>, ConfigError> {
+        let cluster = &self.cluster;
+        if cluster.peers.is_empty() {
+            return Ok(None);
+        }
+        let advertise = match cluster.advertise.as_deref() {
+            Some("") | None => {
+                return Err(ConfigError::new(
+                    "cluster.advertise",
+                    "must be set with cluster.peers",
+                ))
+            }
+            Some(advertise) => advertise,
+        };
+        if cluster.peers.iter().any(|peer| peer == advertise) {
+            return Err(ConfigError::new(
+                "cluster.peers",
+                "must not include cluster.advertise",
+            ));
+        }
+        let token = match cluster.token.as_deref() {
+            Some("") | None => {
+                return Err(ConfigError::new(
+                    "cluster.token",
+                    "must be set with cluster.peers",
+                ))
+            }
+            Some(token) => token,
+        };
+        let mut cluster = Cluster::new(advertise, token).with_peers(cluster.peers.clone());
+        match self.cluster.tls.verify("cluster.tls")? {
+            Some(verify) => cluster = cluster.with_verify(verify),
+            None if self.tls.cert.is_none() => {
+                return Err(ConfigError::new(
+                    "cluster.tls",
+                    "must be set with cluster.peers unless tls.cert is",
+                ))
+            }
+            None => (),
+        }
+        Ok(Some(cluster))
+    }
+
+    /// The admin address and token, set together or not at all.
+    pub fn admin(&self) -> Result<Option<(&str, &str)>, ConfigError> {
+        let addr = parse_addr("admin.addr", self.admin.addr.as_deref())?;
+        match (addr, self.admin.token.as_deref()) {
+            (Some(_), Some("")) => Err(ConfigError::new("admin.token", "must not be empty")),
+            (Some(addr), Some(token)) => Ok(Some((addr, token))),
+            (Some(_), None) => Err(ConfigError::new(
+                "admin.token",
+                "must be set with admin.addr",
+            )),
+            (None, _) => Ok(None),
+        }
+    }
+
+    pub fn bandwidth(&self) -> BandwidthLimits {
+        let limits = &self.limits;
+        let mut bandwidth = BandwidthLimits::new()
+            .with_global(limits.bandwidth_global.unwrap_or_default())
+            .with_tunnel(limits.bandwidth_tunnel.unwrap_or_default());
+        for (tag, rate) in &limits.bandwidth_tags {
+            bandwidth = bandwidth.with_tag(tag, *rate);
+        }
+        bandwidth
+    }
+
+    pub fn acl(&self) -> Acl {
+        self.acl
+            .iter()
+            .fold(Acl::new(), |acl, (target_tag, source_tags)| {
+                acl.allow(target_tag, source_tags)
+            })
+    }
+
+    /// What a running server picks up again on reload.
+    pub fn settings(&self) -> Result<Settings, ConfigError> {
+        Ok(Settings {
+            bandwidth: self.bandwidth(),
+            connection_limits: self.connection_limits(),
+            acl: self.acl(),
+            balance: self.balance()?,
+            namespaces: self.namespaces()?,
+            admin_token: self.admin()?.map(|(_, token)| token.to_owned()),
+        })
+    }
+
+    pub fn balance(&self) -> Result<Balance, ConfigError> {
+        self.balance
+            .as_deref()
+            .map_or(Ok(Balance::default()), str::parse)
+            .map_err(|error| ConfigError::new("balance", error))
+    }
+
+    pub fn namespaces(&self) -> Result<Namespaces, ConfigError> {
+        let mut namespaces = Namespaces::new();
+        for (name, namespace) in &self.namespaces {
+            if name.is_empty() || name.contains('/') {
+                return Err(ConfigError::new(
+                    format!("namespaces.{}", name),
+                    "must not be empty or contain `/`",
+                ));
+            }
+            let key = format!("namespaces.{}.token", name);
+            match (name.as_str(), namespace.token.as_deref()) {
+                (DEFAULT_NAMESPACE, Some(_)) => {
+                    return Err(ConfigError::new(key, "the default namespace is open"))
+                }
+                (DEFAULT_NAMESPACE, None) => (),
+                (_, Some("") | None) => return Err(ConfigError::new(key, "must be set")),
+                (_, Some(token)) => namespaces = namespaces.with_namespace(name, token),
+            }
+            namespaces = namespaces.grant(name, &namespace.allow);
+        }
+        Ok(namespaces)
+    }
+
+    pub fn definitions(&self) -> Vec<AgentDefinition> {
+        self.agents
+            .iter()
+            .map(|(tag, definition)| AgentDefinition {
+                tag: tag.clone(),
+                token: definition.token.clone(),
+                services: definition.services.clone(),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    pub fn connection_limits(&self) -> ConnectionLimits {
+        ConnectionLimits {
+            max_tunnels: self.limits.max_tunnels,
+            max_tunnels_per_source: self.limits.max_tunnels_per_source,
+            max_tunnels_per_target: self.limits.max_tunnels_per_target,
+            max_connections_per_ip: self.limits.max_connections_per_ip,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientConfig {
+    pub server_host: Option<String>,
+    pub tag: Option<String>,
+    /// Token to register with, when the server defines the agent or its
+    /// namespace with one.
+    pub token: Option<String>,
+    /// Namespace the client's tag lives in, the default one when unset.
+    pub namespace: Option<String>,
+    pub metrics_addr: Option<String>,
+    /// Bandwidth limit of all tunnels in bytes per second.
+    pub bandwidth: Option<u64>,
+    /// Seconds to wait for the server to set an RM tunnel up, at least the
+    /// server's heartbeat interval times heartbeat_missed.
+    pub tunnel_timeout: Option<u64>,
+    /// Target hosts other agents may reach through this client, any when empty.
+    pub services: Vec<String>,
+    /// Labels other agents may select this client by, such as `env = "prod"`.
+    pub labels: HashMap<String, String>,
+    pub tls: VerifyConfig,
+    pub agents: Vec<AgentConfig>,
+}
+
+/// A local port forwarded to a target host behind another agent.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AgentConfig {
+    pub mode: String,
+    pub target_tag: String,
+    pub target_host: String,
+    pub port: String,
+    /// Local host to listen on, all interfaces by default.
+    pub bind: Option<String>,
+    /// Bandwidth limit of this agent's tunnels in bytes per second.
+    pub bandwidth: Option<u64>,
+    /// Namespace of the target, the client's own when unset.
+    pub target_namespace: Option<String>,
+}
+
+impl AgentConfig {
+    /// Parses the `--agent` flag, either `MODE-target_tag-target_host-port`
+    /// or `mode://target_tag/target_host?listen=[host:]port`.
+    pub fn parse(key: &str, value: &str) -> Result<Self, ConfigError> {
+        let agent_info: AgentInfo = value
+            .parse()
+            .map_err(|error: ParseAgentError| ConfigError::new(key, error))?;
+        Ok(AgentConfig {
+            mode: agent_info.agent_mode.as_str().to_owned(),
+            target_tag: agent_info.target_tag,
+            target_host: agent_info.target_host,
+            port: agent_info.agent_port,
+            bind: Some(agent_info.agent_host),
+            bandwidth: agent_info.rate_limit.map(|rate_limit| rate_limit.rate()),
+            target_namespace: agent_info.target_namespace,
+        })
+    }
+
+    fn agent_info(&self, key: &str) -> Result<AgentInfo, ConfigError> {
+        let agent_mode: AgentMode = self.mode.parse().map_err(|error: ParseAgentError| {
+            ConfigError::new(format!("{}.mode", key), error.message())
+        })?;
+        let mut agent_info =
+            AgentInfo::new(agent_mode, &self.target_tag, &self.target_host, &self.port);
+        if let Some(bind) = &self.bind {
+            agent_info = agent_info.with_agent_host(bind);
+        }
+        if let Some(target_namespace) = &self.target_namespace {
+            agent_info = agent_info.with_target_namespace(target_namespace);
+        }
+        agent_info.validate().map_err(|error| {
+            let field = match error.field() {
+                "agent_host" => "bind",
+                "agent_port" => "port",
+                field => field,
+            };
+            ConfigError::new(format!("{}.{}", key, field), error.message())
+        })?;
+        if let Some(bandwidth) = self.bandwidth {
+            agent_info = agent_info.with_rate_limit(bandwidth);
+        }
+        Ok(agent_info)
+    }
+}
+
+impl ClientConfig {
+    pub fn server_host(&self) -> Result<&str, ConfigError> {
+        match self.server_host.as_deref() {
+            Some("") | None => Err(ConfigError::new("server_host", "must be set")),
+            Some(server_host) => Ok(server_host),
+        }
+    }
+
+    pub fn tag(&self) -> Result<&str, ConfigError> {
+        match self.tag.as_deref() {
+            Some("") | None => Err(ConfigError::new("tag", "must be set")),
+            Some(tag) => Ok(tag),
+        }
+    }
+
+    pub fn metrics_addr(&self) -> Result<Option<&str>, ConfigError> {
+        parse_addr("metrics_addr", self.metrics_addr.as_deref())
+    }
+
+    /// How the servers are verified, against the system's CAs by default.
+    pub fn verify(&self) -> Result<Verify, ConfigError> {
+        Ok(self.tls.verify("tls")?.unwrap_or_default())
+    }
+
+    pub fn labels(&self) -> Result<HashMap<String, String>, ConfigError> {
+        for (key, value) in &self.labels {
+            if !is_label(key) || !is_label(value) {
+                return Err(ConfigError::new(
+                    format!("labels.{}", key),
+                    "keys and values must not be empty or contain spaces or any of `=!,/&?`",
+                ));
+            }
+        }
+        Ok(self.labels.clone())
+    }
+
+    /// What a running client picks up again on reload.
+    pub fn settings(&self) -> Result<client::Settings, ConfigError> {
+        let default = client::Settings::default();
+        Ok(client::Settings {
+            token: self.token.clone(),
+            namespace: self.namespace.clone(),
+            labels: self.labels()?,
+            services: self.services.clone(),
+            bandwidth: self.bandwidth.unwrap_or_default(),
+            tunnel_timeout: self
+                .tunnel_timeout
+                .map_or(default.tunnel_timeout, Duration::from_secs),
+        })
+    }
+
+    /// The agents, tagged with the client tag.
+    pub fn agents(&self) -> Result<Vec<AgentInfo>, ConfigError> {
+        let tag = self.tag()?;
+        self.agents
+            .iter()
+            .enumerate()
+            .map(|(index, agent)| {
+                let agent_info = agent.agent_info(&format!("agents[{}]", index))?;
+                Ok(agent_info.with_agent_tag(tag))
+            })
+            .collect()
+    }
+}
+
+/// Parses the `--label` flag, `key=value`.
+pub fn parse_label(key: &str, value: &str) -> Result<(String, String), ConfigError> {
+    match value.split_once('=') {
+        Some((label, value)) => Ok((label.to_owned(), value.to_owned())),
+        None => Err(ConfigError::new(
+            key,
+            format!("expected key=value, got `{}`", value),
+        )),
+    }
+}
+
+fn parse_port(key: &str, port: &str) -> Result<u16, ConfigError> {
+    port.parse()
+        .map_err(|_| ConfigError::new(key, format!("not a port number : {}", port)))
+}
+
+fn parse_addr<'a>(key: &str, addr: Option<&'a str>) -> Result<Option<&'a str>, ConfigError> {
+    if let Some(addr) = addr {
+        addr.parse::<SocketAddr>()
+            .map_err(|error| ConfigError::new(key, format!("{} : {}", error, addr)))?;
+    }
+    Ok(addr)
+}
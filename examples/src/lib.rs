@@ -1,4 +1,5 @@
 use tracing_subscriber::fmt::writer::MakeWriterExt;
+pub mod config;
 
 pub fn init_log() {
     let stdout = std::io::stdout.with_max_level(tracing::Level::DEBUG);
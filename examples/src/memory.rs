@@ -0,0 +1,50 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use examples::init_log;
+use fusen_net::{
+    client::{self, AgentInfo, AgentMode, Config},
+    server,
+    transport::memory::MemoryTransport,
+};
+use tokio::sync::mpsc;
+
+#[tokio::main(worker_threads = 512)]
+async fn main() {
+    init_log();
+    // The in-memory network carries no TLS, so there is no certificate to
+    // verify or pin, unlike with the default transport in test.rs.
+    let network = MemoryTransport::new();
+    let server = server::Server::new("8089").with_transport(network.clone());
+    tokio::spawn(async move {
+        let _ = server.start().await;
+        println!("server end");
+    });
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    let transport = Arc::new(network.host());
+    tokio::spawn(async move {
+        let error = client::register_with(
+            transport,
+            Config::default(),
+            "127.0.0.1:8089".to_owned(),
+            "agent1".to_owned(),
+        )
+        .await;
+        println!("error1 -- {:?}", error);
+    });
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    let transport = Arc::new(network.host());
+    tokio::spawn(async move {
+        let error = client::agent_with(
+            transport,
+            Config::default(),
+            "127.0.0.1:8089".to_owned(),
+            AgentInfo::new(AgentMode::RM, "agent1", "127.0.0.1:8081", "8078"),
+        )
+        .await;
+        println!("error2 -- {:?}", error);
+    });
+
+    let mut m = mpsc::channel(1);
+    let _: Option<i32> = m.1.recv().await;
+}
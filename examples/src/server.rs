@@ -1,18 +1,237 @@
+use examples::config::{self, ConfigError, ServerConfig};
 use examples::init_log;
-use fusen_net::server;
+use fusen_net::server::registry::{MemoryRegistry, Registry, SqliteRegistry};
+use fusen_net::server::Server;
 use structopt::StructOpt;
+use tracing::error;
 
 #[tokio::main(worker_threads = 512)]
 async fn main() {
     init_log();
     let cli = Cli::from_args();
-    let port = cli.port.as_deref().unwrap_or("8089");
-    let server = server::Server::new(port);
-    let _ = server.start().await;
+    let server = match server_config(cli.clone()).and_then(|config| build(&config, cli)) {
+        Ok(server) => server,
+        Err(error) => {
+            error!("{}", error);
+            return;
+        }
+    };
+    if let Err(error) = server.start().await {
+        error!("{}", error);
+    }
 }
 
-#[derive(StructOpt)]
+/// The config file overridden by the environment and flags.
+fn server_config(cli: Cli) -> Result<ServerConfig, ConfigError> {
+    let mut config: ServerConfig = config::load(cli.config.as_deref())?;
+    overlay(&mut config.port, cli.port);
+    overlay(&mut config.bind, cli.bind);
+    config.tcp |= cli.tcp;
+    overlay(&mut config.ws_port, cli.ws_port);
+    overlay(&mut config.heartbeat_interval, cli.heartbeat_interval);
+    overlay(&mut config.heartbeat_missed, cli.heartbeat_missed);
+    overlay(&mut config.drain_timeout, cli.drain_timeout);
+    overlay(&mut config.metrics_addr, cli.metrics_addr);
+    overlay(&mut config.session_log, cli.session_log);
+    overlay(&mut config.registry, cli.registry);
+    overlay(&mut config.balance, cli.balance);
+    overlay(&mut config.tls.cert, cli.tls_cert);
+    overlay(&mut config.tls.key, cli.tls_key);
+    overlay(&mut config.admin.addr, cli.admin_addr);
+    overlay(&mut config.admin.token, cli.admin_token);
+    overlay(&mut config.cluster.advertise, cli.advertise);
+    overlay(&mut config.cluster.token, cli.cluster_token);
+    overlay(&mut config.cluster.tls.ca, cli.cluster_tls_ca);
+    if !cli.cluster_tls_pins.is_empty() {
+        config.cluster.tls.pins = cli.cluster_tls_pins;
+    }
+    config.cluster.tls.insecure |= cli.cluster_tls_insecure;
+    if !cli.peers.is_empty() {
+        config.cluster.peers = cli.peers;
+    }
+    let limits = &mut config.limits;
+    overlay(&mut limits.bandwidth_global, cli.bandwidth_global);
+    overlay(&mut limits.bandwidth_tunnel, cli.bandwidth_tunnel);
+    for item in &cli.bandwidth_tag {
+        let rate = item
+            .split_once('=')
+            .and_then(|(tag, rate)| Some((tag, rate.parse().ok()?)));
+        let Some((tag, rate)) = rate else {
+            return Err(ConfigError {
+                key: "bandwidth_tag".into(),
+                message: format!("expected tag=rate, got {}", item),
+            });
+        };
+        limits.bandwidth_tags.insert(tag.to_owned(), rate);
+    }
+    overlay(&mut limits.max_tunnels, cli.max_tunnels);
+    overlay(
+        &mut limits.max_tunnels_per_source,
+        cli.max_tunnels_per_source,
+    );
+    overlay(
+        &mut limits.max_tunnels_per_target,
+        cli.max_tunnels_per_target,
+    );
+    overlay(
+        &mut limits.max_connections_per_ip,
+        cli.max_connections_per_ip,
+    );
+    Ok(config)
+}
+
+fn overlay<T>(value: &mut Option<T>, cli: Option<T>) {
+    if cli.is_some() {
+        *value = cli;
+    }
+}
+
+fn build(config: &ServerConfig, cli: Cli) -> Result<Server, ConfigError> {
+    let settings = config.settings()?;
+    let mut server = Server::new(config.port()?)
+        .with_bind(config.bind()?)
+        .with_heartbeat(config.heartbeat()?)
+        .with_bandwidth(settings.bandwidth)
+        .with_connection_limits(settings.connection_limits)
+        .with_acl(settings.acl)
+        .with_balance(settings.balance)
+        .with_namespaces(settings.namespaces);
+    if config.tcp {
+        server = server.with_tcp();
+    }
+    if let Some(drain_timeout) = config.drain_timeout() {
+        server = server.with_drain_timeout(drain_timeout);
+    }
+    if let Some(ws_port) = config.ws_port()? {
+        server = server.with_ws(ws_port);
+    }
+    if let Some(identity) = config.identity()? {
+        server = server.with_identity(identity);
+    }
+    if let Some(cluster) = config.cluster()? {
+        server = server.with_cluster(cluster);
+    }
+    if let Some((addr, token)) = config.admin()? {
+        server = server.with_admin(addr, token);
+    }
+    if let Some(metrics_addr) = config.metrics_addr()? {
+        server = server.with_metrics(metrics_addr);
+    }
+    if let Some(session_log) = &config.session_log {
+        server = server
+            .with_session_log(session_log)
+            .map_err(|error| ConfigError {
+                key: "session_log".into(),
+                message: error.to_string(),
+            })?;
+    }
+    server = match &config.registry {
+        Some(path) => {
+            let registry_error = |error: fusen_net::Error| ConfigError {
+                key: "registry".into(),
+                message: error.to_string(),
+            };
+            let registry = SqliteRegistry::open(path).map_err(registry_error)?;
+            for definition in config.definitions() {
+                registry.define(definition).map_err(registry_error)?;
+            }
+            server.with_registry(registry)
+        }
+        None => server.with_registry(
+            config
+                .definitions()
+                .into_iter()
+                .fold(MemoryRegistry::new(), MemoryRegistry::with_definition),
+        ),
+    };
+    // Reloads read the file again, still overridden by the environment and
+    // flags. Only the settings apply to the running server.
+    let server = server.with_reloader(move || {
+        let settings = server_config(cli.clone())?.settings()?;
+        Ok(settings)
+    });
+    Ok(server)
+}
+
+#[derive(Clone, StructOpt)]
 struct Cli {
-    #[structopt(short = "p", long = "port")]
+    /// TOML config file, overridden by environment variables and flags.
+    #[structopt(short = "c", long = "config", env = "CONFIG")]
+    config: Option<String>,
+    #[structopt(short = "p", long = "port", env = "PORT")]
     port: Option<String>,
+    #[structopt(long = "bind", env = "BIND")]
+    bind: Option<String>,
+    #[structopt(long = "tcp")]
+    tcp: bool,
+    #[structopt(long = "ws_port", env = "WS_PORT")]
+    ws_port: Option<String>,
+    #[structopt(long = "heartbeat_interval", env = "HEARTBEAT_INTERVAL")]
+    heartbeat_interval: Option<u64>,
+    #[structopt(long = "heartbeat_missed", env = "HEARTBEAT_MISSED")]
+    heartbeat_missed: Option<u32>,
+    /// Seconds to wait on ctrl-c for open tunnels to end before closing them.
+    #[structopt(long = "drain_timeout", env = "DRAIN_TIMEOUT")]
+    drain_timeout: Option<u64>,
+    #[structopt(long = "tls_cert", env = "TLS_CERT")]
+    tls_cert: Option<String>,
+    #[structopt(long = "tls_key", env = "TLS_KEY")]
+    tls_key: Option<String>,
+    #[structopt(long = "admin_addr", env = "ADMIN_ADDR")]
+    admin_addr: Option<String>,
+    #[structopt(long = "admin_token", env = "ADMIN_TOKEN")]
+    admin_token: Option<String>,
+    #[structopt(long = "metrics_addr", env = "METRICS_ADDR")]
+    metrics_addr: Option<String>,
+    #[structopt(long = "session_log", env = "SESSION_LOG")]
+    session_log: Option<String>,
+    /// Address the other servers of the cluster reach this one at.
+    #[structopt(long = "advertise", env = "ADVERTISE")]
+    advertise: Option<String>,
+    /// The other servers of the cluster, may be repeated or comma separated.
+    #[structopt(long = "peer", env = "PEERS", use_delimiter = true)]
+    peers: Vec<String>,
+    /// Secret every server of the cluster shares.
+    #[structopt(long = "cluster_token", env = "CLUSTER_TOKEN")]
+    cluster_token: Option<String>,
+    /// PEM file of the CAs to verify the peers with, instead of trusting
+    /// those sharing --tls_cert or its CA.
+    #[structopt(long = "cluster_tls_ca", env = "CLUSTER_TLS_CA")]
+    cluster_tls_ca: Option<String>,
+    /// SHA-256 fingerprint of a peer certificate to accept, may be repeated
+    /// or comma separated.
+    #[structopt(
+        long = "cluster_tls_pin",
+        env = "CLUSTER_TLS_PINS",
+        use_delimiter = true
+    )]
+    cluster_tls_pins: Vec<String>,
+    /// Accepts any peer certificate, for servers without --tls_cert on a
+    /// trusted network.
+    #[structopt(long = "cluster_tls_insecure")]
+    cluster_tls_insecure: bool,
+    /// SQLite database keeping the agents across restarts.
+    #[structopt(long = "registry", env = "REGISTRY")]
+    registry: Option<String>,
+    /// How tunnels spread over agents sharing a tag: `round_robin`,
+    /// `least_connections` or `latency_weighted`.
+    #[structopt(long = "balance", env = "BALANCE")]
+    balance: Option<String>,
+    /// Bandwidth limits in bytes per second.
+    #[structopt(long = "bandwidth_global", env = "BANDWIDTH_GLOBAL")]
+    bandwidth_global: Option<u64>,
+    #[structopt(long = "bandwidth_tunnel", env = "BANDWIDTH_TUNNEL")]
+    bandwidth_tunnel: Option<u64>,
+    /// `tag=rate`, may be repeated.
+    #[structopt(long = "bandwidth_tag")]
+    bandwidth_tag: Vec<String>,
+    #[structopt(long = "max_tunnels", env = "MAX_TUNNELS")]
+    max_tunnels: Option<usize>,
+    #[structopt(long = "max_tunnels_per_source", env = "MAX_TUNNELS_PER_SOURCE")]
+    max_tunnels_per_source: Option<usize>,
+    #[structopt(long = "max_tunnels_per_target", env = "MAX_TUNNELS_PER_TARGET")]
+    max_tunnels_per_target: Option<usize>,
+    /// New tunnels per second per remote ip.
+    #[structopt(long = "max_connections_per_ip", env = "MAX_CONNECTIONS_PER_IP")]
+    max_connections_per_ip: Option<u32>,
 }
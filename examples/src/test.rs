@@ -1,30 +1,45 @@
+use std::sync::Arc;
 use std::time::Duration;
 
 use examples::init_log;
 use fusen_net::{
-    client::{self, AgentInfo},
+    client::{self, AgentInfo, AgentMode, Config},
     server,
+    tls::{Identity, Verify},
+    transport::DefaultTransport,
 };
 use tokio::sync::mpsc;
 
 #[tokio::main(worker_threads = 512)]
 async fn main() {
     init_log();
-    let server = server::Server::new("8089");
+    // The clients pin the server's self-signed certificate.
+    let identity = Identity::self_signed().unwrap();
+    let transport = Arc::new(DefaultTransport::default().with_verify(Verify::identity(&identity)));
+    let server = server::Server::new("8089").with_identity(identity);
     tokio::spawn(async move {
         let _ = server.start().await;
         println!("server end");
     });
     tokio::time::sleep(Duration::from_secs(1)).await;
+    let register_transport = transport.clone();
     tokio::spawn(async move {
-        let error = client::register("127.0.0.1:8089".to_owned(), "agent1".to_owned()).await;
+        let error = client::register_with(
+            register_transport,
+            Config::default(),
+            "127.0.0.1:8089".to_owned(),
+            "agent1".to_owned(),
+        )
+        .await;
         println!("error1 -- {:?}", error);
     });
     tokio::time::sleep(Duration::from_secs(1)).await;
     tokio::spawn(async move {
-        let error = client::agent(
+        let error = client::agent_with(
+            transport,
+            Config::default(),
             "127.0.0.1:8089".to_owned(),
-            AgentInfo::from("RM-agent1-127.0.0.1:8081-8078"),
+            AgentInfo::new(AgentMode::RM, "agent1", "127.0.0.1:8081", "8078"),
         )
         .await;
         println!("error2 -- {:?}", error);
@@ -1,24 +1,42 @@
-use crate::frame::Frame;
+use crate::frame::{self, Frame};
+use crate::metrics::metrics;
 use bytes::{Buf, BytesMut};
 use quinn::{RecvStream, SendStream};
 use std::fmt::Debug;
 use std::io::Cursor;
+use std::pin::Pin;
+use std::task::{Context, Poll};
 use std::time::Duration;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt, BufWriter},
-    net::TcpStream,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter, ReadBuf},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
 };
 pub struct TcpBuffer {
     stream: BufWriter<TcpStream>,
     buffer: BytesMut,
 }
 
-pub struct QuicBuffer {
-    send_stream: SendStream,
-    recv_stram: RecvStream,
+/// A framed bidirectional stream to the server or a peer, carried by a QUIC
+/// stream or, for the fallback transports, any other byte stream.
+pub struct StreamBuffer {
+    send_stream: SendHalf,
+    recv_stram: RecvHalf,
     buffer: BytesMut,
 }
 
+pub enum RecvHalf {
+    Quic(RecvStream),
+    Stream(Box<dyn AsyncRead + Send + Sync + Unpin>),
+}
+
+pub enum SendHalf {
+    Quic(SendStream),
+    Stream(Box<dyn AsyncWrite + Send + Sync + Unpin>),
+}
+
 impl Debug for TcpBuffer {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Buffer")
@@ -38,25 +56,26 @@ impl TcpBuffer {
 }
 
 impl TcpBuffer {
-    pub async fn read_buf(&mut self) -> Result<&BytesMut, crate::Error> {
-        self.buffer.clear();
-        if 0 == self.stream.read_buf(&mut self.buffer).await? {
-            return Err("connection reset by peer".into());
-        }
-        Ok(&self.buffer)
-    }
-
-    pub async fn write_buf(&mut self, buf: &BytesMut) -> Result<(), crate::Error> {
-        self.stream.write_all(buf.chunk()).await?;
-        self.stream.flush().await.map_err(|e| e.into())
+    /// Splits into the bytes already read but not yet consumed as frames,
+    /// the read half and the write half of the socket.
+    pub fn into_split(self) -> (BytesMut, OwnedReadHalf, OwnedWriteHalf) {
+        let (read_half, write_half) = self.stream.into_inner().into_split();
+        (self.buffer, read_half, write_half)
     }
 
     pub async fn read_frame(&mut self) -> Result<Frame, crate::Error> {
         loop {
             let mut buf = Cursor::new(&self.buffer[..]);
-            if let Ok(frame) = Frame::parse(&mut buf) {
-                self.buffer.advance(buf.position() as usize);
-                return Ok(frame);
+            match Frame::parse(&mut buf) {
+                Ok(frame) => {
+                    self.buffer.advance(buf.position() as usize);
+                    return Ok(frame);
+                }
+                Err(frame::Error::Incomplete) => (),
+                Err(frame::Error::Other(error)) => {
+                    metrics().frame_parse_errors.inc();
+                    return Err(error);
+                }
             }
             if 0 == self.stream.read_buf(&mut self.buffer).await? {
                 return Err("connection reset by peer".into());
@@ -79,9 +98,9 @@ impl TcpBuffer {
     }
 }
 
-impl Debug for QuicBuffer {
+impl Debug for StreamBuffer {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("QuicBuffer")
+        f.debug_struct("StreamBuffer")
             .field("send_stream", &"...")
             .field("recv_stram", &"...")
             .field("buffer", &"...")
@@ -89,36 +108,48 @@ impl Debug for QuicBuffer {
     }
 }
 
-impl QuicBuffer {
+impl StreamBuffer {
     pub fn new(send_stream: SendStream, recv_stram: RecvStream) -> Self {
-        QuicBuffer {
-            send_stream,
-            recv_stram,
+        StreamBuffer {
+            send_stream: SendHalf::Quic(send_stream),
+            recv_stram: RecvHalf::Quic(recv_stram),
             buffer: BytesMut::with_capacity(4 * 1024),
         }
     }
-}
 
-impl QuicBuffer {
-    pub async fn read_buf(&mut self) -> Result<&BytesMut, crate::Error> {
-        self.buffer.clear();
-        if 0 == self.recv_stram.read_buf(&mut self.buffer).await? {
-            return Err("connection reset by peer".into());
+    pub fn from_stream<S>(stream: S) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Send + Sync + 'static,
+    {
+        let (recv_stram, send_stream) = tokio::io::split(stream);
+        StreamBuffer {
+            send_stream: SendHalf::Stream(Box::new(send_stream)),
+            recv_stram: RecvHalf::Stream(Box::new(recv_stram)),
+            buffer: BytesMut::with_capacity(4 * 1024),
         }
-        Ok(&self.buffer)
     }
+}
 
-    pub async fn write_buf(&mut self, buf: &BytesMut) -> Result<(), crate::Error> {
-        self.send_stream.write_all(buf.chunk()).await?;
-        self.send_stream.flush().await.map_err(|e| e.into())
+impl StreamBuffer {
+    /// Splits into the bytes already read but not yet consumed as frames,
+    /// the receive half and the send half.
+    pub fn into_split(self) -> (BytesMut, RecvHalf, SendHalf) {
+        (self.buffer, self.recv_stram, self.send_stream)
     }
 
     pub async fn read_frame(&mut self) -> Result<Frame, crate::Error> {
         loop {
             let mut buf = Cursor::new(&self.buffer[..]);
-            if let Ok(frame) = Frame::parse(&mut buf) {
-                self.buffer.advance(buf.position() as usize);
-                return Ok(frame);
+            match Frame::parse(&mut buf) {
+                Ok(frame) => {
+                    self.buffer.advance(buf.position() as usize);
+                    return Ok(frame);
+                }
+                Err(frame::Error::Incomplete) => (),
+                Err(frame::Error::Other(error)) => {
+                    metrics().frame_parse_errors.inc();
+                    return Err(error);
+                }
             }
             if 0 == self.recv_stram.read_buf(&mut self.buffer).await? {
                 return Err("connection reset by peer".into());
@@ -140,3 +171,43 @@ impl QuicBuffer {
         self.send_stream.flush().await.map_err(|e| e.into())
     }
 }
+
+impl AsyncRead for RecvHalf {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            RecvHalf::Quic(stream) => Pin::new(stream).poll_read(cx, buf),
+            RecvHalf::Stream(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for SendHalf {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            SendHalf::Quic(stream) => Pin::new(stream).poll_write(cx, buf),
+            SendHalf::Stream(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            SendHalf::Quic(stream) => Pin::new(stream).poll_flush(cx),
+            SendHalf::Stream(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            SendHalf::Quic(stream) => Pin::new(stream).poll_shutdown(cx),
+            SendHalf::Stream(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
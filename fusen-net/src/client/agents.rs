@@ -0,0 +1,99 @@
+use super::{agent_with, AgentInfo, Config, Servers};
+use crate::limit::RateLimit;
+use crate::transport::{DefaultTransport, Transport};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::task::JoinHandle;
+use tracing::info;
+
+/// The agents of a client, started and stopped as the list of them changes.
+/// A stopped agent closes its listener at once, while the tunnels it already
+/// opened run to their end.
+pub struct Agents {
+    transport: Arc<dyn Transport>,
+    config: Config,
+    servers: Servers,
+    running: HashMap<String, Running>,
+}
+
+struct Running {
+    rate_limit: Arc<RateLimit>,
+    task: JoinHandle<()>,
+}
+
+impl Agents {
+    pub fn new(servers: impl Into<Servers>) -> Self {
+        Self::with_transport(Arc::new(DefaultTransport::default()), servers)
+    }
+
+    pub fn with_transport(transport: Arc<dyn Transport>, servers: impl Into<Servers>) -> Self {
+        Agents {
+            transport,
+            config: Config::default(),
+            servers: servers.into(),
+            running: HashMap::new(),
+        }
+    }
+
+    /// Runs the agents with `config` instead of the default one.
+    pub fn with_config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Runs exactly `agents`: stops the running ones left out, starts the new
+    /// ones and moves the kept ones to their new bandwidth limit.
+    pub async fn update(&mut self, agents: Vec<AgentInfo>) {
+        let agents: HashMap<String, AgentInfo> = agents
+            .into_iter()
+            .map(|agent_info| (key(&agent_info), agent_info))
+            .collect();
+        let removed: Vec<String> = self
+            .running
+            .keys()
+            .filter(|key| !agents.contains_key(*key))
+            .cloned()
+            .collect();
+        for key in removed {
+            let running = self.running.remove(&key).unwrap();
+            running.task.abort();
+            // Waits for the listener to close so that its port can be reused.
+            let _ = running.task.await;
+            info!("agent stopped : {}", key);
+        }
+        for (key, mut agent_info) in agents {
+            let rate = agent_info
+                .rate_limit
+                .as_ref()
+                .map_or(0, |limit| limit.rate());
+            if let Some(running) = self.running.get(&key) {
+                running.rate_limit.set_rate(rate);
+                continue;
+            }
+            let rate_limit = Arc::new(RateLimit::new(rate));
+            agent_info.rate_limit = Some(rate_limit.clone());
+            let transport = self.transport.clone();
+            let config = self.config.clone();
+            let servers = self.servers.clone();
+            let name = key.clone();
+            let task = tokio::spawn(async move {
+                let res = agent_with(transport, config, servers, agent_info).await;
+                info!("agent end : {} , {:?}", name, res);
+            });
+            info!("agent started : {}", key);
+            self.running.insert(key, Running { rate_limit, task });
+        }
+    }
+}
+
+impl Drop for Agents {
+    fn drop(&mut self) {
+        for running in self.running.values() {
+            running.task.abort();
+        }
+    }
+}
+
+fn key(agent_info: &AgentInfo) -> String {
+    agent_info.to_string()
+}
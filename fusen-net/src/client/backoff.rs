@@ -0,0 +1,31 @@
+use rand::Rng;
+use std::time::Duration;
+
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Exponential backoff between reconnect attempts, jittered so that agents
+/// dropped together don't all come back at the same moment.
+pub(crate) struct Backoff {
+    current: Duration,
+}
+
+impl Backoff {
+    pub(crate) fn new() -> Self {
+        Backoff {
+            current: INITIAL_BACKOFF,
+        }
+    }
+
+    /// A random delay between half and all of the current backoff, which then
+    /// doubles up to `MAX_BACKOFF`.
+    pub(crate) fn next_delay(&mut self) -> Duration {
+        let delay = rand::thread_rng().gen_range(self.current / 2..=self.current);
+        self.current = (self.current * 2).min(MAX_BACKOFF);
+        delay
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.current = INITIAL_BACKOFF;
+    }
+}
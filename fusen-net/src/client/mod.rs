@@ -1,31 +1,104 @@
-use crate::buffer::{QuicBuffer, TcpBuffer};
+use crate::buffer::{StreamBuffer, TcpBuffer};
 use crate::common::get_uuid;
+use crate::connection::{self, Meter};
 use crate::frame::{ConnectionInfo, Frame, RegisterInfo, SubscribeInfo};
-use crate::quic::support::make_server_endpoint;
+use crate::limit::RateLimit;
+use crate::metrics::metrics;
+use crate::selector::Selector;
 use crate::server::cache::AsyncCache;
-use crate::{connection, quic};
+use crate::socket::{self, bind_tcp};
+use crate::transport::{DefaultTransport, Listener, Transport};
+use crate::Protocol;
+pub use agents::Agents;
+use backoff::Backoff;
+use prometheus::IntCounter;
 use serde::{Deserialize, Serialize};
+pub use servers::{ServerHealth, Servers};
+pub use settings::{Config, Settings};
+pub use spec::ParseAgentError;
 use std::net::SocketAddr;
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::net::{TcpListener, TcpStream};
-use tracing::debug;
+use tracing::{debug, info};
+mod agents;
+pub(crate) mod backoff;
+mod servers;
+mod settings;
+mod spec;
+
+/// Agents listen on all interfaces, IPv6 and IPv4, unless given a host.
+const DEFAULT_AGENT_HOST: &str = "::";
 
 #[derive(Clone)]
 pub struct AgentInfo {
     pub agent_mode: AgentMode,
+    /// Tag of the target, or a label selector such as `env=prod,site=sh`.
     pub target_tag: String,
     pub target_host: String,
+    /// Local host the agent listens on, `::` by default.
+    pub agent_host: String,
     pub agent_port: String,
+    /// Tag of this agent, reported to the server with every connection.
+    pub agent_tag: Option<String>,
+    /// Namespace of the target, the client's own when unset.
+    pub target_namespace: Option<String>,
+    /// Bandwidth limit shared by the tunnels of this agent.
+    pub rate_limit: Option<Arc<RateLimit>>,
 }
 
-impl From<&str> for AgentInfo {
-    fn from(value: &str) -> Self {
-        let agent_info: Vec<&str> = value.split('-').collect();
+impl AgentInfo {
+    pub fn new(
+        agent_mode: AgentMode,
+        target_tag: &str,
+        target_host: &str,
+        agent_port: &str,
+    ) -> Self {
         AgentInfo {
-            agent_mode: agent_info[0].into(),
-            target_tag: agent_info[1].to_owned(),
-            target_host: agent_info[2].to_owned(),
-            agent_port: agent_info[3].to_owned(),
+            agent_mode,
+            target_tag: target_tag.to_owned(),
+            target_host: target_host.to_owned(),
+            agent_host: DEFAULT_AGENT_HOST.to_owned(),
+            agent_port: agent_port.to_owned(),
+            agent_tag: None,
+            target_namespace: None,
+            rate_limit: None,
+        }
+    }
+
+    /// Listens on `agent_host` instead of all interfaces.
+    pub fn with_agent_host(mut self, agent_host: &str) -> Self {
+        self.agent_host = agent_host.to_owned();
+        self
+    }
+
+    pub fn with_agent_tag(mut self, agent_tag: &str) -> Self {
+        self.agent_tag = Some(agent_tag.to_owned());
+        self
+    }
+
+    /// Reaches `target_tag` in another namespace, which has to grant access.
+    pub fn with_target_namespace(mut self, target_namespace: &str) -> Self {
+        self.target_namespace = Some(target_namespace.to_owned());
+        self
+    }
+
+    /// Limits the tunnels of this agent to `rate` bytes per second altogether.
+    pub fn with_rate_limit(mut self, rate: u64) -> Self {
+        self.rate_limit = Some(Arc::new(RateLimit::new(rate)));
+        self
+    }
+
+    /// The label selector the target is picked by, if `target_tag` is one.
+    pub fn selector(&self) -> Option<String> {
+        Selector::is_selector(&self.target_tag).then(|| self.target_tag.clone())
+    }
+
+    fn meter(&self, config: &Config, sent: IntCounter, received: IntCounter) -> Meter {
+        let meter = config.meter(sent, received);
+        match &self.rate_limit {
+            Some(rate_limit) => meter.with_limit(rate_limit.clone()),
+            None => meter,
         }
     }
 }
@@ -36,200 +109,631 @@ pub enum AgentMode {
     RM,
 }
 
-impl From<&str> for AgentMode {
-    fn from(val: &str) -> Self {
-        if val.to_uppercase().contains("DM") {
-            AgentMode::DM
-        } else {
-            AgentMode::RM
+impl AgentMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AgentMode::DM => "DM",
+            AgentMode::RM => "RM",
         }
     }
 }
 
-pub async fn register(register_addr: String, tag: String) -> Result<(), crate::Error> {
-    let mut server_endpoint = make_server_endpoint("0.0.0.0:0".to_string().parse().unwrap())
-        .unwrap()
-        .0;
-    let host: SocketAddr = register_addr.parse().unwrap();
-    let (mut quic_buffer, _local_addr) = quic::connect_reuse(&mut server_endpoint, host).await?;
-    let _ = quic_buffer
-        .write_frame(&Frame::Register(RegisterInfo::new(register_addr, tag)))
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(3);
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a dial may take before the next address is tried.
+const DIAL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Opens a stream to `addr` on a new connection.
+async fn open_stream(transport: &dyn Transport, addr: &str) -> Result<StreamBuffer, crate::Error> {
+    let connection = transport.dial(addr).await.inspect_err(|_| {
+        metrics().handshake_failures.inc();
+    })?;
+    connection.open_stream().await
+}
+
+/// Opens a stream to the DM listener of the agent at `addr` on a new connection.
+async fn open_agent_stream(
+    transport: &dyn Transport,
+    addr: &str,
+) -> Result<StreamBuffer, crate::Error> {
+    let connection = transport.dial_agent(addr).await.inspect_err(|_| {
+        metrics().handshake_failures.inc();
+    })?;
+    connection.open_stream().await
+}
+
+/// Registers over a [`DefaultTransport`] with the default [`Config`]. The
+/// transport verifies the servers against the system's CAs, so servers with a
+/// self-signed certificate need [`register_with`] and a transport that pins it.
+pub async fn register(servers: impl Into<Servers>, tag: String) -> Result<(), crate::Error> {
+    register_with(
+        Arc::new(DefaultTransport::default()),
+        Config::default(),
+        servers,
+        tag,
+    )
+    .await
+}
+
+/// Registers `tag` with every one of `servers` over `transport` and keeps it
+/// registered, reconnecting to each with backoff whenever it stops answering.
+/// The transport also listens for DM connections from other agents, which
+/// reach it at the address a server sees for the registration.
+pub async fn register_with(
+    transport: Arc<dyn Transport>,
+    config: Config,
+    servers: impl Into<Servers>,
+    tag: String,
+) -> Result<(), crate::Error> {
+    let servers = servers.into();
+    let listener = transport.listen("[::]:0").await?;
+    let addrs = peer_addrs(listener.local_addr()?.port());
+    let registrations = servers.addrs().map(|register_addr| {
+        keep_registered(
+            transport.clone(),
+            config.clone(),
+            servers.clone(),
+            register_addr.to_owned(),
+            tag.clone(),
+            addrs.clone(),
+        )
+    });
+    tokio::select! {
+        res = accept_peers(listener, transport.clone(), config.clone(), servers.clone()) => res,
+        res = futures::future::try_join_all(registrations) => res.map(|_| ()),
+    }
+}
+
+/// Addresses peers may reach the DM listener on `port` at besides the one
+/// the server sees: the global IPv6 address, which needs no NAT traversal.
+fn peer_addrs(port: u16) -> Vec<String> {
+    socket::global_ipv6()
+        .map(|ip| SocketAddr::from((ip, port)).to_string())
+        .into_iter()
+        .collect()
+}
+
+async fn keep_registered(
+    transport: Arc<dyn Transport>,
+    config: Config,
+    servers: Servers,
+    register_addr: String,
+    tag: String,
+    addrs: Vec<String>,
+) -> Result<(), crate::Error> {
+    let mut backoff = Backoff::new();
+    loop {
+        let res = register_session(
+            &transport,
+            &config,
+            &servers,
+            &register_addr,
+            &tag,
+            &addrs,
+            &mut backoff,
+        )
         .await;
-    let _frame = tokio::select! {
-        res = quic_buffer.read_frame() => res?,
-        _ = tokio::time::sleep(Duration::from_secs(3)) => return Err("register time out".into()),
-    };
-    tokio::spawn(async move {
-        while let Ok(frame) = quic_buffer.read_frame().await {
-            debug!("rev frame2 : {:?}", frame);
-            if let Frame::Connection(connection) = frame {
-                tokio::spawn(async move {
-                    debug!("start rm connection : {:?}", connection);
-                    let tcp_stream = TcpStream::connect(connection.get_target_host())
-                        .await
-                        .unwrap();
-                    let buffer = TcpBuffer::new(tcp_stream);
-                    let (mut quic_buffer, _) = quic::connect(host).await.unwrap();
-                    let _ = quic_buffer
-                        .write_frame(&Frame::TargetConnection(connection))
-                        .await;
-                    let _frame = tokio::select! {
-                        res = quic_buffer.read_frame() => res.unwrap(),
-                        _ = tokio::time::sleep(Duration::from_secs(3)) => panic!("connect time out"),
-                    };
-                    let _ = connection::connect_tcp_to_quic(buffer, quic_buffer).await;
-                });
+        servers.failed(&register_addr);
+        let delay = backoff.next_delay();
+        info!(
+            "register with {} end : {:?} , try register again in {:?}",
+            register_addr, res, delay
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Registers once and serves the registration until the server goes away,
+/// which is noticed either by the stream failing or by nothing arriving for
+/// `HEARTBEAT_TIMEOUT` while we keep pinging.
+async fn register_session(
+    transport: &Arc<dyn Transport>,
+    config: &Config,
+    servers: &Servers,
+    register_addr: &str,
+    tag: &str,
+    addrs: &[String],
+    backoff: &mut Backoff,
+) -> Result<(), crate::Error> {
+    let register_info = RegisterInfo::new(register_addr.to_owned(), tag.to_owned())
+        .with_addrs(addrs.to_vec())
+        .with_token(config.token())
+        .with_namespace(config.namespace())
+        .with_mate_data(config.labels());
+    let mut quic_buffer = open_stream(&**transport, register_addr).await?;
+    quic_buffer
+        .write_frame(&Frame::Register(register_info))
+        .await?;
+    match quic_buffer.read_frame_wait(Duration::from_secs(3)).await? {
+        Frame::Error(error) => return Err(format!("register rejected : {}", error).into()),
+        Frame::GoAway => return Err("server going away".into()),
+        _ => (),
+    }
+    backoff.reset();
+    servers.succeeded(register_addr);
+    info!("register success : {} , {}", tag, register_addr);
+    let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+    let mut last_seen = Instant::now();
+    loop {
+        tokio::select! {
+            frame = quic_buffer.read_frame() => {
+                let frame = frame?;
+                debug!("rev frame2 : {:?}", frame);
+                last_seen = Instant::now();
+                match frame {
+                    Frame::Connection(connection) => {
+                        tokio::spawn(rm_connection(
+                            transport.clone(),
+                            config.clone(),
+                            register_addr.to_owned(),
+                            connection,
+                        ));
+                    }
+                    Frame::Ping => quic_buffer.write_frame(&Frame::Ack).await?,
+                    // Registered with the other servers meanwhile, if any.
+                    Frame::GoAway => return Err("server going away".into()),
+                    _ => (),
+                }
+            }
+            _ = heartbeat.tick() => {
+                if last_seen.elapsed() > HEARTBEAT_TIMEOUT {
+                    return Err("server heartbeat time out".into());
+                }
+                quic_buffer.write_frame(&Frame::Ping).await?;
             }
         }
-    });
-    while let Some(connecting) = server_endpoint.accept().await {
+    }
+}
+
+async fn accept_peers(
+    mut listener: Box<dyn Listener>,
+    transport: Arc<dyn Transport>,
+    config: Config,
+    servers: Servers,
+) -> Result<(), crate::Error> {
+    loop {
+        let connecting = listener.accept().await?;
+        let transport = transport.clone();
+        let config = config.clone();
+        let servers = servers.clone();
         tokio::spawn(async move {
-            let Ok(connection) = connecting.await else {
+            let Ok(mut connection) = connecting.await else {
+                metrics().handshake_failures.inc();
                 return;
             };
-            let (send_stream, recv_stream) = connection
-                .accept_bi()
-                .await
-                .expect("connection accept_bi err");
-            let mut quic_buffer = QuicBuffer::new(send_stream, recv_stream);
-            while let Ok(frame) = quic_buffer.read_frame().await {
-                debug!("rev frame : {:?}", frame);
-                match frame {
-                    Frame::Ping => {
-                        let _ = quic_buffer.write_frame(&Frame::Ack).await;
+            while let Ok(Some(quic_buffer)) = connection.accept_stream().await {
+                tokio::spawn(peer_stream(
+                    transport.clone(),
+                    config.clone(),
+                    servers.clone(),
+                    quic_buffer,
+                ));
+            }
+        });
+    }
+}
+
+/// Serves a stream opened by another agent.
+async fn peer_stream(
+    transport: Arc<dyn Transport>,
+    config: Config,
+    servers: Servers,
+    mut quic_buffer: StreamBuffer,
+) {
+    while let Ok(frame) = quic_buffer.read_frame().await {
+        debug!("rev frame : {:?}", frame);
+        match frame {
+            Frame::Ping => {
+                let _ = quic_buffer.write_frame(&Frame::Ack).await;
+            }
+            Frame::Connection(connection) => {
+                match connection.get_agent_mode() {
+                    AgentMode::DM => {
+                        debug!("start dm connection : {:?}", connection);
+                        if !config.is_exposed(connection.get_target_host()) {
+                            let error = format!("{} is not exposed", connection.get_target_host());
+                            info!("dm connection refused : {}", error);
+                            let _ = quic_buffer.write_frame(&Frame::Error(error)).await;
+                            return;
+                        }
+                        let tcp_stream =
+                            match TcpStream::connect(connection.get_target_host()).await {
+                                Ok(tcp_stream) => tcp_stream,
+                                Err(error) => {
+                                    let error = format!(
+                                        "connect {} err : {}",
+                                        connection.get_target_host(),
+                                        error
+                                    );
+                                    info!("dm connection failed : {}", error);
+                                    let _ = quic_buffer.write_frame(&Frame::Error(error)).await;
+                                    return;
+                                }
+                            };
+                        let tcp_buffer = TcpBuffer::new(tcp_stream);
+                        let meter = target_meter(&config, connection.get_target_tag());
+                        let _ = quic_buffer
+                            .write_frame(&Frame::TargetConnection(connection))
+                            .await;
+                        let _tunnel = metrics().open_tunnel(AgentMode::DM.as_str());
+                        let _ =
+                            connection::connect_tcp_to_stream(tcp_buffer, quic_buffer, meter).await;
+                        return;
                     }
-                    Frame::Connection(connection) => {
-                        match connection.get_agent_mode() {
-                            AgentMode::DM => {
-                                debug!("start dm connection : {:?}", connection);
-                                let tcp_stream = TcpStream::connect(connection.get_target_host())
-                                    .await
-                                    .unwrap();
-                                let tcp_buffer = TcpBuffer::new(tcp_stream);
-                                let _ = quic_buffer
-                                    .write_frame(&Frame::TargetConnection(connection))
-                                    .await;
-                                let _ =
-                                    connection::connect_tcp_to_quic(tcp_buffer, quic_buffer).await;
-                                return;
-                            }
-                            AgentMode::RM => tokio::spawn(async move {
-                                debug!("start rm connection : {:?}", connection);
-                                let tcp_stream = TcpStream::connect(connection.get_target_host())
-                                    .await
-                                    .unwrap();
-                                let buffer = TcpBuffer::new(tcp_stream);
-                                let (mut quic_buffer, _) = quic::connect(host).await.unwrap();
-                                let _ = quic_buffer
-                                    .write_frame(&Frame::TargetConnection(connection))
-                                    .await;
-                                let _frame = tokio::select! {
-                                    res = quic_buffer.read_frame() => res.unwrap(),
-                                    _ = tokio::time::sleep(Duration::from_secs(3)) => panic!("connect time out"),
-                                };
-                                let _ = connection::connect_tcp_to_quic(buffer, quic_buffer).await;
-                            }),
-                        };
+                    AgentMode::RM => {
+                        if let Some(register_addr) = servers.candidates().into_iter().next() {
+                            tokio::spawn(rm_connection(
+                                transport.clone(),
+                                config.clone(),
+                                register_addr,
+                                connection,
+                            ));
+                        }
                     }
-                    frame => debug!("rev not support frame : {:?}", frame),
-                }
+                };
             }
-        });
+            frame => debug!("rev not support frame : {:?}", frame),
+        }
+    }
+}
+
+/// Connects the target host of an RM connection back to the server. On
+/// failure the server gives up on the tunnel once it stops waiting.
+async fn rm_connection(
+    transport: Arc<dyn Transport>,
+    config: Config,
+    register_addr: String,
+    connection: ConnectionInfo,
+) {
+    debug!("start rm connection : {:?}", connection);
+    if let Err(error) = connect_back(&*transport, &config, &register_addr, connection).await {
+        info!("rm connection err : {:?}", error);
     }
-    Ok(())
 }
 
-pub async fn agent(register_addr: String, agent_info: AgentInfo) -> Result<(), crate::Error> {
+async fn connect_back(
+    transport: &dyn Transport,
+    config: &Config,
+    register_addr: &str,
+    connection: ConnectionInfo,
+) -> Result<(), crate::Error> {
+    let target_host = connection.get_target_host();
+    if !config.is_exposed(target_host) {
+        return Err(format!("{} is not exposed", target_host).into());
+    }
+    let tcp_stream = TcpStream::connect(target_host)
+        .await
+        .map_err(|error| format!("connect {} err : {}", target_host, error))?;
+    let buffer = TcpBuffer::new(tcp_stream);
+    let meter = target_meter(config, connection.get_target_tag());
+    let mut quic_buffer = open_stream(transport, register_addr).await?;
+    quic_buffer
+        .write_frame(&Frame::TargetConnection(connection))
+        .await?;
+    quic_buffer.read_frame_wait(Duration::from_secs(3)).await?;
+    let _tunnel = metrics().open_tunnel(AgentMode::RM.as_str());
+    connection::connect_tcp_to_stream(buffer, quic_buffer, meter).await
+}
+
+/// Meters a tunnel on the target side, where the local socket is the target
+/// host, so that `sent` still counts the bytes going towards it.
+fn target_meter(config: &Config, tag: &str) -> Meter {
+    let (sent, received) = metrics().relayed(tag);
+    config.meter(received, sent)
+}
+
+/// Serves `agent_info` over a [`DefaultTransport`] with the default
+/// [`Config`], see [`register`].
+pub async fn agent(servers: impl Into<Servers>, agent_info: AgentInfo) -> Result<(), crate::Error> {
+    agent_with(
+        Arc::new(DefaultTransport::default()),
+        Config::default(),
+        servers,
+        agent_info,
+    )
+    .await
+}
+
+/// Serves `agent_info`, setting every tunnel up through the first of
+/// `servers` that answers, the healthy ones first.
+pub async fn agent_with(
+    transport: Arc<dyn Transport>,
+    config: Config,
+    servers: impl Into<Servers>,
+    agent_info: AgentInfo,
+) -> Result<(), crate::Error> {
+    let servers = servers.into();
     match &agent_info.agent_mode {
-        AgentMode::DM => dm_handler(register_addr, agent_info).await,
-        AgentMode::RM => rm_handler(register_addr, agent_info).await,
+        AgentMode::DM => dm_handler(transport, config, servers, agent_info).await,
+        AgentMode::RM => rm_handler(transport, config, servers, agent_info).await,
+    }
+}
+
+/// Opens a stream to the first of `servers` that answers, returning it with
+/// the address of that server.
+async fn open_server_stream(
+    transport: &dyn Transport,
+    servers: &Servers,
+) -> Result<(StreamBuffer, String), crate::Error> {
+    let mut last_error: crate::Error = "no server".into();
+    for addr in servers.candidates() {
+        let res = match tokio::time::timeout(DIAL_TIMEOUT, open_stream(transport, &addr)).await {
+            Ok(res) => res,
+            Err(_) => Err(format!("dial {} time out", addr).into()),
+        };
+        match res {
+            Ok(quic_buffer) => {
+                servers.succeeded(&addr);
+                return Ok((quic_buffer, addr));
+            }
+            Err(error) => {
+                info!("server {} unreachable : {:?}", addr, error);
+                servers.failed(&addr);
+                last_error = error;
+            }
+        }
+    }
+    Err(last_error)
+}
+
+async fn dm_handler(
+    transport: Arc<dyn Transport>,
+    config: Config,
+    servers: Servers,
+    agent_info: AgentInfo,
+) -> Result<(), crate::Error> {
+    let async_cache: AsyncCache<String, Vec<String>> = AsyncCache::new();
+    let listener = bind_tcp(&agent_info.listen_addr()).await?;
+    tokio::select! {
+        res = keep_subscribed(
+            transport.clone(),
+            config.clone(),
+            servers,
+            SubscribeInfo::new(agent_info.target_tag.clone())
+                .with_agent_tag(agent_info.agent_tag.clone())
+                .with_target_namespace(agent_info.target_namespace.clone())
+                .with_selector(agent_info.selector()),
+            async_cache.clone(),
+        ) => res,
+        res = accept_dm(listener, transport, config, agent_info, async_cache) => res,
     }
 }
 
-async fn dm_handler(register_addr: String, agent_info: AgentInfo) -> Result<(), crate::Error> {
-    let async_cache: AsyncCache<String, String> = AsyncCache::new();
-    let register_addr_clone = register_addr.clone();
-    let host: SocketAddr = register_addr_clone.parse().unwrap();
-    let (mut quic_buffer, _) = quic::connect(host).await.expect("udp connect error");
-    let _ = quic_buffer
-        .write_frame(&Frame::Subscribe(SubscribeInfo::new(
-            agent_info.target_tag.clone(),
-        )))
+/// Keeps the address of the subscribed tag in `async_cache` up to date,
+/// subscribing again with backoff, through the next server if need be,
+/// whenever the subscription goes quiet.
+async fn keep_subscribed(
+    transport: Arc<dyn Transport>,
+    config: Config,
+    servers: Servers,
+    subscribe_info: SubscribeInfo,
+    async_cache: AsyncCache<String, Vec<String>>,
+) -> Result<(), crate::Error> {
+    let mut backoff = Backoff::new();
+    loop {
+        let res = subscribe_session(
+            &*transport,
+            &config,
+            &servers,
+            &subscribe_info,
+            &async_cache,
+            &mut backoff,
+        )
         .await;
-    let Ok(Frame::Subscribe(subscribe_info)) =
-        quic_buffer.read_frame_wait(Duration::from_secs(3)).await
-    else {
-        return Err("subscribe time out".into());
-    };
-    if let Some(addr) = subscribe_info.get_target_sockeraddr() {
-        let _ = async_cache
-            .insert(subscribe_info.get_target_tag().to_string(), addr.to_owned())
-            .await;
-    }
-    let async_cache_clone = async_cache.clone();
-    tokio::spawn(async move {
-        while let Ok(frame) = quic_buffer.read_frame().await {
-            if let Frame::Subscribe(subscribe_info) = frame {
-                if let Some(addr) = subscribe_info.get_target_sockeraddr() {
-                    let _ = async_cache_clone
-                        .insert(subscribe_info.get_target_tag().to_string(), addr.to_owned())
-                        .await;
+        let delay = backoff.next_delay();
+        info!(
+            "subscribe end : {:?} , try subscribe again in {:?}",
+            res, delay
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+async fn subscribe_session(
+    transport: &dyn Transport,
+    config: &Config,
+    servers: &Servers,
+    subscribe_info: &SubscribeInfo,
+    async_cache: &AsyncCache<String, Vec<String>>,
+    backoff: &mut Backoff,
+) -> Result<(), crate::Error> {
+    let ipv6 = socket::global_ipv6().is_some();
+    let subscribe_info = subscribe_info
+        .clone()
+        .with_namespace(config.namespace())
+        .with_token(config.token());
+    let (mut quic_buffer, register_addr) = open_server_stream(transport, servers).await?;
+    quic_buffer
+        .write_frame(&Frame::Subscribe(subscribe_info))
+        .await?;
+    loop {
+        // The server pushes every change and keeps the stream alive meanwhile.
+        let frame = match quic_buffer.read_frame_wait(HEARTBEAT_TIMEOUT).await {
+            Ok(frame) => frame,
+            Err(error) => {
+                servers.failed(&register_addr);
+                return Err(error);
+            }
+        };
+        match frame {
+            Frame::Subscribe(subscribe_info) => {
+                backoff.reset();
+                let target_tag = subscribe_info.get_target_tag().to_string();
+                let addrs = dm_addrs(&subscribe_info, ipv6);
+                if addrs.is_empty() {
+                    debug!("{} offline", target_tag);
+                    let _ = async_cache.remove(target_tag).await;
+                } else {
+                    debug!("{} at {:?}", target_tag, addrs);
+                    let _ = async_cache.insert(target_tag, addrs).await;
                 }
             }
+            Frame::Error(error) => return Err(error.into()),
+            Frame::GoAway => {
+                servers.failed(&register_addr);
+                return Err(format!("server {} going away", register_addr).into());
+            }
+            _ => (),
         }
-    });
-    let listener = TcpListener::bind(&format!("0.0.0.0:{}", agent_info.agent_port)).await?;
+    }
+}
+
+/// The addresses to reach a DM target at, its IPv6 ones first when this host
+/// has IPv6 too, then the one the server sees.
+fn dm_addrs(subscribe_info: &SubscribeInfo, ipv6: bool) -> Vec<String> {
+    let mut addrs: Vec<String> = subscribe_info
+        .get_target_addrs()
+        .iter()
+        .filter(|addr| {
+            ipv6 && addr
+                .parse::<SocketAddr>()
+                .is_ok_and(|addr| Protocol::of(&addr) == Protocol::V6)
+        })
+        .cloned()
+        .collect();
+    addrs.extend(subscribe_info.get_target_sockeraddr().map(str::to_owned));
+    addrs
+}
+
+/// Opens a stream to the first of `addrs` that answers.
+async fn open_dm_stream(
+    transport: &dyn Transport,
+    addrs: &[String],
+) -> Result<StreamBuffer, crate::Error> {
+    let mut res = Err("no address".into());
+    for addr in addrs {
+        res = match tokio::time::timeout(DIAL_TIMEOUT, open_agent_stream(transport, addr)).await {
+            Ok(res) => res,
+            Err(_) => Err(format!("dial {} time out", addr).into()),
+        };
+        match &res {
+            Ok(_) => break,
+            Err(error) => debug!("dm dial {} fail : {:?}", addr, error),
+        }
+    }
+    res
+}
+
+async fn accept_dm(
+    listener: TcpListener,
+    transport: Arc<dyn Transport>,
+    config: Config,
+    agent_info: AgentInfo,
+    async_cache: AsyncCache<String, Vec<String>>,
+) -> Result<(), crate::Error> {
     while let Ok(tcp_stream) = listener.accept().await {
         let agent_info = agent_info.clone();
         let async_cache_clone = async_cache.clone();
+        let transport = transport.clone();
+        let config = config.clone();
         tokio::spawn(async move {
+            let start = Instant::now();
             let tcp_buffer = TcpBuffer::new(tcp_stream.0);
-            let Ok(Some(addr)) = async_cache_clone.get(agent_info.target_tag.clone()).await else {
+            let Ok(Some(addrs)) = async_cache_clone.get(agent_info.target_tag.clone()).await else {
                 debug!("not find addr");
                 return;
             };
-            let host: SocketAddr = addr.parse().unwrap();
-            debug!("{:?}", host);
-            let (mut quic_buffer, _) = quic::connect(host).await.expect("udp connect error");
+            debug!("{:?}", addrs);
+            let (sent, received) = metrics().relayed(&agent_info.target_tag);
+            let meter = agent_info.meter(&config, sent, received);
+            let mut quic_buffer = match open_dm_stream(&*transport, &addrs).await {
+                Ok(quic_buffer) => quic_buffer,
+                Err(error) => {
+                    info!("dm connect err : {:?}", error);
+                    return;
+                }
+            };
             let _ = quic_buffer
-                .write_frame(&Frame::Connection(ConnectionInfo::new(
-                    AgentMode::DM,
-                    get_uuid(),
-                    agent_info.target_tag,
-                    agent_info.target_host,
-                )))
+                .write_frame(&Frame::Connection(
+                    ConnectionInfo::new(
+                        AgentMode::DM,
+                        get_uuid(),
+                        agent_info.target_tag,
+                        agent_info.target_host,
+                    )
+                    .with_agent_tag(agent_info.agent_tag),
+                ))
                 .await;
-            if let Ok(Frame::TargetConnection(_connection)) = quic_buffer.read_frame().await {
-                let _ = connection::connect_tcp_to_quic(tcp_buffer, quic_buffer).await;
+            match quic_buffer.read_frame().await {
+                Ok(Frame::TargetConnection(_)) => {
+                    metrics().observe_setup(AgentMode::DM.as_str(), start);
+                    let _tunnel = metrics().open_tunnel(AgentMode::DM.as_str());
+                    let _ = connection::connect_tcp_to_stream(tcp_buffer, quic_buffer, meter).await;
+                }
+                Ok(Frame::Error(error)) => info!("tunnel rejected : {}", error),
+                res => debug!("dm connection end : {:?}", res),
             }
         });
     }
     Ok(())
 }
 
-async fn rm_handler(register_addr: String, agent_info: AgentInfo) -> Result<(), crate::Error> {
-    let listener = TcpListener::bind(&format!("0.0.0.0:{}", agent_info.agent_port)).await?;
+async fn rm_handler(
+    transport: Arc<dyn Transport>,
+    config: Config,
+    servers: Servers,
+    agent_info: AgentInfo,
+) -> Result<(), crate::Error> {
+    let listener = bind_tcp(&agent_info.listen_addr()).await?;
     while let Ok(tcp_stream) = listener.accept().await {
         let tcp_buffer = TcpBuffer::new(tcp_stream.0);
         let agent_info = agent_info.clone();
-        let register_addr = register_addr.clone();
+        let servers = servers.clone();
+        let transport = transport.clone();
+        let config = config.clone();
         tokio::spawn(async move {
-            let host: SocketAddr = register_addr.parse().unwrap();
-            let (mut quic_buffer, _) = quic::connect(host).await.expect("udp connect error");
-            let _ = quic_buffer
-                .write_frame(&Frame::Connection(ConnectionInfo::new(
-                    AgentMode::RM,
-                    get_uuid(),
-                    agent_info.target_tag,
-                    agent_info.target_host,
-                )))
-                .await;
-            let _frame = tokio::select! {
-                res = quic_buffer.read_frame() => res.unwrap(),
-                _ = tokio::time::sleep(Duration::from_secs(3)) => panic!("agent time out"),
+            let start = Instant::now();
+            let (sent, received) = metrics().relayed(&agent_info.target_tag);
+            let meter = agent_info.meter(&config, sent, received);
+            let selector = agent_info.selector();
+            let connection_info = ConnectionInfo::new(
+                AgentMode::RM,
+                get_uuid(),
+                agent_info.target_tag,
+                agent_info.target_host,
+            )
+            .with_selector(selector)
+            .with_agent_tag(agent_info.agent_tag)
+            .with_namespace(config.namespace())
+            .with_target_namespace(agent_info.target_namespace)
+            .with_token(config.token());
+            let quic_buffer = match rm_tunnel(&*transport, &config, &servers, connection_info).await
+            {
+                Ok(quic_buffer) => quic_buffer,
+                Err(error) => {
+                    info!("rm tunnel err : {:?}", error);
+                    return;
+                }
             };
-            let _ = connection::connect_tcp_to_quic(tcp_buffer, quic_buffer).await;
+            metrics().observe_setup(AgentMode::RM.as_str(), start);
+            let _tunnel = metrics().open_tunnel(AgentMode::RM.as_str());
+            let _ = connection::connect_tcp_to_stream(tcp_buffer, quic_buffer, meter).await;
         });
     }
     Ok(())
 }
+
+/// Asks the servers in turn for an RM tunnel, moving on from the ones that
+/// are unreachable or going away.
+async fn rm_tunnel(
+    transport: &dyn Transport,
+    config: &Config,
+    servers: &Servers,
+    connection_info: ConnectionInfo,
+) -> Result<StreamBuffer, crate::Error> {
+    for _ in servers.addrs() {
+        let (mut quic_buffer, register_addr) = open_server_stream(transport, servers).await?;
+        quic_buffer
+            .write_frame(&Frame::Connection(connection_info.clone()))
+            .await?;
+        match quic_buffer.read_frame_wait(config.tunnel_timeout()).await? {
+            Frame::Error(error) => return Err(format!("tunnel rejected : {}", error).into()),
+            Frame::GoAway => {
+                info!("server {} going away", register_addr);
+                servers.failed(&register_addr);
+            }
+            _ => return Ok(quic_buffer),
+        }
+    }
+    Err("every server is going away".into())
+}
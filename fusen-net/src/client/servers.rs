@@ -0,0 +1,152 @@
+use crate::metrics::metrics;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const INITIAL_RETRY: Duration = Duration::from_secs(1);
+const MAX_RETRY: Duration = Duration::from_secs(30);
+
+/// The servers of a client in order of preference, each with how it has been
+/// doing lately. Clones share the health.
+#[derive(Clone)]
+pub struct Servers {
+    servers: Arc<[Server]>,
+}
+
+struct Server {
+    addr: String,
+    health: Mutex<Health>,
+}
+
+#[derive(Default)]
+struct Health {
+    /// Failures in a row, since the server last answered.
+    failures: u32,
+    /// When to try the server again after failures.
+    retry_at: Option<Instant>,
+}
+
+/// How one of the [`Servers`] has been doing.
+#[derive(Clone, Debug)]
+pub struct ServerHealth {
+    pub addr: String,
+    pub healthy: bool,
+    pub failures: u32,
+}
+
+impl Servers {
+    pub fn new(addrs: Vec<String>) -> Self {
+        let servers = addrs
+            .into_iter()
+            .map(|addr| {
+                metrics().servers.with_label_values(&[&addr]).set(1);
+                Server {
+                    addr,
+                    health: Default::default(),
+                }
+            })
+            .collect();
+        Servers { servers }
+    }
+
+    pub fn addrs(&self) -> impl Iterator<Item = &str> {
+        self.servers.iter().map(|server| server.addr.as_str())
+    }
+
+    pub fn health(&self) -> Vec<ServerHealth> {
+        self.servers
+            .iter()
+            .map(|server| {
+                let health = server.health.lock().unwrap();
+                ServerHealth {
+                    addr: server.addr.clone(),
+                    healthy: health.failures == 0,
+                    failures: health.failures,
+                }
+            })
+            .collect()
+    }
+
+    /// The servers to try in turn: the healthy ones in order of preference,
+    /// then the failing ones due for a retry, then the rest by when they are.
+    pub(crate) fn candidates(&self) -> Vec<String> {
+        let now = Instant::now();
+        let mut candidates: Vec<(bool, bool, Option<Instant>, &str)> = self
+            .servers
+            .iter()
+            .map(|server| {
+                let health = server.health.lock().unwrap();
+                let waiting = health.retry_at.filter(|retry_at| *retry_at > now);
+                (
+                    waiting.is_some(),
+                    health.failures > 0,
+                    waiting,
+                    server.addr.as_str(),
+                )
+            })
+            .collect();
+        // Stable, so servers that are equally good keep their order.
+        candidates.sort_by_key(|(waiting, failing, retry_at, _)| (*waiting, *failing, *retry_at));
+        candidates
+            .into_iter()
+            .map(|(.., addr)| addr.to_owned())
+            .collect()
+    }
+
+    pub(crate) fn succeeded(&self, addr: &str) {
+        if let Some(server) = self.get(addr) {
+            *server.health.lock().unwrap() = Health::default();
+            metrics().servers.with_label_values(&[addr]).set(1);
+        }
+    }
+
+    /// Counts a failure of `addr`, which is then tried again only after a
+    /// delay doubling with every failure in a row.
+    pub(crate) fn failed(&self, addr: &str) {
+        if let Some(server) = self.get(addr) {
+            let mut health = server.health.lock().unwrap();
+            health.failures = health.failures.saturating_add(1);
+            let delay = INITIAL_RETRY
+                .saturating_mul(1 << (health.failures - 1).min(5))
+                .min(MAX_RETRY);
+            health.retry_at = Some(Instant::now() + delay);
+            metrics().servers.with_label_values(&[addr]).set(0);
+        }
+    }
+
+    fn get(&self, addr: &str) -> Option<&Server> {
+        self.servers.iter().find(|server| server.addr == addr)
+    }
+}
+
+/// A comma separated list of server addresses, the first one preferred.
+impl From<&str> for Servers {
+    fn from(addrs: &str) -> Self {
+        Servers::new(
+            addrs
+                .split(',')
+                .map(str::trim)
+                .filter(|addr| !addr.is_empty())
+                .map(str::to_owned)
+                .collect(),
+        )
+    }
+}
+
+impl From<String> for Servers {
+    fn from(addrs: String) -> Self {
+        Servers::from(addrs.as_str())
+    }
+}
+
+impl From<Vec<String>> for Servers {
+    fn from(addrs: Vec<String>) -> Self {
+        Servers::new(addrs)
+    }
+}
+
+impl fmt::Display for Servers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.addrs().collect::<Vec<_>>().join(","))
+    }
+}
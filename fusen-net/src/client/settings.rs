@@ -0,0 +1,92 @@
+use crate::connection::Meter;
+use crate::limit::RateLimit;
+use crate::server::Heartbeat;
+use crate::MetaData;
+use prometheus::IntCounter;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// The parts of the client configuration that can change while it runs.
+#[derive(Clone, Debug)]
+pub struct Settings {
+    /// Token to register with, for servers that define the agent or its
+    /// namespace with one.
+    pub token: Option<String>,
+    /// Namespace tags are registered and looked up in, the default one when unset.
+    pub namespace: Option<String>,
+    /// Labels such as `env=prod`, so that other agents may select this one by
+    /// label instead of by tag.
+    pub labels: HashMap<String, String>,
+    /// Target hosts other agents may reach through this client, any when empty.
+    pub services: Vec<String>,
+    /// Bandwidth limit of all tunnels in bytes per second, unlimited when 0.
+    pub bandwidth: u64,
+    /// How long to wait for the server to set an RM tunnel up. The server
+    /// waits its heartbeat timeout for the target to connect back, 9 seconds
+    /// by default, so this should be at least as long; a second more by default.
+    pub tunnel_timeout: Duration,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            token: None,
+            namespace: None,
+            labels: HashMap::new(),
+            services: Vec::new(),
+            bandwidth: 0,
+            tunnel_timeout: Heartbeat::default().timeout() + Duration::from_secs(1),
+        }
+    }
+}
+
+/// The [`Settings`] a registration and its agents run with. Clones share
+/// them, so a reload reaches those already running; a registration picks it
+/// up when it registers again.
+#[derive(Clone, Default)]
+pub struct Config {
+    settings: Arc<RwLock<Settings>>,
+    /// Shared by every tunnel run with this config.
+    rate_limit: Arc<RateLimit>,
+}
+
+impl Config {
+    pub fn new(settings: Settings) -> Self {
+        let config = Config::default();
+        config.reload(settings);
+        config
+    }
+
+    pub fn reload(&self, settings: Settings) {
+        self.rate_limit.set_rate(settings.bandwidth);
+        *self.settings.write().unwrap() = settings;
+    }
+
+    pub(super) fn token(&self) -> Option<String> {
+        self.settings.read().unwrap().token.clone()
+    }
+
+    pub(super) fn namespace(&self) -> Option<String> {
+        self.settings.read().unwrap().namespace.clone()
+    }
+
+    pub(super) fn labels(&self) -> MetaData {
+        self.settings.read().unwrap().labels.clone().into()
+    }
+
+    pub(super) fn tunnel_timeout(&self) -> Duration {
+        self.settings.read().unwrap().tunnel_timeout
+    }
+
+    pub(super) fn is_exposed(&self, target_host: &str) -> bool {
+        let services = &self.settings.read().unwrap().services;
+        services.is_empty() || services.iter().any(|service| service == target_host)
+    }
+
+    pub(super) fn meter(&self, sent: IntCounter, received: IntCounter) -> Meter {
+        Meter::default()
+            .with(sent, received)
+            .with_limit(self.rate_limit.clone())
+    }
+}
@@ -0,0 +1,251 @@
+//! Parsing of agent specifications, either `RM-agent1-10.0.0.5:8081-8078` or
+//! `rm://agent1/10.0.0.5:8081?listen=127.0.0.1:8078&bandwidth=1048576&namespace=team-b`.
+//! The target tag may be a label selector instead, as in
+//! `rm://env=prod,site=sh/10.0.0.5:8081?listen=8078`.
+
+use super::{AgentInfo, AgentMode, DEFAULT_AGENT_HOST};
+use crate::selector::Selector;
+use std::fmt;
+use std::str::FromStr;
+
+/// A malformed agent specification, naming the offending `AgentInfo` field.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseAgentError {
+    field: &'static str,
+    message: String,
+}
+
+impl ParseAgentError {
+    fn new(field: &'static str, message: impl Into<String>) -> Self {
+        ParseAgentError {
+            field,
+            message: message.into(),
+        }
+    }
+
+    pub fn field(&self) -> &'static str {
+        self.field
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for ParseAgentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid {} : {}", self.field, self.message)
+    }
+}
+
+impl std::error::Error for ParseAgentError {}
+
+impl FromStr for AgentMode {
+    type Err = ParseAgentError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if value.eq_ignore_ascii_case("DM") {
+            Ok(AgentMode::DM)
+        } else if value.eq_ignore_ascii_case("RM") {
+            Ok(AgentMode::RM)
+        } else {
+            Err(ParseAgentError::new(
+                "agent_mode",
+                format!("expected DM or RM, got `{}`", value),
+            ))
+        }
+    }
+}
+
+impl FromStr for AgentInfo {
+    type Err = ParseAgentError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let agent_info = match value.split_once("://") {
+            Some((mode, rest)) => parse_url(mode, rest)?,
+            None => parse_dashed(value)?,
+        };
+        agent_info.validate()?;
+        Ok(agent_info)
+    }
+}
+
+impl fmt::Display for AgentInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}://{}/{}?listen={}",
+            self.agent_mode.as_str().to_lowercase(),
+            self.target_tag,
+            self.target_host,
+            self.listen_addr()
+        )?;
+        match &self.target_namespace {
+            Some(target_namespace) => write!(f, "&namespace={}", target_namespace),
+            None => Ok(()),
+        }
+    }
+}
+
+impl AgentInfo {
+    /// Checks the fields the same way parsing does.
+    pub fn validate(&self) -> Result<(), ParseAgentError> {
+        if self.target_tag.is_empty() || self.target_tag.contains('/') {
+            return Err(ParseAgentError::new(
+                "target_tag",
+                format!("expected a tag without `/`, got `{}`", self.target_tag),
+            ));
+        }
+        if let Some(selector) = self.selector() {
+            selector
+                .parse::<Selector>()
+                .map_err(|message| ParseAgentError::new("target_tag", message))?;
+        }
+        if let Some(target_namespace) = &self.target_namespace {
+            if target_namespace.is_empty() || target_namespace.contains('/') {
+                return Err(ParseAgentError::new(
+                    "target_namespace",
+                    format!(
+                        "expected a namespace without `/`, got `{}`",
+                        target_namespace
+                    ),
+                ));
+            }
+        }
+        match split_host_port(&self.target_host) {
+            Some((_, port)) if port.parse::<u16>().is_ok() => (),
+            _ => {
+                return Err(ParseAgentError::new(
+                    "target_host",
+                    format!(
+                        "expected host:port or [ipv6]:port, got `{}`",
+                        self.target_host
+                    ),
+                ))
+            }
+        }
+        let agent_host = self
+            .agent_host
+            .strip_prefix('[')
+            .and_then(|host| host.strip_suffix(']'))
+            .unwrap_or(&self.agent_host);
+        if agent_host.is_empty() || agent_host.contains(['[', ']', '/']) {
+            return Err(ParseAgentError::new(
+                "agent_host",
+                format!("expected a local address, got `{}`", self.agent_host),
+            ));
+        }
+        if self.agent_port.parse::<u16>().is_err() {
+            return Err(ParseAgentError::new(
+                "agent_port",
+                format!("expected a port number, got `{}`", self.agent_port),
+            ));
+        }
+        Ok(())
+    }
+
+    /// The local address the agent listens on, IPv6 hosts in brackets.
+    pub fn listen_addr(&self) -> String {
+        if self.agent_host.contains(':') && !self.agent_host.starts_with('[') {
+            format!("[{}]:{}", self.agent_host, self.agent_port)
+        } else {
+            format!("{}:{}", self.agent_host, self.agent_port)
+        }
+    }
+}
+
+/// `MODE-target_tag-target_host-listen`: the tag ends at the first `-` after
+/// the mode and the listen address starts after the last one, so only the
+/// target host may contain `-`.
+fn parse_dashed(value: &str) -> Result<AgentInfo, ParseAgentError> {
+    let invalid = || {
+        ParseAgentError::new(
+            "agent",
+            format!("expected MODE-target_tag-target_host-port, got `{}`", value),
+        )
+    };
+    let (mode, rest) = value.split_once('-').ok_or_else(invalid)?;
+    let (rest, listen) = rest.rsplit_once('-').ok_or_else(invalid)?;
+    let (target_tag, target_host) = rest.split_once('-').ok_or_else(invalid)?;
+    let (agent_host, agent_port) = split_listen(listen)?;
+    Ok(
+        AgentInfo::new(mode.parse()?, target_tag, target_host, agent_port)
+            .with_agent_host(agent_host),
+    )
+}
+
+/// `mode://target_tag/target_host?listen=[host:]port&bandwidth=rate&namespace=name`.
+fn parse_url(mode: &str, rest: &str) -> Result<AgentInfo, ParseAgentError> {
+    let (path, query) = rest.split_once('?').unwrap_or((rest, ""));
+    let (target_tag, target_host) = path.split_once('/').ok_or_else(|| {
+        ParseAgentError::new(
+            "agent",
+            format!("expected mode://target_tag/target_host, got `{}`", path),
+        )
+    })?;
+    let mut listen = None;
+    let mut bandwidth = None;
+    let mut target_namespace = None;
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        match pair.split_once('=') {
+            Some(("listen", value)) => listen = Some(split_listen(value)?),
+            Some(("namespace", value)) => target_namespace = Some(value),
+            Some(("bandwidth", value)) => {
+                bandwidth = Some(value.parse::<u64>().map_err(|_| {
+                    ParseAgentError::new(
+                        "rate_limit",
+                        format!("expected bytes per second, got `{}`", value),
+                    )
+                })?)
+            }
+            _ => {
+                return Err(ParseAgentError::new(
+                    "agent",
+                    format!("unknown parameter `{}`", pair),
+                ))
+            }
+        }
+    }
+    let (agent_host, agent_port) =
+        listen.ok_or_else(|| ParseAgentError::new("agent_port", "missing `listen` parameter"))?;
+    let mut agent_info = AgentInfo::new(mode.parse()?, target_tag, target_host, agent_port)
+        .with_agent_host(agent_host);
+    if let Some(target_namespace) = target_namespace {
+        agent_info = agent_info.with_target_namespace(target_namespace);
+    }
+    Ok(match bandwidth {
+        Some(rate) => agent_info.with_rate_limit(rate),
+        None => agent_info,
+    })
+}
+
+/// A bare port listens on all interfaces.
+fn split_listen(value: &str) -> Result<(&str, &str), ParseAgentError> {
+    if !value.contains(':') {
+        return Ok((DEFAULT_AGENT_HOST, value));
+    }
+    split_host_port(value).ok_or_else(|| {
+        ParseAgentError::new(
+            "agent_host",
+            format!("expected port, host:port or [ipv6]:port, got `{}`", value),
+        )
+    })
+}
+
+/// Splits `host:port` or `[ipv6]:port`, refusing IPv6 hosts without brackets.
+fn split_host_port(value: &str) -> Option<(&str, &str)> {
+    let (host, port) = match value.strip_prefix('[') {
+        Some(rest) => rest.split_once("]:")?,
+        None => {
+            let (host, port) = value.rsplit_once(':')?;
+            if host.contains(':') {
+                return None;
+            }
+            (host, port)
+        }
+    };
+    if host.is_empty() || port.is_empty() {
+        return None;
+    }
+    Some((host, port))
+}
@@ -1,27 +1,245 @@
-use crate::buffer::{QuicBuffer, TcpBuffer};
+use crate::buffer::{RecvHalf, SendHalf, StreamBuffer, TcpBuffer};
+use crate::limit::RateLimit;
+use bytes::{Bytes, BytesMut};
+use prometheus::IntCounter;
+use quinn::{RecvStream, SendStream};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::sync::mpsc;
 
-pub async fn connect_tcp_to_quic(mut buf1: TcpBuffer, mut buf2: QuicBuffer) -> Result<(), crate::Error> {
-    loop {
-        tokio::select! {
-            res1 = buf1.read_buf() => {
-                buf2.write_buf(res1?).await?;
-            },
-            res2 = buf2.read_buf() => {
-                buf1.write_buf(res2?).await?;
-            },
+const MIN_CHUNK_SIZE: usize = 4 * 1024;
+const MAX_CHUNK_SIZE: usize = 128 * 1024;
+/// Chunks in flight per direction, so a direction holds at most
+/// `PIPE_DEPTH * MAX_CHUNK_SIZE` bytes before the reader waits for the writer.
+const PIPE_DEPTH: usize = 4;
+
+/// Byte counters bumped and rate limits applied as a tunnel copies data,
+/// `sent` for the bytes going from the first buffer to the second and
+/// `received` for the way back. Limits are shared by both directions.
+#[derive(Clone, Default)]
+pub struct Meter {
+    sent: Vec<IntCounter>,
+    received: Vec<IntCounter>,
+    limits: Vec<Arc<RateLimit>>,
+}
+
+impl Meter {
+    pub fn with(mut self, sent: IntCounter, received: IntCounter) -> Self {
+        self.sent.push(sent);
+        self.received.push(received);
+        self
+    }
+
+    pub fn with_limit(mut self, limit: Arc<RateLimit>) -> Self {
+        self.limits.push(limit);
+        self
+    }
+
+    fn sent(&self) -> Flow<'_> {
+        Flow {
+            counters: &self.sent,
+            limits: &self.limits,
+        }
+    }
+
+    fn received(&self) -> Flow<'_> {
+        Flow {
+            counters: &self.received,
+            limits: &self.limits,
+        }
+    }
+}
+
+/// One direction of a [`Meter`].
+#[derive(Clone, Copy)]
+struct Flow<'a> {
+    counters: &'a [IntCounter],
+    limits: &'a [Arc<RateLimit>],
+}
+
+impl Flow<'_> {
+    /// Waits until every limit lets `len` more bytes through.
+    async fn throttle(&self, len: usize) {
+        for limit in self.limits {
+            limit.acquire(len).await;
         }
     }
+
+    fn count(&self, len: usize) {
+        for counter in self.counters {
+            counter.inc_by(len as u64);
+        }
+    }
+}
+
+pub async fn connect_tcp_to_stream(
+    buf1: TcpBuffer,
+    buf2: StreamBuffer,
+    meter: Meter,
+) -> Result<(), crate::Error> {
+    let (cache1, read1, write1) = buf1.into_split();
+    let (cache2, read2, write2) = buf2.into_split();
+    tokio::try_join!(
+        pipe(cache1, ChunkReader::new(read1), write2, meter.sent()),
+        pipe_from(cache2, read2, write1, meter.received())
+    )?;
+    Ok(())
+}
+
+pub async fn connect_stream_to_stream(
+    buf1: StreamBuffer,
+    buf2: StreamBuffer,
+    meter: Meter,
+) -> Result<(), crate::Error> {
+    let (cache1, read1, write1) = buf1.into_split();
+    let (cache2, read2, write2) = buf2.into_split();
+    tokio::try_join!(
+        pipe_from(cache1, read1, write2, meter.sent()),
+        pipe_from(cache2, read2, write1, meter.received())
+    )?;
+    Ok(())
+}
+
+/// Keeps the zero-copy read path for QUIC streams.
+async fn pipe_from<W: ChunkWrite>(
+    cache: BytesMut,
+    reader: RecvHalf,
+    writer: W,
+    flow: Flow<'_>,
+) -> Result<(), crate::Error> {
+    match reader {
+        RecvHalf::Quic(reader) => pipe(cache, reader, writer, flow).await,
+        RecvHalf::Stream(reader) => pipe(cache, ChunkReader::new(reader), writer, flow).await,
+    }
 }
 
-pub async fn connect_quic_to_quic(mut buf1: QuicBuffer, mut buf2: QuicBuffer) -> Result<(), crate::Error> {
-    loop {
-        tokio::select! {
-            res1 = buf1.read_buf() => {
-                buf2.write_buf(res1?).await?;
-            },
-            res2 = buf2.read_buf() => {
-                buf1.write_buf(res2?).await?;
-            },
+/// Copies one direction until the reader reaches EOF, then shuts down the
+/// writer so the peer sees the half-close while the other direction keeps going.
+/// Reading and writing run concurrently through a bounded queue, so a slow
+/// writer only stalls its own reader once the queue is full.
+async fn pipe<R, W>(
+    cache: BytesMut,
+    mut reader: R,
+    mut writer: W,
+    flow: Flow<'_>,
+) -> Result<(), crate::Error>
+where
+    R: ChunkRead,
+    W: ChunkWrite,
+{
+    let (sender, mut receiver) = mpsc::channel::<Bytes>(PIPE_DEPTH);
+    let read = async move {
+        if !cache.is_empty() && sender.send(cache.freeze()).await.is_err() {
+            return Ok(());
+        }
+        while let Some(chunk) = reader.next_chunk().await? {
+            if sender.send(chunk).await.is_err() {
+                break;
+            }
+        }
+        Ok::<(), crate::Error>(())
+    };
+    let write = async move {
+        while let Some(chunk) = receiver.recv().await {
+            let len = chunk.len();
+            flow.throttle(len).await;
+            writer.send_chunk(chunk).await?;
+            flow.count(len);
         }
+        writer.close().await
+    };
+    tokio::try_join!(read, write)?;
+    Ok(())
+}
+
+trait ChunkRead {
+    /// Returns the next chunk of data, or `None` once the peer finished sending.
+    async fn next_chunk(&mut self) -> Result<Option<Bytes>, crate::Error>;
+}
+
+trait ChunkWrite {
+    async fn send_chunk(&mut self, chunk: Bytes) -> Result<(), crate::Error>;
+
+    async fn close(&mut self) -> Result<(), crate::Error>;
+}
+
+/// Reads a byte stream into freshly split chunks whose size follows the observed
+/// read sizes, growing while reads fill the chunk and shrinking when they don't.
+struct ChunkReader<R> {
+    read_half: R,
+    buffer: BytesMut,
+    chunk_size: usize,
+}
+
+impl<R: AsyncRead + Unpin> ChunkReader<R> {
+    fn new(read_half: R) -> Self {
+        ChunkReader {
+            read_half,
+            buffer: BytesMut::new(),
+            chunk_size: MIN_CHUNK_SIZE,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> ChunkRead for ChunkReader<R> {
+    async fn next_chunk(&mut self) -> Result<Option<Bytes>, crate::Error> {
+        self.buffer.reserve(self.chunk_size);
+        let len = self.read_half.read_buf(&mut self.buffer).await?;
+        if len == 0 {
+            return Ok(None);
+        }
+        if len >= self.chunk_size {
+            self.chunk_size = (self.chunk_size * 2).min(MAX_CHUNK_SIZE);
+        } else if len < self.chunk_size / 4 {
+            self.chunk_size = (self.chunk_size / 2).max(MIN_CHUNK_SIZE);
+        }
+        Ok(Some(self.buffer.split().freeze()))
+    }
+}
+
+impl ChunkRead for RecvStream {
+    async fn next_chunk(&mut self) -> Result<Option<Bytes>, crate::Error> {
+        let chunk = self.read_chunk(MAX_CHUNK_SIZE, true).await?;
+        Ok(chunk.map(|chunk| chunk.bytes))
+    }
+}
+
+impl ChunkWrite for OwnedWriteHalf {
+    async fn send_chunk(&mut self, chunk: Bytes) -> Result<(), crate::Error> {
+        self.write_all(&chunk).await.map_err(|e| e.into())
+    }
+
+    async fn close(&mut self) -> Result<(), crate::Error> {
+        self.shutdown().await.map_err(|e| e.into())
+    }
+}
+
+impl ChunkWrite for SendHalf {
+    async fn send_chunk(&mut self, chunk: Bytes) -> Result<(), crate::Error> {
+        match self {
+            SendHalf::Quic(stream) => stream.send_chunk(chunk).await,
+            SendHalf::Stream(stream) => {
+                stream.write_all(&chunk).await?;
+                stream.flush().await.map_err(|e| e.into())
+            }
+        }
+    }
+
+    async fn close(&mut self) -> Result<(), crate::Error> {
+        match self {
+            SendHalf::Quic(stream) => stream.close().await,
+            SendHalf::Stream(stream) => stream.shutdown().await.map_err(|e| e.into()),
+        }
+    }
+}
+
+impl ChunkWrite for SendStream {
+    async fn send_chunk(&mut self, chunk: Bytes) -> Result<(), crate::Error> {
+        self.write_chunk(chunk).await.map_err(|e| e.into())
+    }
+
+    async fn close(&mut self) -> Result<(), crate::Error> {
+        self.finish().await.map_err(|e| e.into())
     }
 }
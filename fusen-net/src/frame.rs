@@ -1,7 +1,7 @@
-use crate::{buffer::QuicBuffer, client::AgentMode, MetaData};
+use crate::{buffer::StreamBuffer, client::AgentMode, server::namespace::qualify, MetaData};
 use bytes::Buf;
 use serde::{Deserialize, Serialize};
-use std::{fmt::Debug, io::Cursor, string::FromUtf8Error};
+use std::{fmt::Debug, io::Cursor, net::SocketAddr, string::FromUtf8Error};
 
 #[derive(Debug)]
 pub enum Error {
@@ -16,6 +16,17 @@ pub struct RegisterInfo {
     tcp_port: Option<String>,
     udp_port: Option<String>,
     mate_data: MetaData,
+    /// Addresses other agents may reach this agent at directly, besides the
+    /// one the server sees, e.g. its global IPv6 address.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    addrs: Vec<String>,
+    /// Credentials of the agent, checked against its definition or else its
+    /// namespace on the server.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    token: Option<String>,
+    /// Namespace the tag lives in, the default one when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    namespace: Option<String>,
 }
 impl RegisterInfo {
     pub fn new(server_host: String, tag: String) -> Self {
@@ -25,12 +36,52 @@ impl RegisterInfo {
             tcp_port: Default::default(),
             udp_port: Default::default(),
             mate_data: Default::default(),
+            addrs: Default::default(),
+            token: None,
+            namespace: None,
         }
     }
 
+    pub fn with_namespace(mut self, namespace: Option<String>) -> Self {
+        self.namespace = namespace;
+        self
+    }
+
+    pub fn get_namespace(&self) -> Option<&str> {
+        self.namespace.as_deref()
+    }
+
+    /// Labels such as `env=prod`, for initiators selecting agents by label.
+    pub fn with_mate_data(mut self, mate_data: MetaData) -> Self {
+        self.mate_data = mate_data;
+        self
+    }
+
+    pub fn with_token(mut self, token: Option<String>) -> Self {
+        self.token = token;
+        self
+    }
+
+    pub fn get_token(&self) -> Option<&str> {
+        self.token.as_deref()
+    }
+
+    pub fn with_addrs(mut self, addrs: Vec<String>) -> Self {
+        self.addrs = addrs;
+        self
+    }
+
+    pub fn get_addrs(&self) -> &[String] {
+        &self.addrs
+    }
+
     pub fn get_tag(&self) -> &str {
         &self.tag
     }
+
+    pub fn get_mate_data(&self) -> &MetaData {
+        &self.mate_data
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -39,12 +90,47 @@ pub struct ConnectionInfo {
     source_tag: String,
     target_tag: String,
     target_host: String,
+    /// Tag of the agent asking for the connection, when it has one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    agent_tag: Option<String>,
+    /// Namespace of the agent asking, the default one when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    namespace: Option<String>,
+    /// Namespace of the target, the agent's own when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    target_namespace: Option<String>,
+    /// Credentials of the agent's namespace.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    token: Option<String>,
+    /// Label selector the server picks the target by, see
+    /// [`Selector`](crate::selector::Selector), in place of the target tag.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    selector: Option<String>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct SubscribeInfo {
     target_tag: String,
     target_sockeraddr: Option<String>,
+    /// Other addresses the target registered, see [`RegisterInfo`].
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    target_addrs: Vec<String>,
+    /// Tag of the subscribing agent, when it has one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    agent_tag: Option<String>,
+    /// Namespace of the agent asking, the default one when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    namespace: Option<String>,
+    /// Namespace of the target, the agent's own when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    target_namespace: Option<String>,
+    /// Credentials of the agent's namespace.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    token: Option<String>,
+    /// Label selector the server picks the target by, see
+    /// [`Selector`](crate::selector::Selector), in place of the target tag.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    selector: Option<String>,
 }
 
 impl SubscribeInfo {
@@ -52,8 +138,59 @@ impl SubscribeInfo {
         SubscribeInfo {
             target_tag,
             target_sockeraddr: None,
+            target_addrs: Default::default(),
+            agent_tag: None,
+            namespace: None,
+            target_namespace: None,
+            token: None,
+            selector: None,
         }
     }
+
+    pub fn with_agent_tag(mut self, agent_tag: Option<String>) -> Self {
+        self.agent_tag = agent_tag;
+        self
+    }
+
+    pub fn with_namespace(mut self, namespace: Option<String>) -> Self {
+        self.namespace = namespace;
+        self
+    }
+
+    pub fn with_target_namespace(mut self, target_namespace: Option<String>) -> Self {
+        self.target_namespace = target_namespace;
+        self
+    }
+
+    pub fn with_token(mut self, token: Option<String>) -> Self {
+        self.token = token;
+        self
+    }
+
+    pub fn get_namespace(&self) -> Option<&str> {
+        self.namespace.as_deref()
+    }
+
+    pub fn get_target_namespace(&self) -> Option<&str> {
+        self.target_namespace.as_deref().or(self.get_namespace())
+    }
+
+    pub fn get_token(&self) -> Option<&str> {
+        self.token.as_deref()
+    }
+
+    pub fn with_selector(mut self, selector: Option<String>) -> Self {
+        self.selector = selector;
+        self
+    }
+
+    pub fn get_selector(&self) -> Option<&str> {
+        self.selector.as_deref()
+    }
+
+    pub fn get_agent_tag(&self) -> Option<&str> {
+        self.agent_tag.as_deref()
+    }
     pub fn get_target_tag(&self) -> &str {
         &self.target_tag
     }
@@ -63,6 +200,12 @@ impl SubscribeInfo {
     pub fn set_target_sockeraddr(&mut self, target_sockeraddr: Option<String>) {
         self.target_sockeraddr = target_sockeraddr;
     }
+    pub fn get_target_addrs(&self) -> &[String] {
+        &self.target_addrs
+    }
+    pub fn set_target_addrs(&mut self, target_addrs: Vec<String>) {
+        self.target_addrs = target_addrs;
+    }
 }
 
 impl ConnectionInfo {
@@ -77,8 +220,87 @@ impl ConnectionInfo {
             source_tag,
             target_tag,
             target_host,
+            agent_tag: None,
+            namespace: None,
+            target_namespace: None,
+            token: None,
+            selector: None,
+        }
+    }
+
+    pub fn with_agent_tag(mut self, agent_tag: Option<String>) -> Self {
+        self.agent_tag = agent_tag;
+        self
+    }
+
+    pub fn with_namespace(mut self, namespace: Option<String>) -> Self {
+        self.namespace = namespace;
+        self
+    }
+
+    pub fn with_target_namespace(mut self, target_namespace: Option<String>) -> Self {
+        self.target_namespace = target_namespace;
+        self
+    }
+
+    pub fn with_token(mut self, token: Option<String>) -> Self {
+        self.token = token;
+        self
+    }
+
+    pub fn get_namespace(&self) -> Option<&str> {
+        self.namespace.as_deref()
+    }
+
+    pub fn get_target_namespace(&self) -> Option<&str> {
+        self.target_namespace.as_deref().or(self.get_namespace())
+    }
+
+    pub fn get_token(&self) -> Option<&str> {
+        self.token.as_deref()
+    }
+
+    pub fn with_selector(mut self, selector: Option<String>) -> Self {
+        self.selector = selector;
+        self
+    }
+
+    pub fn get_selector(&self) -> Option<&str> {
+        self.selector.as_deref()
+    }
+
+    /// The connection under the tunnel id `source_tag`, which the server hands
+    /// out so that no client can reuse another's.
+    pub(crate) fn with_source_tag(self, source_tag: String) -> Self {
+        ConnectionInfo { source_tag, ..self }
+    }
+
+    /// The connection with both tags named as on the server, see [`qualify`],
+    /// and without credentials, to pass on to the target.
+    pub(crate) fn qualified(self) -> Self {
+        ConnectionInfo {
+            target_tag: qualify(self.get_target_namespace(), &self.target_tag),
+            agent_tag: self
+                .agent_tag
+                .as_deref()
+                .map(|agent_tag| qualify(self.get_namespace(), agent_tag)),
+            namespace: None,
+            target_namespace: None,
+            token: None,
+            ..self
+        }
+    }
+
+    /// The connection sent on to `target_tag`, the agent picked for its
+    /// selector.
+    pub(crate) fn routed_to(self, target_tag: &str) -> Self {
+        ConnectionInfo {
+            target_tag: target_tag.to_owned(),
+            selector: None,
+            ..self
         }
     }
+
     pub fn get_agent_mode(&self) -> &AgentMode {
         &self.agent_mode
     }
@@ -92,6 +314,100 @@ impl ConnectionInfo {
     pub fn get_target_host(&self) -> &str {
         &self.target_host
     }
+    pub fn get_agent_tag(&self) -> Option<&str> {
+        self.agent_tag.as_deref()
+    }
+}
+
+/// A server of the cluster introducing itself to a peer, before announcing
+/// its agents or relaying a tunnel to it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PeerInfo {
+    /// The address peers reach the server at.
+    server: String,
+    /// The cluster's shared secret.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    token: Option<String>,
+}
+
+impl PeerInfo {
+    pub fn new(server: String, token: Option<String>) -> Self {
+        PeerInfo { server, token }
+    }
+
+    pub fn get_server(&self) -> &str {
+        &self.server
+    }
+
+    pub fn get_token(&self) -> Option<&str> {
+        self.token.as_deref()
+    }
+}
+
+/// The agents registered under `tag` with the announcing server, replacing
+/// whatever it announced for the tag before. Tags with too many agents for a
+/// frame are announced in several, all but the last flagged `more`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GossipInfo {
+    tag: String,
+    agents: Vec<AnnouncedAgent>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    more: bool,
+}
+
+impl GossipInfo {
+    pub fn new(tag: String, agents: Vec<AnnouncedAgent>) -> Self {
+        GossipInfo {
+            tag,
+            agents,
+            more: false,
+        }
+    }
+
+    pub fn with_more(mut self, more: bool) -> Self {
+        self.more = more;
+        self
+    }
+
+    pub fn has_more(&self) -> bool {
+        self.more
+    }
+
+    pub fn into_agents(self) -> Vec<AnnouncedAgent> {
+        self.agents
+    }
+
+    pub fn get_tag(&self) -> &str {
+        &self.tag
+    }
+
+    pub fn get_agents(&self) -> &[AnnouncedAgent] {
+        &self.agents
+    }
+}
+
+/// An agent as its server sees it, without its credentials.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AnnouncedAgent {
+    net_addr: SocketAddr,
+    register_info: RegisterInfo,
+}
+
+impl AnnouncedAgent {
+    pub fn new(net_addr: SocketAddr, register_info: RegisterInfo) -> Self {
+        AnnouncedAgent {
+            net_addr,
+            register_info: register_info.with_token(None),
+        }
+    }
+
+    pub fn get_net_addr(&self) -> SocketAddr {
+        self.net_addr
+    }
+
+    pub fn get_register_info(&self) -> &RegisterInfo {
+        &self.register_info
+    }
 }
 
 #[derive(Debug)]
@@ -103,7 +419,14 @@ pub enum Frame {
     Connection(ConnectionInfo),
     TargetConnection(ConnectionInfo),
     Subscribe(SubscribeInfo),
-    TargetBuffer(QuicBuffer),
+    TargetBuffer(StreamBuffer),
+    /// A request the server refused, with the reason.
+    Error(String),
+    Peer(PeerInfo),
+    Gossip(GossipInfo),
+    /// The server is shutting down and takes no new work: go to another one.
+    /// Older clients read it as an ack.
+    GoAway,
 }
 
 impl Frame {
@@ -125,16 +448,23 @@ impl Frame {
         let start = start + 2;
         let buf = &buf[start..start + lenght];
         bytes.set_position((start + lenght) as u64);
-        let frame = match buf[0] {
+        let Some(&kind) = buf.first() else {
+            return Err(Error::Other("empty frame".into()));
+        };
+        let frame = match kind {
             b'*' => Frame::Connection(serde_json::from_slice(&buf[1..])?),
             b'&' => Frame::TargetConnection(serde_json::from_slice(&buf[1..])?),
             b'^' => Frame::Subscribe(serde_json::from_slice(&buf[1..])?),
             b'!' => match buf[1..buf.len()].as_ref() {
                 b"ping" => Frame::Ping,
                 b"keepalive" => Frame::KeepAlive,
+                b"goaway" => Frame::GoAway,
                 _ => Frame::Ack,
             },
             b'+' => Frame::Register(serde_json::from_slice(&buf[1..])?),
+            b'-' => Frame::Error(String::from_utf8(buf[1..].to_vec())?),
+            b'~' => Frame::Peer(serde_json::from_slice(&buf[1..])?),
+            b'%' => Frame::Gossip(serde_json::from_slice(&buf[1..])?),
             _ => return Err(Error::Other("parse error".into())),
         };
         Ok(frame)
@@ -168,13 +498,32 @@ impl Frame {
                 bytes.push(b'!');
                 bytes.extend_from_slice(b"keepalive");
             }
+            Frame::GoAway => {
+                bytes.push(b'!');
+                bytes.extend_from_slice(b"goaway");
+            }
             Frame::Register(register_info) => {
                 bytes.push(b'+');
                 bytes.extend_from_slice(serde_json::to_string(register_info)?.as_bytes());
             }
+            Frame::Error(message) => {
+                bytes.push(b'-');
+                bytes.extend_from_slice(message.as_bytes());
+            }
+            Frame::Peer(peer_info) => {
+                bytes.push(b'~');
+                bytes.extend_from_slice(serde_json::to_string(peer_info)?.as_bytes());
+            }
+            Frame::Gossip(gossip_info) => {
+                bytes.push(b'%');
+                bytes.extend_from_slice(serde_json::to_string(gossip_info)?.as_bytes());
+            }
             _ => return Err("serialization error".into()),
         }
-        let length = (bytes.len() - 3) as u16;
+        let length = bytes.len() - 3;
+        if length > u16::MAX as usize {
+            return Err(format!("frame of {} bytes is too large", length).into());
+        }
         bytes[1] = (length >> 8) as u8;
         bytes[2] = length as u8;
         Ok(bytes)
@@ -206,3 +555,103 @@ fn get_u16(u8_array: &[u8], start: usize) -> u16 {
     lenght |= u8_array[start + 1] as u16;
     lenght
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(frame: &Frame) -> Frame {
+        let bytes = frame.serialization().unwrap();
+        let mut cursor = Cursor::new(&bytes[..]);
+        let parsed = Frame::parse(&mut cursor).unwrap();
+        assert_eq!(cursor.position() as usize, bytes.len());
+        parsed
+    }
+
+    #[test]
+    fn peer_round_trips() {
+        let frame = Frame::Peer(PeerInfo::new(
+            "10.0.0.1:8089".to_owned(),
+            Some("cluster-secret".to_owned()),
+        ));
+        let Frame::Peer(peer_info) = round_trip(&frame) else {
+            panic!("expected a peer frame");
+        };
+        assert_eq!(peer_info.get_server(), "10.0.0.1:8089");
+        assert_eq!(peer_info.get_token(), Some("cluster-secret"));
+    }
+
+    #[test]
+    fn gossip_round_trips_without_tokens() {
+        let register_info = RegisterInfo::new("server".to_owned(), "agent1".to_owned())
+            .with_namespace(Some("team-a".to_owned()))
+            .with_token(Some("s3cret".to_owned()));
+        let agent = AnnouncedAgent::new(([10, 0, 0, 2], 4000).into(), register_info);
+        for more in [false, true] {
+            let frame = Frame::Gossip(
+                GossipInfo::new("team-a/agent1".to_owned(), vec![agent.clone()]).with_more(more),
+            );
+            let Frame::Gossip(gossip_info) = round_trip(&frame) else {
+                panic!("expected a gossip frame");
+            };
+            assert_eq!(gossip_info.get_tag(), "team-a/agent1");
+            assert_eq!(gossip_info.has_more(), more);
+            let [agent] = gossip_info.get_agents() else {
+                panic!("expected one agent");
+            };
+            assert_eq!(agent.get_net_addr(), ([10, 0, 0, 2], 4000).into());
+            assert_eq!(agent.get_register_info().get_tag(), "agent1");
+            assert_eq!(agent.get_register_info().get_namespace(), Some("team-a"));
+            assert_eq!(agent.get_register_info().get_token(), None);
+        }
+        let empty = Frame::Gossip(GossipInfo::new("agent1".to_owned(), Vec::new()));
+        assert!(
+            matches!(round_trip(&empty), Frame::Gossip(gossip_info) if gossip_info.get_agents().is_empty())
+        );
+    }
+
+    #[test]
+    fn go_away_round_trips_between_other_frames() {
+        let mut bytes = Vec::new();
+        for frame in [Frame::Ping, Frame::GoAway, Frame::Ack] {
+            bytes.extend_from_slice(&frame.serialization().unwrap());
+        }
+        let mut cursor = Cursor::new(&bytes[..]);
+        assert!(matches!(Frame::parse(&mut cursor), Ok(Frame::Ping)));
+        assert!(matches!(Frame::parse(&mut cursor), Ok(Frame::GoAway)));
+        assert!(matches!(Frame::parse(&mut cursor), Ok(Frame::Ack)));
+        assert_eq!(cursor.position() as usize, bytes.len());
+    }
+
+    #[test]
+    fn error_round_trips() {
+        let frame = Frame::Error("too many tunnels from 127.0.0.1, max 1".to_owned());
+        assert!(matches!(
+            round_trip(&frame),
+            Frame::Error(error) if error == "too many tunnels from 127.0.0.1, max 1"
+        ));
+    }
+
+    #[test]
+    fn oversized_frame_is_an_error() {
+        let frame = Frame::Error("x".repeat(u16::MAX as usize));
+        assert!(frame.serialization().is_err());
+        let frame = Frame::Error("x".repeat(u16::MAX as usize - 1));
+        assert!(matches!(round_trip(&frame), Frame::Error(error) if error.len() == 65534));
+    }
+
+    #[test]
+    fn empty_frame_is_an_error() {
+        let bytes = [b'0', 0, 0];
+        let mut cursor = Cursor::new(&bytes[..]);
+        assert!(matches!(Frame::parse(&mut cursor), Err(Error::Other(_))));
+        assert_eq!(cursor.position() as usize, bytes.len());
+    }
+
+    #[test]
+    fn incomplete_frame_waits_for_more() {
+        let bytes = Frame::Ping.serialization().unwrap();
+        let mut cursor = Cursor::new(&bytes[..bytes.len() - 1]);
+        assert!(matches!(Frame::parse(&mut cursor), Err(Error::Incomplete)));
+    }
+}
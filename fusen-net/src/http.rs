@@ -0,0 +1,181 @@
+//! A minimal HTTP/1.1 server for the admin and metrics endpoints: one request
+//! per connection, bodies sized by `Content-Length`.
+
+use crate::FusenFuture;
+use serde::Serialize;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info};
+
+const MAX_HEAD_SIZE: usize = 8 * 1024;
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug)]
+pub(crate) struct Request {
+    pub(crate) method: String,
+    pub(crate) path: String,
+    headers: Vec<(String, String)>,
+    pub(crate) body: Vec<u8>,
+}
+
+impl Request {
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The path split into its non-empty segments.
+    pub(crate) fn segments(&self) -> Vec<&str> {
+        self.path.split('/').filter(|s| !s.is_empty()).collect()
+    }
+}
+
+pub(crate) struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Response {
+    pub(crate) fn json<T: Serialize>(value: &T) -> Response {
+        match serde_json::to_vec(value) {
+            Ok(body) => Response {
+                status: 200,
+                content_type: "application/json",
+                body,
+            },
+            Err(error) => Response::error(500, &error.to_string()),
+        }
+    }
+
+    pub(crate) fn text(body: String) -> Response {
+        Response {
+            status: 200,
+            content_type: "text/plain; version=0.0.4",
+            body: body.into_bytes(),
+        }
+    }
+
+    pub(crate) fn error(status: u16, message: &str) -> Response {
+        Response {
+            status,
+            content_type: "application/json",
+            body: serde_json::json!({ "error": message })
+                .to_string()
+                .into_bytes(),
+        }
+    }
+
+    pub(crate) fn no_content() -> Response {
+        Response {
+            status: 204,
+            content_type: "application/json",
+            body: vec![],
+        }
+    }
+}
+
+pub(crate) type Handler = Arc<dyn Fn(Request) -> FusenFuture<Response> + Send + Sync>;
+
+pub(crate) async fn serve(listener: TcpListener, handler: Handler) {
+    loop {
+        let (stream, socket_addr) = match listener.accept().await {
+            Ok(res) => res,
+            Err(error) => {
+                info!("http accept err : {:?}", error);
+                continue;
+            }
+        };
+        let handler = handler.clone();
+        tokio::spawn(async move {
+            if let Err(error) = serve_connection(stream, handler).await {
+                debug!("http connection {:?} err : {:?}", socket_addr, error);
+            }
+        });
+    }
+}
+
+async fn serve_connection(stream: TcpStream, handler: Handler) -> Result<(), crate::Error> {
+    let mut stream = BufReader::new(stream);
+    let response = match read_request(&mut stream).await {
+        Ok(request) => handler(request).await,
+        Err(error) => Response::error(400, &error.to_string()),
+    };
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        reason(response.status),
+        response.content_type,
+        response.body.len()
+    );
+    let stream = stream.get_mut();
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(&response.body).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+async fn read_request(stream: &mut BufReader<TcpStream>) -> Result<Request, crate::Error> {
+    let mut head_size = 0;
+    let mut lines = vec![];
+    loop {
+        let mut line = String::new();
+        let len = (&mut *stream)
+            .take((MAX_HEAD_SIZE - head_size) as u64)
+            .read_line(&mut line)
+            .await?;
+        if len == 0 || !line.ends_with('\n') {
+            return Err("incomplete request head".into());
+        }
+        head_size += len;
+        let line = line.trim_end().to_owned();
+        if line.is_empty() {
+            break;
+        }
+        lines.push(line);
+    }
+    let mut lines = lines.into_iter();
+    let request_line = lines.next().ok_or("empty request")?;
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return Err(format!("bad request line : {}", request_line).into());
+    };
+    let path = target.split('?').next().unwrap_or_default().to_owned();
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            Some((key.trim().to_owned(), value.trim().to_owned()))
+        })
+        .collect();
+    let mut request = Request {
+        method: method.to_owned(),
+        path,
+        headers,
+        body: vec![],
+    };
+    let length: usize = match request.header("Content-Length") {
+        Some(length) => length.parse()?,
+        None => 0,
+    };
+    if length > MAX_BODY_SIZE {
+        return Err("request body too large".into());
+    }
+    request.body.resize(length, 0);
+    stream.read_exact(&mut request.body).await?;
+    Ok(request)
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "Internal Server Error",
+    }
+}
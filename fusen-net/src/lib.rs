@@ -1,17 +1,32 @@
-use std::{collections::HashMap, net::SocketAddr};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
 use frame::{Frame, RegisterInfo};
 use serde::{Deserialize, Serialize};
-use tokio::sync::mpsc::UnboundedSender;
+use server::balance::Load;
+use tokio::sync::{mpsc::UnboundedSender, Notify};
 pub mod buffer;
 pub mod client;
 pub mod common;
 pub mod connection;
 pub mod frame;
+mod http;
+pub mod limit;
+pub mod metrics;
+pub mod mux;
+pub mod selector;
 pub mod server;
 pub mod shutdown;
 pub mod socket;
 pub mod quic;
+pub mod tcp;
+pub mod tls;
+pub mod transport;
+pub mod ws;
 pub type Error = Box<dyn std::error::Error + Send + Sync>;
 pub type FusenFuture<T> = std::pin::Pin<Box<dyn std::future::Future<Output = T> + Send>>;
 
@@ -20,17 +35,45 @@ pub struct MetaData {
     pub inner: HashMap<String, String>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+impl MetaData {
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.inner.get(key).map(String::as_str)
+    }
+}
+
+impl From<HashMap<String, String>> for MetaData {
+    fn from(inner: HashMap<String, String>) -> Self {
+        MetaData { inner }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
 pub enum Protocol {
     V4,
     V6,
 }
 
+impl Protocol {
+    pub fn of(addr: &SocketAddr) -> Self {
+        match addr {
+            SocketAddr::V4(_) => Protocol::V4,
+            SocketAddr::V6(_) => Protocol::V6,
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct ChannelInfo {
+    tag: String,
     net_addr: SocketAddr,
     register_info: RegisterInfo,
     sender: UnboundedSender<Frame>,
+    created_at: SystemTime,
+    close: Arc<Notify>,
+    load: Arc<Load>,
+    /// The server of the cluster the agent is registered with, when it is
+    /// another one.
+    peer: Option<String>,
 }
 
 impl ChannelInfo {
@@ -40,9 +83,55 @@ impl ChannelInfo {
         sender: UnboundedSender<Frame>,
     ) -> Self {
         Self {
+            tag: server::namespace::qualify(register_info.get_namespace(), register_info.get_tag()),
             net_addr,
             register_info,
             sender,
+            created_at: SystemTime::now(),
+            close: Default::default(),
+            load: Default::default(),
+            peer: None,
         }
     }
+
+    /// An agent registered with the cluster peer at `server`.
+    pub(crate) fn with_peer(mut self, server: &str) -> Self {
+        self.peer = Some(server.to_owned());
+        self
+    }
+
+    pub fn peer(&self) -> Option<&str> {
+        self.peer.as_deref()
+    }
+
+    /// The tag qualified by its namespace, see [`server::namespace::qualify`].
+    pub fn tag(&self) -> &str {
+        &self.tag
+    }
+
+    pub fn net_addr(&self) -> SocketAddr {
+        self.net_addr
+    }
+
+    pub fn register_info(&self) -> &RegisterInfo {
+        &self.register_info
+    }
+
+    pub fn created_at(&self) -> SystemTime {
+        self.created_at
+    }
+
+    /// Tunnels relayed through this agent right now.
+    pub fn tunnels(&self) -> usize {
+        self.load.tunnels()
+    }
+
+    /// Smoothed round trip of the server's pings, once one was answered.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.load.rtt()
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.load.is_healthy()
+    }
 }
@@ -0,0 +1,74 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// A token bucket limiting throughput to `rate` bytes per second, with bursts of
+/// up to one second worth of bytes. A rate of 0 means unlimited. The rate can
+/// change at any time and applies to everything sharing the bucket.
+#[derive(Debug)]
+pub struct RateLimit {
+    rate: AtomicU64,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl RateLimit {
+    pub fn new(rate: u64) -> Self {
+        RateLimit {
+            rate: AtomicU64::new(rate),
+            bucket: Mutex::new(Bucket {
+                tokens: rate as f64,
+                updated_at: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn unlimited() -> Self {
+        Self::new(0)
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate.load(Ordering::Relaxed)
+    }
+
+    pub fn set_rate(&self, rate: u64) {
+        self.rate.store(rate, Ordering::Relaxed);
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        self.rate() == 0
+    }
+
+    /// Takes `len` bytes worth of tokens, waiting for the bucket to refill when
+    /// there aren't enough. Tokens may go into debt so that chunks larger than
+    /// the bucket still pass, the debt is paid by whoever comes next.
+    pub async fn acquire(&self, len: usize) {
+        let rate = self.rate();
+        if rate == 0 {
+            return;
+        }
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * rate as f64).min(rate as f64);
+            bucket.updated_at = now;
+            bucket.tokens -= len as f64;
+            -bucket.tokens / rate as f64
+        };
+        if wait > 0.0 {
+            tokio::time::sleep(Duration::from_secs_f64(wait)).await;
+        }
+    }
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        RateLimit::unlimited()
+    }
+}
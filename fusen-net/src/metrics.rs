@@ -0,0 +1,152 @@
+//! Prometheus metrics of the server and the client, served as text on `/metrics`.
+
+use crate::http::{self, Request, Response};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use std::sync::{Arc, OnceLock};
+use std::time::Instant;
+use tokio::net::TcpListener;
+use tracing::info;
+
+pub(crate) struct Metrics {
+    registry: Registry,
+    /// Agents registered with the server.
+    pub(crate) agents: IntGauge,
+    /// Open tunnels by mode.
+    pub(crate) tunnels: IntGaugeVec,
+    /// Bytes copied by tunnels, by target tag and direction.
+    pub(crate) relayed_bytes: IntCounterVec,
+    pub(crate) handshake_failures: IntCounter,
+    /// Time from asking for a tunnel until it carries data, by mode.
+    pub(crate) setup_seconds: HistogramVec,
+    pub(crate) frame_parse_errors: IntCounter,
+    /// Tunnels refused for breaking a connection limit.
+    pub(crate) rejected_tunnels: IntCounter,
+    /// Whether each server of the client answers, by address.
+    pub(crate) servers: IntGaugeVec,
+}
+
+pub(crate) fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(|| {
+        let metrics = Metrics {
+            registry: Registry::new_custom(Some("fusen_net".into()), None).unwrap(),
+            agents: IntGauge::new("agents", "Registered agents").unwrap(),
+            tunnels: IntGaugeVec::new(Opts::new("tunnels", "Open tunnels"), &["mode"]).unwrap(),
+            relayed_bytes: IntCounterVec::new(
+                Opts::new("relayed_bytes_total", "Bytes relayed by tunnels"),
+                &["tag", "direction"],
+            )
+            .unwrap(),
+            handshake_failures: IntCounter::new(
+                "handshake_failures_total",
+                "Connections that failed to complete their handshake",
+            )
+            .unwrap(),
+            setup_seconds: HistogramVec::new(
+                HistogramOpts::new(
+                    "connection_setup_seconds",
+                    "Time to set up a tunnel until it carries data",
+                ),
+                &["mode"],
+            )
+            .unwrap(),
+            frame_parse_errors: IntCounter::new(
+                "frame_parse_errors_total",
+                "Frames that failed to parse",
+            )
+            .unwrap(),
+            rejected_tunnels: IntCounter::new(
+                "rejected_tunnels_total",
+                "Tunnels refused for breaking a connection limit",
+            )
+            .unwrap(),
+            servers: IntGaugeVec::new(
+                Opts::new("server_up", "Whether the server answers the client"),
+                &["server"],
+            )
+            .unwrap(),
+        };
+        let registry = &metrics.registry;
+        registry.register(Box::new(metrics.agents.clone())).unwrap();
+        registry
+            .register(Box::new(metrics.tunnels.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(metrics.relayed_bytes.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(metrics.handshake_failures.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(metrics.setup_seconds.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(metrics.frame_parse_errors.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(metrics.rejected_tunnels.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(metrics.servers.clone()))
+            .unwrap();
+        metrics
+    })
+}
+
+impl Metrics {
+    /// Counts a tunnel of `mode` as open until the returned guard drops.
+    pub(crate) fn open_tunnel(&self, mode: &str) -> TunnelGuard {
+        let gauge = self.tunnels.with_label_values(&[mode]);
+        gauge.inc();
+        TunnelGuard(gauge)
+    }
+
+    pub(crate) fn observe_setup(&self, mode: &str, start: Instant) {
+        self.setup_seconds
+            .with_label_values(&[mode])
+            .observe(start.elapsed().as_secs_f64());
+    }
+
+    /// Byte counters of a tunnel to `tag`, for what it sends towards the target
+    /// and what it receives back.
+    pub(crate) fn relayed(&self, tag: &str) -> (IntCounter, IntCounter) {
+        (
+            self.relayed_bytes.with_label_values(&[tag, "sent"]),
+            self.relayed_bytes.with_label_values(&[tag, "received"]),
+        )
+    }
+
+    fn encode(&self) -> String {
+        let mut buffer = vec![];
+        let _ = TextEncoder::new().encode(&self.registry.gather(), &mut buffer);
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
+
+pub(crate) struct TunnelGuard(IntGauge);
+
+impl Drop for TunnelGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+/// Serves the metrics on `/metrics` at `addr`. The server runs this itself
+/// when built `with_metrics`; clients call it directly.
+pub async fn serve(addr: &str) -> Result<(), crate::Error> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("metrics start : {}", addr);
+    let handler: http::Handler = Arc::new(|request| Box::pin(async move { handle(request) }));
+    http::serve(listener, handler).await;
+    Ok(())
+}
+
+fn handle(request: Request) -> Response {
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/metrics") => Response::text(metrics().encode()),
+        _ => Response::error(404, "not found"),
+    }
+}
@@ -0,0 +1,194 @@
+use crate::buffer::StreamBuffer;
+use crate::tls::Verify;
+use crate::transport::Connection;
+use futures::future::BoxFuture;
+use futures::StreamExt;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::mpsc;
+use tokio_yamux::{Config, Control, Session, StreamHandle};
+use tracing::debug;
+
+/// A yamux session over a TLS or WebSocket stream, carrying one stream per
+/// channel the way a QUIC connection does.
+pub struct MuxConnection {
+    control: Control,
+    remote_addr: SocketAddr,
+    incoming: Option<mpsc::UnboundedReceiver<StreamHandle>>,
+}
+
+impl MuxConnection {
+    /// Starts the server side of a session, whose streams come out of `accept_stream`.
+    pub fn server<S>(stream: S, remote_addr: SocketAddr) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let mut session = Session::new_server(stream, Config::default());
+        let control = session.control();
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let stream = tokio::select! {
+                    res = session.next() => res,
+                    _ = sender.closed() => break,
+                };
+                let Some(Ok(stream)) = stream else {
+                    break;
+                };
+                if sender.send(stream).is_err() {
+                    break;
+                }
+            }
+            debug!("mux session close : {:?}", remote_addr);
+        });
+        MuxConnection {
+            control,
+            remote_addr,
+            incoming: Some(receiver),
+        }
+    }
+}
+
+impl Connection for MuxConnection {
+    fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+
+    fn open_stream(&self) -> BoxFuture<'_, Result<StreamBuffer, crate::Error>> {
+        Box::pin(async move {
+            let stream = self.control.clone().open_stream().await?;
+            Ok(StreamBuffer::from_stream(stream))
+        })
+    }
+
+    fn accept_stream(&mut self) -> BoxFuture<'_, Result<Option<StreamBuffer>, crate::Error>> {
+        Box::pin(async move {
+            let Some(incoming) = self.incoming.as_mut() else {
+                return Ok(None);
+            };
+            let stream = incoming.recv().await;
+            Ok(stream.map(StreamBuffer::from_stream))
+        })
+    }
+}
+
+/// How the server of a client session was verified. A session is only
+/// reused by callers that would have verified the server the same way.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Verified {
+    verify: Verify,
+    server_name: String,
+}
+
+impl Verified {
+    pub fn new(verify: &Verify, server_name: &str) -> Self {
+        Verified {
+            verify: verify.clone(),
+            server_name: server_name.to_owned(),
+        }
+    }
+}
+
+struct SessionEntry {
+    id: u64,
+    control: Control,
+    remote_addr: SocketAddr,
+    verified: Verified,
+}
+
+/// Client sessions keyed by server address, shared so that every stream opened
+/// to the same server is multiplexed over a single underlying connection.
+fn sessions() -> &'static Mutex<HashMap<String, SessionEntry>> {
+    static SESSIONS: OnceLock<Mutex<HashMap<String, SessionEntry>>> = OnceLock::new();
+    SESSIONS.get_or_init(Default::default)
+}
+
+/// Whether a session to `key` is currently open.
+pub fn is_connected(key: &str) -> bool {
+    sessions().lock().unwrap().contains_key(key)
+}
+
+/// The live session to `key`, if there is one whose server was verified as
+/// `verified` asks.
+pub fn session(key: &str, verified: &Verified) -> Option<MuxConnection> {
+    sessions()
+        .lock()
+        .unwrap()
+        .get(key)
+        .filter(|entry| entry.verified == *verified)
+        .map(|entry| MuxConnection {
+            control: entry.control.clone(),
+            remote_addr: entry.remote_addr,
+            incoming: None,
+        })
+}
+
+/// Starts a client session over `stream`, to a server verified as `verified`,
+/// and registers it under `key` in place of any other.
+pub fn start_session<S>(
+    key: String,
+    verified: Verified,
+    stream: S,
+    remote_addr: SocketAddr,
+) -> MuxConnection
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+    let mut session = Session::new_client(stream, Config::default());
+    let control = session.control();
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    sessions().lock().unwrap().insert(
+        key.clone(),
+        SessionEntry {
+            id,
+            control: control.clone(),
+            remote_addr,
+            verified,
+        },
+    );
+    tokio::spawn(async move {
+        // The server never opens streams towards clients, polling only drives the session.
+        while let Some(Ok(_stream)) = session.next().await {}
+        debug!("mux session close : {:?}", key);
+        let mut sessions = sessions().lock().unwrap();
+        if sessions.get(&key).is_some_and(|entry| entry.id == id) {
+            sessions.remove(&key);
+        }
+    });
+    MuxConnection {
+        control,
+        remote_addr,
+        incoming: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn sessions_are_reused_only_as_verified() {
+        let (stream, _server) = tokio::io::duplex(1024);
+        let addr: SocketAddr = "127.0.0.1:9".parse().unwrap();
+        let pinned = Verify::default()
+            .with_pin(&"ab".repeat(32))
+            .map(|verify| Verified::new(&verify, "127.0.0.1"))
+            .unwrap();
+        let _connection = start_session(
+            addr.to_string(),
+            Verified::new(&Verify::insecure(), "127.0.0.1"),
+            stream,
+            addr,
+        );
+        let key = addr.to_string();
+        assert!(is_connected(&key));
+        assert!(session(&key, &Verified::new(&Verify::insecure(), "127.0.0.1")).is_some());
+        assert!(session(&key, &Verified::new(&Verify::default(), "127.0.0.1")).is_none());
+        assert!(session(&key, &Verified::new(&Verify::insecure(), "localhost")).is_none());
+        assert!(session(&key, &pinned).is_none());
+    }
+}
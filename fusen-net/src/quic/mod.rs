@@ -1,56 +1,158 @@
-use crate::buffer::QuicBuffer;
+use crate::buffer::StreamBuffer;
+use crate::socket::{canonical, resolve, unspecified_for};
+use crate::tls::{self, Identity, Verify};
+use crate::transport::{Connecting, Connection, Listener, Transport};
+use futures::future::BoxFuture;
 use quinn::{ClientConfig, Endpoint};
-use std::{net::SocketAddr, sync::Arc};
-use support::make_client_endpoint;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use support::{make_client_endpoint, make_server_endpoint_with};
 pub mod support;
 
-pub async fn connect(target_host: SocketAddr) -> Result<(QuicBuffer, SocketAddr), crate::Error> {
-    let mut endpoint = make_client_endpoint("0.0.0.0:0".parse()?, &[])?;
-    let local_addr = endpoint.local_addr().unwrap();
-    endpoint.set_default_client_config(get_config());
-    let connection = endpoint.connect(target_host, "fusen-net")?.await?;
-    let (send_stream, recv_stream) = connection.open_bi().await?;
-    let buffer = QuicBuffer::new(send_stream, recv_stream);
-    Ok((buffer, local_addr))
-}
-pub async fn connect_reuse(
-    endpoint: &mut Endpoint,
+pub async fn connect(
     target_host: SocketAddr,
-) -> Result<(QuicBuffer, SocketAddr), crate::Error> {
+    verify: &Verify,
+) -> Result<(StreamBuffer, SocketAddr), crate::Error> {
+    let mut endpoint = make_client_endpoint(unspecified_for(&target_host), &[])?;
     let local_addr = endpoint.local_addr().unwrap();
-    endpoint.set_default_client_config(get_config());
-    let connection = endpoint.connect(target_host, "fusen-net")?.await?;
+    endpoint.set_default_client_config(client_config(verify));
+    let server_name = target_host.ip().to_string();
+    let connection = endpoint.connect(target_host, &server_name)?.await?;
     let (send_stream, recv_stream) = connection.open_bi().await?;
-    let buffer = QuicBuffer::new(send_stream, recv_stream);
+    let buffer = StreamBuffer::new(send_stream, recv_stream);
     Ok((buffer, local_addr))
 }
 
-struct SkipServerVerification;
+/// QUIC transport. Once listening, it dials from the listening endpoint, so that
+/// peers can reach the listener through the NAT mapping opened by the dial.
+#[derive(Default)]
+pub struct QuicTransport {
+    endpoint: Mutex<Option<Endpoint>>,
+    identity: Option<Identity>,
+    verify: Verify,
+}
 
-impl SkipServerVerification {
-    fn new() -> Arc<Self> {
-        Arc::new(Self)
+impl QuicTransport {
+    /// Listens presenting `identity` instead of a self-signed certificate.
+    pub fn with_identity(identity: Identity) -> Self {
+        QuicTransport {
+            identity: Some(identity),
+            ..Default::default()
+        }
+    }
+
+    /// Verifies the servers it dials with `verify` rather than the system's CAs.
+    pub fn with_verify(mut self, verify: Verify) -> Self {
+        self.verify = verify;
+        self
     }
-}
 
-impl rustls::client::ServerCertVerifier for SkipServerVerification {
-    fn verify_server_cert(
+    async fn connect(
         &self,
-        _end_entity: &rustls::Certificate,
-        _intermediates: &[rustls::Certificate],
-        _server_name: &rustls::ServerName,
-        _scts: &mut dyn Iterator<Item = &[u8]>,
-        _ocsp_response: &[u8],
-        _now: std::time::SystemTime,
-    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
-        Ok(rustls::client::ServerCertVerified::assertion())
-    }
-}
-
-pub fn get_config() -> ClientConfig {
-    let crypto = rustls::ClientConfig::builder()
-        .with_safe_defaults()
-        .with_custom_certificate_verifier(SkipServerVerification::new())
-        .with_no_client_auth();
-    ClientConfig::new(Arc::new(crypto))
+        addr: &str,
+        config: ClientConfig,
+        server_name: &str,
+    ) -> Result<Box<dyn Connection>, crate::Error> {
+        let target_host = resolve(addr).await?;
+        let endpoint = self.endpoint.lock().unwrap().clone();
+        let endpoint = match endpoint {
+            Some(endpoint) => endpoint,
+            None => make_client_endpoint(unspecified_for(&target_host), &[])?,
+        };
+        let connection = endpoint
+            .connect_with(config, target_host, server_name)?
+            .await?;
+        Ok(Box::new(QuicConnection { connection }) as Box<dyn Connection>)
+    }
+}
+
+impl Transport for QuicTransport {
+    fn listen<'a>(
+        &'a self,
+        addr: &'a str,
+    ) -> BoxFuture<'a, Result<Box<dyn Listener>, crate::Error>> {
+        Box::pin(async move {
+            let identity = match &self.identity {
+                Some(identity) => identity.clone(),
+                None => Identity::self_signed()?,
+            };
+            let endpoint = make_server_endpoint_with(addr.parse()?, identity)?.0;
+            self.endpoint
+                .lock()
+                .unwrap()
+                .get_or_insert_with(|| endpoint.clone());
+            Ok(Box::new(QuicListener { endpoint }) as Box<dyn Listener>)
+        })
+    }
+
+    fn dial<'a>(
+        &'a self,
+        addr: &'a str,
+    ) -> BoxFuture<'a, Result<Box<dyn Connection>, crate::Error>> {
+        let config = client_config(&self.verify);
+        Box::pin(self.connect(addr, config, tls::server_name(addr)))
+    }
+
+    /// Agents listen with self-signed certificates, so these aren't verified.
+    fn dial_agent<'a>(
+        &'a self,
+        addr: &'a str,
+    ) -> BoxFuture<'a, Result<Box<dyn Connection>, crate::Error>> {
+        let config = client_config(&Verify::insecure());
+        Box::pin(self.connect(addr, config, "fusen-net"))
+    }
+}
+
+struct QuicListener {
+    endpoint: Endpoint,
+}
+
+impl Listener for QuicListener {
+    fn local_addr(&self) -> Result<SocketAddr, crate::Error> {
+        Ok(self.endpoint.local_addr()?)
+    }
+
+    fn accept(&mut self) -> BoxFuture<'_, Result<Connecting, crate::Error>> {
+        Box::pin(async move {
+            let connecting = self.endpoint.accept().await.ok_or("endpoint closed")?;
+            let connecting: Connecting = Box::pin(async move {
+                let connection = connecting.await?;
+                Ok(Box::new(QuicConnection { connection }) as Box<dyn Connection>)
+            });
+            Ok(connecting)
+        })
+    }
+}
+
+struct QuicConnection {
+    connection: quinn::Connection,
+}
+
+impl Connection for QuicConnection {
+    fn remote_addr(&self) -> SocketAddr {
+        canonical(self.connection.remote_address())
+    }
+
+    fn open_stream(&self) -> BoxFuture<'_, Result<StreamBuffer, crate::Error>> {
+        Box::pin(async move {
+            let (send_stream, recv_stream) = self.connection.open_bi().await?;
+            Ok(StreamBuffer::new(send_stream, recv_stream))
+        })
+    }
+
+    fn accept_stream(&mut self) -> BoxFuture<'_, Result<Option<StreamBuffer>, crate::Error>> {
+        Box::pin(async move {
+            match self.connection.accept_bi().await {
+                Ok((send_stream, recv_stream)) => {
+                    Ok(Some(StreamBuffer::new(send_stream, recv_stream)))
+                }
+                Err(quinn::ConnectionError::ApplicationClosed(_)) => Ok(None),
+                Err(error) => Err(error.into()),
+            }
+        })
+    }
+}
+
+fn client_config(verify: &Verify) -> ClientConfig {
+    ClientConfig::new(Arc::new(verify.client_config()))
 }
@@ -1,6 +1,8 @@
 //! Commonly used code in most examples.
 
-use quinn::{ClientConfig, Endpoint, ServerConfig};
+use crate::socket::bind_udp;
+use crate::tls::Identity;
+use quinn::{ClientConfig, Endpoint, EndpointConfig, ServerConfig, TokioRuntime};
 use std::{net::SocketAddr, sync::Arc};
 
 /// Constructs a QUIC endpoint configured for use a client only.
@@ -14,7 +16,12 @@ pub fn make_client_endpoint(
     server_certs: &[&[u8]],
 ) -> Result<Endpoint, crate::Error> {
     let client_cfg = configure_client(server_certs)?;
-    let mut endpoint = Endpoint::client(bind_addr)?;
+    let endpoint = Endpoint::new(
+        EndpointConfig::default(),
+        None,
+        bind_udp(bind_addr)?,
+        Arc::new(TokioRuntime),
+    )?;
     Ok(endpoint)
 }
 
@@ -27,8 +34,21 @@ pub fn make_client_endpoint(
 /// - server certificate serialized into DER format
 #[allow(unused)]
 pub fn make_server_endpoint(bind_addr: SocketAddr) -> Result<(Endpoint, Vec<u8>), crate::Error> {
-    let (server_config, server_cert) = configure_server()?;
-    let endpoint = Endpoint::server(server_config, bind_addr)?;
+    make_server_endpoint_with(bind_addr, Identity::self_signed()?)
+}
+
+/// Like [`make_server_endpoint`], presenting `identity`.
+pub fn make_server_endpoint_with(
+    bind_addr: SocketAddr,
+    identity: Identity,
+) -> Result<(Endpoint, Vec<u8>), crate::Error> {
+    let (server_config, server_cert) = configure_server(identity)?;
+    let endpoint = Endpoint::new(
+        EndpointConfig::default(),
+        Some(server_config),
+        bind_udp(bind_addr)?,
+        Arc::new(TokioRuntime),
+    )?;
     Ok((endpoint, server_cert))
 }
 
@@ -48,13 +68,9 @@ fn configure_client(server_certs: &[&[u8]]) -> Result<ClientConfig, crate::Error
 }
 
 /// Returns default server configuration along with its certificate.
-fn configure_server() -> Result<(ServerConfig, Vec<u8>), crate::Error> {
-    let cert = rcgen::generate_simple_self_signed(vec!["fusen-net".into()]).unwrap();
-    let cert_der = cert.serialize_der().unwrap();
-    let priv_key = cert.serialize_private_key_der();
-    let priv_key = rustls::PrivateKey(priv_key);
-    let cert_chain = vec![rustls::Certificate(cert_der.clone())];
-    let mut server_config = ServerConfig::with_single_cert(cert_chain, priv_key)?;
+fn configure_server(identity: Identity) -> Result<(ServerConfig, Vec<u8>), crate::Error> {
+    let cert_der = identity.cert_chain[0].0.clone();
+    let mut server_config = ServerConfig::with_single_cert(identity.cert_chain, identity.key)?;
     let transport_config = Arc::get_mut(&mut server_config.transport).unwrap();
     transport_config.max_concurrent_uni_streams(0_u8.into());
     Ok((server_config, cert_der))
@@ -0,0 +1,143 @@
+//! Label selectors such as `env=prod,site=sh`, picking agents by the labels
+//! they registered with instead of by tag.
+
+use crate::MetaData;
+use std::fmt;
+use std::str::FromStr;
+
+/// Requirements on labels that all have to hold, separated by `,`: `key=value`
+/// or `key!=value`, the latter also met by agents without the label.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Selector {
+    requirements: Vec<Requirement>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Requirement {
+    Equals(String, String),
+    NotEquals(String, String),
+}
+
+impl Selector {
+    /// Whether `value` is meant as a selector rather than a tag.
+    pub fn is_selector(value: &str) -> bool {
+        value.contains('=')
+    }
+
+    pub fn matches(&self, labels: &MetaData) -> bool {
+        self.requirements
+            .iter()
+            .all(|requirement| match requirement {
+                Requirement::Equals(key, value) => labels.get(key) == Some(value.as_str()),
+                Requirement::NotEquals(key, value) => labels.get(key) != Some(value.as_str()),
+            })
+    }
+}
+
+impl FromStr for Selector {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let requirements = value
+            .split(',')
+            .map(|requirement| {
+                let requirement = requirement.trim();
+                let (key, label, not) = match requirement.split_once("!=") {
+                    Some((key, label)) => (key, label, true),
+                    None => match requirement.split_once('=') {
+                        Some((key, label)) => (key, label, false),
+                        None => ("", "", false),
+                    },
+                };
+                let (key, label) = (key.trim(), label.trim());
+                if !is_label(key) || !is_label(label) {
+                    return Err(format!(
+                        "expected key=value or key!=value, got `{}`",
+                        requirement
+                    ));
+                }
+                Ok(match not {
+                    true => Requirement::NotEquals(key.to_owned(), label.to_owned()),
+                    false => Requirement::Equals(key.to_owned(), label.to_owned()),
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Selector { requirements })
+    }
+}
+
+impl fmt::Display for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, requirement) in self.requirements.iter().enumerate() {
+            if index > 0 {
+                f.write_str(",")?;
+            }
+            match requirement {
+                Requirement::Equals(key, value) => write!(f, "{}={}", key, value)?,
+                Requirement::NotEquals(key, value) => write!(f, "{}!={}", key, value)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether `value` may be a label key or value: not empty, and none of the
+/// characters selectors and agent specifications are split on.
+pub fn is_label(value: &str) -> bool {
+    !value.is_empty() && !value.contains(|c: char| c.is_whitespace() || "=!,/&?".contains(c))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn labels(labels: &[(&str, &str)]) -> MetaData {
+        labels
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect::<HashMap<_, _>>()
+            .into()
+    }
+
+    #[test]
+    fn selectors_parse_and_print_back() {
+        let selector: Selector = " env = prod , site!=sh ".parse().unwrap();
+        assert_eq!(selector.to_string(), "env=prod,site!=sh");
+        assert_eq!(selector.to_string().parse(), Ok(selector));
+    }
+
+    #[test]
+    fn malformed_selectors_are_errors() {
+        for value in [
+            "",
+            "env",
+            "env=",
+            "=prod",
+            "env==prod",
+            "env=prod,",
+            "env=a b",
+            "env=a/b",
+        ] {
+            assert!(value.parse::<Selector>().is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn every_requirement_has_to_match() {
+        let selector: Selector = "env=prod,site!=sh".parse().unwrap();
+        assert!(selector.matches(&labels(&[("env", "prod"), ("site", "bj")])));
+        assert!(selector.matches(&labels(&[("env", "prod")])));
+        assert!(!selector.matches(&labels(&[("env", "prod"), ("site", "sh")])));
+        assert!(!selector.matches(&labels(&[("env", "test")])));
+        assert!(!selector.matches(&labels(&[])));
+    }
+
+    #[test]
+    fn selectors_are_told_from_tags() {
+        assert!(Selector::is_selector("env=prod"));
+        assert!(!Selector::is_selector("agent1"));
+        assert!(is_label("agent-1.a_b"));
+        assert!(!is_label("") && !is_label("a,b") && !is_label("a b"));
+    }
+}
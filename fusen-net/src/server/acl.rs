@@ -0,0 +1,67 @@
+use std::collections::{HashMap, HashSet};
+
+/// Which agents may open tunnels to a tag or look up its address, by the
+/// source agent's tag. Tags without a rule are open to every agent.
+#[derive(Clone, Debug, Default)]
+pub struct Acl {
+    rules: HashMap<String, HashSet<String>>,
+}
+
+impl Acl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Lets only `source_tags` reach `target_tag`.
+    pub fn allow<I, S>(mut self, target_tag: &str, source_tags: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.rules
+            .entry(target_tag.to_owned())
+            .or_default()
+            .extend(source_tags.into_iter().map(Into::into));
+        self
+    }
+
+    pub(crate) fn check(&self, source_tag: Option<&str>, target_tag: &str) -> Result<(), String> {
+        let Some(sources) = self.rules.get(target_tag) else {
+            return Ok(());
+        };
+        match source_tag {
+            Some(source_tag) if sources.contains(source_tag) => Ok(()),
+            Some(source_tag) => Err(format!("{} may not reach {}", source_tag, target_tag)),
+            None => Err(format!("untagged agents may not reach {}", target_tag)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tags_without_rules_are_open() {
+        let acl = Acl::new().allow("agent1", ["agent2"]);
+        assert!(acl.check(None, "agent3").is_ok());
+        assert!(acl.check(Some("agent2"), "agent3").is_ok());
+    }
+
+    #[test]
+    fn only_allowed_sources_reach_a_ruled_tag() {
+        let acl = Acl::new()
+            .allow("agent1", ["agent2"])
+            .allow("agent1", ["agent3"]);
+        assert!(acl.check(Some("agent2"), "agent1").is_ok());
+        assert!(acl.check(Some("agent3"), "agent1").is_ok());
+        assert_eq!(
+            acl.check(Some("agent4"), "agent1"),
+            Err("agent4 may not reach agent1".to_owned())
+        );
+        assert_eq!(
+            acl.check(None, "agent1"),
+            Err("untagged agents may not reach agent1".to_owned())
+        );
+    }
+}
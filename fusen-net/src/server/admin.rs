@@ -0,0 +1,214 @@
+//! The admin endpoint. Every request needs `Authorization: Bearer <token>`.
+//!
+//! - `GET /agents` known agents, connected or not
+//! - `DELETE /agents/{tag}` disconnects every agent of a tag
+//! - `PUT /agents/{tag}/definition` with `{"token": "...", "services": [...]}`
+//!   defines an agent, `DELETE` forgets the definition
+//! - `GET /tunnels` tunnels relayed by the server
+//! - `DELETE /tunnels/{id}` closes a tunnel
+//! - `GET /subscribers` DM agents subscribed to a tag
+//! - `GET /limits` bandwidth limits in bytes per second
+//! - `PUT /limits/global`, `/limits/tunnel`, `/limits/tags/{tag}` or
+//!   `/limits/services/{tag}/{target_host}` with `{"rate": 1048576}` sets a
+//!   limit, 0 removes it
+//! - `POST /reload` reloads the settings, like SIGHUP
+
+use super::registry::{AgentDefinition, MemberRecord};
+use super::state::State;
+use crate::client::AgentMode;
+use crate::http::{Request, Response};
+use crate::MetaData;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::info;
+
+#[derive(Serialize)]
+struct AgentView {
+    tag: String,
+    online: bool,
+    net_addr: Option<SocketAddr>,
+    mate_data: MetaData,
+    uptime_secs: Option<u64>,
+    /// Milliseconds since the Unix epoch.
+    last_seen: Option<u64>,
+    defined: bool,
+    services: Vec<String>,
+    members: Vec<MemberRecord>,
+}
+
+#[derive(Serialize)]
+struct TunnelView {
+    id: String,
+    agent_mode: AgentMode,
+    source_tag: Option<String>,
+    source_addr: SocketAddr,
+    target_tag: String,
+    target_host: String,
+    uptime_secs: u64,
+    bytes_sent: u64,
+    bytes_received: u64,
+}
+
+#[derive(Serialize)]
+struct SubscriberView {
+    id: String,
+    net_addr: SocketAddr,
+    target_tag: String,
+    uptime_secs: u64,
+}
+
+#[derive(Deserialize)]
+struct RateBody {
+    rate: u64,
+}
+
+pub(crate) async fn handle(state: Arc<State>, request: Request) -> Response {
+    let token = state.admin_token();
+    let authorized = request
+        .header("Authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|value| token.is_some_and(|token| value == &*token));
+    if !authorized {
+        return Response::error(401, "invalid admin token");
+    }
+    match (request.method.as_str(), request.segments().as_slice()) {
+        ("GET", ["agents"]) => {
+            let mut agents: Vec<AgentView> = state
+                .registry
+                .list()
+                .into_iter()
+                .map(|record| AgentView {
+                    tag: record.tag,
+                    online: record.net_addr.is_some(),
+                    net_addr: record.net_addr,
+                    mate_data: record.mate_data,
+                    uptime_secs: record
+                        .connected_at
+                        .map(|connected_at| UNIX_EPOCH + Duration::from_millis(connected_at))
+                        .map(uptime_secs),
+                    last_seen: record.last_seen,
+                    defined: record.definition.is_some(),
+                    services: record
+                        .definition
+                        .map(|definition| definition.services)
+                        .unwrap_or_default(),
+                    members: record.members,
+                })
+                .collect();
+            agents.sort_by(|a, b| a.tag.cmp(&b.tag));
+            Response::json(&agents)
+        }
+        ("DELETE", ["agents", tag]) => {
+            let members = state.registry.members(tag);
+            if members.is_empty() {
+                return Response::error(404, "agent not found");
+            }
+            for channel_info in members {
+                channel_info.close.notify_one();
+            }
+            Response::no_content()
+        }
+        ("PUT", ["agents", tag, "definition"]) => {
+            let definition = match serde_json::from_slice::<AgentDefinition>(&request.body) {
+                Ok(definition) => AgentDefinition {
+                    tag: tag.to_string(),
+                    ..definition
+                },
+                Err(error) => return Response::error(400, &error.to_string()),
+            };
+            match state.registry.define(definition) {
+                Ok(()) => Response::no_content(),
+                Err(error) => Response::error(500, &error.to_string()),
+            }
+        }
+        ("DELETE", ["agents", tag, "definition"]) => match state.registry.undefine(tag) {
+            Ok(true) => Response::no_content(),
+            Ok(false) => Response::error(404, "definition not found"),
+            Err(error) => Response::error(500, &error.to_string()),
+        },
+        ("GET", ["tunnels"]) => {
+            let tunnels: Vec<TunnelView> = state
+                .tunnels
+                .lock()
+                .unwrap()
+                .values()
+                .map(|tunnel| TunnelView {
+                    id: tunnel.id.clone(),
+                    agent_mode: tunnel.agent_mode.clone(),
+                    source_tag: tunnel.agent_tag.clone(),
+                    source_addr: tunnel.source_addr,
+                    target_tag: tunnel.target_tag.clone(),
+                    target_host: tunnel.target_host.clone(),
+                    uptime_secs: uptime_secs(tunnel.created_at),
+                    bytes_sent: tunnel.sent.get(),
+                    bytes_received: tunnel.received.get(),
+                })
+                .collect();
+            Response::json(&tunnels)
+        }
+        ("DELETE", ["tunnels", id]) => {
+            let tunnel = state.tunnels.lock().unwrap().get(*id).cloned();
+            match tunnel {
+                Some(tunnel) => {
+                    tunnel.close.notify_one();
+                    Response::no_content()
+                }
+                None => Response::error(404, "tunnel not found"),
+            }
+        }
+        ("GET", ["subscribers"]) => {
+            let subscribers: Vec<SubscriberView> = state
+                .subscribers
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(id, subscriber)| SubscriberView {
+                    id: id.clone(),
+                    net_addr: subscriber.net_addr,
+                    target_tag: subscriber.target_tag.clone(),
+                    uptime_secs: uptime_secs(subscriber.created_at),
+                })
+                .collect();
+            Response::json(&subscribers)
+        }
+        ("GET", ["limits"]) => Response::json(&state.bandwidth.view()),
+        ("PUT", ["limits", ..]) => {
+            let rate = match serde_json::from_slice::<RateBody>(&request.body) {
+                Ok(body) => body.rate,
+                Err(error) => return Response::error(400, &error.to_string()),
+            };
+            let bandwidth = &state.bandwidth;
+            match request.segments().as_slice() {
+                ["limits", "global"] => bandwidth.set_global(rate),
+                ["limits", "tunnel"] => state.set_tunnel_limit(rate),
+                ["limits", "tags", tag] => bandwidth.set_tag(tag, rate),
+                ["limits", "services", tag, target_host] => {
+                    bandwidth.set_service(tag, target_host, rate)
+                }
+                _ => return Response::error(404, "not found"),
+            }
+            Response::json(&bandwidth.view())
+        }
+        ("POST", ["reload"]) => match &state.reloader {
+            Some(reloader) => match reloader() {
+                Ok(settings) => {
+                    state.reload(settings);
+                    info!("settings reloaded");
+                    Response::no_content()
+                }
+                Err(error) => Response::error(400, &error.to_string()),
+            },
+            None => Response::error(404, "reload is not configured"),
+        },
+        (_, ["agents" | "tunnels" | "subscribers" | "limits" | "reload", ..]) => {
+            Response::error(405, "method not allowed")
+        }
+        _ => Response::error(404, "not found"),
+    }
+}
+
+fn uptime_secs(since: SystemTime) -> u64 {
+    since.elapsed().unwrap_or_default().as_secs()
+}
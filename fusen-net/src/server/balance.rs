@@ -0,0 +1,275 @@
+//! Picking one of the agents registered under the same tag for each tunnel.
+
+use crate::ChannelInfo;
+use rand::Rng;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// How to spread tunnels over the agents sharing a tag.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Balance {
+    /// Each agent in turn.
+    #[default]
+    RoundRobin,
+    /// The agent relaying the fewest tunnels.
+    LeastConnections,
+    /// A random agent, favouring the ones answering pings quickly.
+    LatencyWeighted,
+}
+
+impl Balance {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Balance::RoundRobin => "round_robin",
+            Balance::LeastConnections => "least_connections",
+            Balance::LatencyWeighted => "latency_weighted",
+        }
+    }
+}
+
+impl fmt::Display for Balance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Balance {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        [
+            Balance::RoundRobin,
+            Balance::LeastConnections,
+            Balance::LatencyWeighted,
+        ]
+        .into_iter()
+        .find(|balance| balance.as_str() == value)
+        .ok_or_else(|| {
+            format!(
+                "expected round_robin, least_connections or latency_weighted, got `{}`",
+                value
+            )
+        })
+    }
+}
+
+/// How busy and how responsive a registered agent is.
+#[derive(Debug, Default)]
+pub(crate) struct Load {
+    tunnels: AtomicUsize,
+    /// Pings sent since the agent last said anything.
+    missed: AtomicU32,
+    /// Smoothed ping round trip, zero until the first answer.
+    rtt_micros: AtomicU64,
+    /// When the agent last got a tunnel, counted in picks, zero if never.
+    picked: AtomicU64,
+}
+
+impl Load {
+    pub(crate) fn tunnels(&self) -> usize {
+        self.tunnels.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_missed(&self, missed: u32) {
+        self.missed.store(missed, Ordering::Relaxed);
+    }
+
+    /// An agent is healthy until a ping goes unanswered for a whole interval.
+    pub(crate) fn is_healthy(&self) -> bool {
+        self.missed.load(Ordering::Relaxed) < 2
+    }
+
+    pub(crate) fn rtt(&self) -> Option<Duration> {
+        match self.rtt_micros.load(Ordering::Relaxed) {
+            0 => None,
+            micros => Some(Duration::from_micros(micros)),
+        }
+    }
+
+    pub(crate) fn observe_rtt(&self, rtt: Duration) {
+        let sample = (rtt.as_micros() as u64).max(1);
+        let rtt = match self.rtt_micros.load(Ordering::Relaxed) {
+            0 => sample,
+            previous => (previous * 4 + sample) / 5,
+        };
+        self.rtt_micros.store(rtt.max(1), Ordering::Relaxed);
+    }
+
+    /// Counts a tunnel against the agent until the guard drops.
+    pub(crate) fn open_tunnel(self: &Arc<Self>) -> TunnelLoad {
+        self.tunnels.fetch_add(1, Ordering::Relaxed);
+        TunnelLoad(self.clone())
+    }
+}
+
+pub(crate) struct TunnelLoad(Arc<Load>);
+
+impl Drop for TunnelLoad {
+    fn drop(&mut self) {
+        self.0.tunnels.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Applies the configured [`Balance`]. Turns go to the agent picked least
+/// recently, so they need no state beyond the agents themselves.
+#[derive(Default)]
+pub(crate) struct Balancer {
+    balance: RwLock<Balance>,
+    picks: AtomicU64,
+}
+
+impl Balancer {
+    pub(crate) fn new(balance: Balance) -> Self {
+        Balancer {
+            balance: RwLock::new(balance),
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn set(&self, balance: Balance) {
+        *self.balance.write().unwrap() = balance;
+    }
+
+    /// One of `agents`, skipping unhealthy ones unless none is healthy.
+    pub(crate) fn pick(&self, agents: Vec<Arc<ChannelInfo>>) -> Option<Arc<ChannelInfo>> {
+        let (healthy, unhealthy): (Vec<_>, Vec<_>) = agents
+            .into_iter()
+            .partition(|agent| agent.load.is_healthy());
+        let agents = if healthy.is_empty() {
+            unhealthy
+        } else {
+            healthy
+        };
+        let picked = |agent: &Arc<ChannelInfo>| agent.load.picked.load(Ordering::Relaxed);
+        let agent = match *self.balance.read().unwrap() {
+            Balance::RoundRobin => agents.iter().min_by_key(|agent| picked(agent)),
+            // Ties go round, so idle agents share the first tunnels.
+            Balance::LeastConnections => agents
+                .iter()
+                .min_by_key(|agent| (agent.load.tunnels(), picked(agent))),
+            Balance::LatencyWeighted => agents.get(weighted(&agents)),
+        }?;
+        let pick = self.picks.fetch_add(1, Ordering::Relaxed) + 1;
+        agent.load.picked.store(pick, Ordering::Relaxed);
+        Some(agent.clone())
+    }
+}
+
+/// A random index, each agent weighted by the inverse of its round trip.
+/// Agents not measured yet weigh as much as the average measured one.
+fn weighted(agents: &[Arc<ChannelInfo>]) -> usize {
+    let weights: Vec<Option<f64>> = agents
+        .iter()
+        .map(|agent| agent.load.rtt().map(|rtt| 1.0 / rtt.as_secs_f64()))
+        .collect();
+    let known: Vec<f64> = weights.iter().flatten().copied().collect();
+    let default = match known.len() {
+        0 => 1.0,
+        len => known.iter().sum::<f64>() / len as f64,
+    };
+    let weights: Vec<f64> = weights
+        .into_iter()
+        .map(|weight| weight.unwrap_or(default))
+        .collect();
+    let mut point = rand::thread_rng().gen_range(0.0..weights.iter().sum::<f64>());
+    for (index, weight) in weights.iter().enumerate() {
+        if point < *weight {
+            return index;
+        }
+        point -= weight;
+    }
+    weights.len() - 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::RegisterInfo;
+    use tokio::sync::mpsc;
+
+    fn agents(count: u16) -> Vec<Arc<ChannelInfo>> {
+        (0..count)
+            .map(|index| {
+                Arc::new(ChannelInfo::new(
+                    ([127, 0, 0, 1], 4000 + index).into(),
+                    RegisterInfo::new("server".to_owned(), "agent1".to_owned()),
+                    mpsc::unbounded_channel().0,
+                ))
+            })
+            .collect()
+    }
+
+    fn port(agent: Option<Arc<ChannelInfo>>) -> u16 {
+        agent.unwrap().net_addr().port()
+    }
+
+    #[test]
+    fn balance_parses_its_names() {
+        for balance in [
+            Balance::RoundRobin,
+            Balance::LeastConnections,
+            Balance::LatencyWeighted,
+        ] {
+            assert_eq!(balance.to_string().parse(), Ok(balance));
+        }
+        assert!("random".parse::<Balance>().is_err());
+    }
+
+    #[test]
+    fn round_robin_takes_turns_whatever_the_selection() {
+        let balancer = Balancer::new(Balance::RoundRobin);
+        let agents = agents(3);
+        let ports: Vec<_> = (0..6)
+            .map(|_| port(balancer.pick(agents.clone())))
+            .collect();
+        assert_eq!(ports, [4000, 4001, 4002, 4000, 4001, 4002]);
+        // A narrower selection goes on from the agents it holds.
+        assert_eq!(port(balancer.pick(agents[1..].to_vec())), 4001);
+        assert_eq!(port(balancer.pick(agents.clone())), 4000);
+        assert!(balancer.pick(Vec::new()).is_none());
+    }
+
+    #[test]
+    fn least_connections_takes_the_idlest_agent() {
+        let balancer = Balancer::new(Balance::LeastConnections);
+        let agents = agents(3);
+        let busy = [
+            agents[0].load.open_tunnel(),
+            agents[0].load.open_tunnel(),
+            agents[2].load.open_tunnel(),
+        ];
+        assert_eq!(port(balancer.pick(agents.clone())), 4001);
+        let _tunnel = agents[1].load.open_tunnel();
+        // Ties between agents 1 and 2 go to the one picked less recently.
+        assert_eq!(port(balancer.pick(agents.clone())), 4002);
+        drop(busy);
+        assert_eq!(port(balancer.pick(agents.clone())), 4000);
+    }
+
+    #[test]
+    fn latency_weighted_favours_quick_agents() {
+        let balancer = Balancer::new(Balance::LatencyWeighted);
+        let agents = agents(2);
+        agents[0].load.observe_rtt(Duration::from_millis(1));
+        agents[1].load.observe_rtt(Duration::from_millis(1000));
+        let quick = (0..1000)
+            .filter(|_| port(balancer.pick(agents.clone())) == 4000)
+            .count();
+        assert!(quick > 950, "{}", quick);
+    }
+
+    #[test]
+    fn unhealthy_agents_only_when_none_is_healthy() {
+        let balancer = Balancer::new(Balance::RoundRobin);
+        let agents = agents(2);
+        agents[0].load.set_missed(2);
+        for _ in 0..3 {
+            assert_eq!(port(balancer.pick(agents.clone())), 4001);
+        }
+        agents[1].load.set_missed(2);
+        assert!(balancer.pick(agents.clone()).is_some());
+    }
+}
@@ -1,33 +1,25 @@
+use std::collections::hash_map::RandomState;
 use std::collections::HashMap;
-use std::hash::Hash;
-use std::marker::PhantomData;
-use tokio::sync::mpsc::{self, UnboundedSender};
-use tokio::sync::oneshot;
+use std::hash::{BuildHasher, Hash};
+use std::sync::{Arc, RwLock};
 
-enum CacheSender<K, V> {
-    Get(K),
-    Insert((K, V)),
-    Remove(K),
-}
+type Shard<K, V> = RwLock<HashMap<K, V>>;
 
-enum CacheReceiver<V> {
-    Get(Option<V>),
-    Insert(Option<V>),
-    Remove(Option<V>),
+struct Inner<K, V> {
+    shards: Box<[Shard<K, V>]>,
+    hasher: RandomState,
 }
-type AsyncCacheSender<K, V> =
-    UnboundedSender<(CacheSender<K, V>, oneshot::Sender<CacheReceiver<V>>)>;
 
+/// A concurrent map split into shards, each behind its own lock, so lookups
+/// of different keys rarely wait on each other. Clones share the entries.
 #[derive(Clone)]
 pub struct AsyncCache<K, V> {
-    _k: PhantomData<K>,
-    _v: PhantomData<V>,
-    sender: AsyncCacheSender<K, V>,
+    inner: Arc<Inner<K, V>>,
 }
 
 impl<K, V> Default for AsyncCache<K, V>
 where
-    K: Hash + Eq + std::marker::Send + Sync + 'static,
+    K: Hash + Eq + std::marker::Send + Sync + 'static + Clone,
     V: std::marker::Send + Sync + 'static + Clone,
 {
     fn default() -> Self {
@@ -37,63 +29,95 @@ where
 
 impl<K, V> AsyncCache<K, V>
 where
-    K: Hash + Eq + std::marker::Send + Sync + 'static,
+    K: Hash + Eq + std::marker::Send + Sync + 'static + Clone,
     V: std::marker::Send + Sync + 'static + Clone,
 {
     pub fn new() -> Self {
-        let (sender, mut receiver) =
-            mpsc::unbounded_channel::<(CacheSender<K, V>, oneshot::Sender<CacheReceiver<V>>)>();
-        tokio::spawn(async move {
-            let mut map = HashMap::<K, V>::new();
-            while let Some(msg) = receiver.recv().await {
-                match msg.0 {
-                    CacheSender::Get(key) => {
-                        let _ = msg.1.send(CacheReceiver::Get(map.get(&key).cloned()));
-                    }
-                    CacheSender::Insert((key, value)) => {
-                        let value = map.insert(key, value);
-                        let _ = msg.1.send(CacheReceiver::Insert(value));
-                    }
-                    CacheSender::Remove(key) => {
-                        let value = map.remove(&key);
-                        let _ = msg.1.send(CacheReceiver::Remove(value));
-                    }
-                }
-            }
-        });
-        Self {
-            _k: PhantomData,
-            _v: PhantomData,
-            sender,
+        let shards = std::thread::available_parallelism()
+            .map_or(4, usize::from)
+            .saturating_mul(4)
+            .next_power_of_two();
+        AsyncCache {
+            inner: Arc::new(Inner {
+                shards: (0..shards).map(|_| Default::default()).collect(),
+                hasher: RandomState::new(),
+            }),
         }
     }
 
     pub async fn get(&self, key: K) -> Result<Option<V>, crate::Error> {
-        let oneshot = oneshot::channel();
-        let _ = self.sender.send((CacheSender::Get(key), oneshot.0));
-        match oneshot.1.await? {
-            CacheReceiver::Get(value) => Ok(value),
-            _ => Err("err receiver".into()),
-        }
+        Ok(self.shard(&key).read().unwrap().get(&key).cloned())
     }
 
     pub async fn insert(&self, key: K, value: V) -> Result<Option<V>, crate::Error> {
-        let oneshot = oneshot::channel();
-        let _ = self
-            .sender
-            .send((CacheSender::Insert((key, value)), oneshot.0));
-        match oneshot.1.await? {
-            CacheReceiver::Insert(value) => Ok(value),
-            _ => Err("err receiver".into()),
-        }
+        Ok(self.shard(&key).write().unwrap().insert(key, value))
     }
 
     pub async fn remove(&self, key: K) -> Result<Option<V>, crate::Error> {
-        let oneshot = oneshot::channel();
-        let _ = self.sender.send((CacheSender::Remove(key), oneshot.0));
-        match oneshot.1.await? {
-            CacheReceiver::Remove(value) => Ok(value),
-            _ => Err("err receiver".into()),
+        Ok(self.shard(&key).write().unwrap().remove(&key))
+    }
+
+    pub async fn entries(&self) -> Result<Vec<(K, V)>, crate::Error> {
+        Ok(self.iter().collect())
+    }
+
+    /// The entries, one shard at a time, so the result is not a single
+    /// snapshot when others write meanwhile.
+    pub fn iter(&self) -> impl Iterator<Item = (K, V)> + '_ {
+        self.inner.shards.iter().flat_map(|shard| {
+            let shard = shard.read().unwrap();
+            shard
+                .iter()
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect::<Vec<_>>()
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner
+            .shards
+            .iter()
+            .map(|shard| shard.read().unwrap().len())
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn shard(&self, key: &K) -> &Shard<K, V> {
+        let hash = self.inner.hasher.hash_one(key) as usize;
+        &self.inner.shards[hash & (self.inner.shards.len() - 1)]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn clones_share_the_entries() {
+        let cache = AsyncCache::new();
+        let clone = cache.clone();
+        let tasks: Vec<_> = (0..8)
+            .map(|task| {
+                let cache = cache.clone();
+                tokio::spawn(async move {
+                    for key in 0..100 {
+                        cache.insert(task * 100 + key, task).await.unwrap();
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
         }
+        assert_eq!(clone.len(), 800);
+        assert_eq!(clone.get(701).await.unwrap(), Some(7));
+        assert_eq!(clone.insert(701, 0).await.unwrap(), Some(7));
+        assert_eq!(cache.remove(701).await.unwrap(), Some(0));
+        assert_eq!(cache.get(701).await.unwrap(), None);
+        assert_eq!(cache.entries().await.unwrap().len(), 799);
+        assert!(!cache.is_empty());
     }
 }
@@ -1,20 +1,23 @@
-use super::cache::AsyncCache;
-use crate::buffer::QuicBuffer;
-use crate::connection::connect_quic_to_quic;
-use crate::frame::{ConnectionInfo, Frame};
+use super::cluster::{self, Node};
+use super::state::{State, Subscriber, Target, Tunnel};
+use crate::buffer::StreamBuffer;
+use crate::common::get_uuid;
+use crate::connection::{connect_stream_to_stream, Meter};
+use crate::frame::{ConnectionInfo, Frame, SubscribeInfo};
+use crate::metrics::metrics;
 use crate::shutdown::Shutdown;
 use crate::{frame, ChannelInfo};
-use quinn::Connection;
 use std::net::SocketAddr;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::broadcast;
 use tokio::sync::mpsc::{self, UnboundedReceiver};
 use tracing::info;
 
 pub struct Channel {
-    connection: Connection,
+    buffer: StreamBuffer,
     socket_addr: SocketAddr,
-    async_cache: AsyncCache<String, Arc<ChannelInfo>>,
+    state: Arc<State>,
     _shutdown_complete_tx: mpsc::Sender<()>,
     shutdown: Shutdown,
 }
@@ -26,17 +29,17 @@ pub enum FrameType {
 }
 
 impl Channel {
-    pub fn new(
-        connection: Connection,
+    pub(crate) fn new(
+        buffer: StreamBuffer,
         socket_addr: SocketAddr,
-        async_cache: AsyncCache<String, Arc<ChannelInfo>>,
+        state: Arc<State>,
         _shutdown_complete_tx: mpsc::Sender<()>,
         shutdown: Shutdown,
     ) -> Self {
         Self {
-            connection,
+            buffer,
             socket_addr,
-            async_cache,
+            state,
             _shutdown_complete_tx,
             shutdown,
         }
@@ -44,15 +47,16 @@ impl Channel {
 
     pub async fn run(self) -> Result<(), crate::Error> {
         let Channel {
-            connection,
+            mut buffer,
             socket_addr,
-            async_cache,
+            state,
             _shutdown_complete_tx,
             mut shutdown,
         } = self;
-        let (send_stream, recv_stream) = connection.accept_bi().await?;
-        let mut buffer = QuicBuffer::new(send_stream, recv_stream);
+        let pending = state.pending.clone();
         let (sender, mut receiver) = mpsc::unbounded_channel();
+        // The cluster peer on the other end, once it introduced itself.
+        let mut peer = None;
         loop {
             let frame = tokio::select! {
                 frame = buffer.read_frame() => FrameType::Socket(frame?),
@@ -62,63 +66,122 @@ impl Channel {
                 }
             };
             match frame {
+                // Agents still dial back for the tunnels asked for before the drain.
+                FrameType::Socket(frame)
+                    if state.is_draining()
+                        && !matches!(frame, Frame::TargetConnection(_) | Frame::Ping) =>
+                {
+                    return go_away(buffer).await;
+                }
                 FrameType::Socket(frame) => {
                     match frame {
                         frame::Frame::Register(register_info) => {
-                            let channel_info = Arc::new(ChannelInfo {
-                                net_addr: socket_addr,
+                            let channel_info = Arc::new(ChannelInfo::new(
+                                socket_addr,
                                 register_info,
-                                sender: sender.clone(),
-                            });
+                                sender.clone(),
+                            ));
+                            if let Err(error) = state.register(channel_info.clone()) {
+                                return reject(buffer, error).await;
+                            }
                             let _ = buffer.write_frame(&frame::Frame::Ack).await;
-                            let _ = async_cache
-                                .insert(
-                                    channel_info.register_info.get_tag().to_owned(),
-                                    channel_info.clone(),
-                                )
+                            return serve_register(buffer, receiver, channel_info, state, shutdown)
                                 .await;
-                            let async_cache_clone = async_cache.clone();
-                            //KeepAlive
-                            tokio::spawn(async move {
-                                loop {
-                                    tokio::time::sleep(Duration::from_secs(3)).await;
-                                    if channel_info.sender.send(Frame::Ping).is_err() {
-                                        break;
-                                    }
-                                }
-                                info!("register conn close : {:?}", channel_info);
-                                let _ = async_cache_clone
-                                    .remove(channel_info.register_info.get_tag().to_owned())
-                                    .await;
-                            });
                         }
                         frame::Frame::Connection(connection_info) => {
-                            let target_channel_info = async_cache
-                                .get(connection_info.get_target_tag().to_owned())
-                                .await?
-                                .ok_or(format!("not find connection : {:?}", connection_info))?;
-                            let channel_info = Arc::new(ChannelInfo {
-                                net_addr: socket_addr,
-                                register_info: Default::default(),
-                                sender: sender.clone(),
+                            let resolved = match peer {
+                                Some(_) => state.resolve_relayed(connection_info),
+                                None => state.check_rate(socket_addr.ip()).and_then(|_| {
+                                    state.resolve_connection(connection_info, socket_addr.ip())
+                                }),
+                            };
+                            let (connection_info, target_channel_info) = match resolved {
+                                Ok(resolved) => resolved,
+                                Err(error) => return reject(buffer, error).await,
+                            };
+                            let connection_info = connection_info.with_source_tag(get_uuid());
+                            let tunnel = Tunnel::new(
+                                &connection_info,
+                                socket_addr,
+                                state.bandwidth.tunnel_rate(),
+                            );
+                            let tunnel = Arc::new(match peer {
+                                Some(_) => tunnel.relayed(),
+                                None => tunnel,
                             });
-                            let _ = async_cache
+                            if let Err(error) = state.open_tunnel(tunnel.clone()) {
+                                return reject(buffer, error).await;
+                            }
+                            let channel_info = Arc::new(ChannelInfo::new(
+                                socket_addr,
+                                Default::default(),
+                                sender.clone(),
+                            ));
+                            let _ = pending
                                 .insert(
                                     connection_info.get_source_tag().to_owned(),
                                     channel_info.clone(),
                                 )
                                 .await;
+                            let start = Instant::now();
                             tokio::spawn(async move {
+                                // A draining server waits for the tunnel to end.
+                                let _shutdown_complete_tx = _shutdown_complete_tx;
+                                let _load = target_channel_info.load.open_tunnel();
                                 let tag = connection_info.get_source_tag().to_owned();
-                                let _ =
-                                    handler(connection_info, buffer, target_channel_info, receiver)
-                                        .await;
-                                let _ = async_cache.remove(tag).await;
+                                let (sent, received) =
+                                    metrics().relayed(connection_info.get_target_tag());
+                                let mut meter = Meter::default()
+                                    .with(sent, received)
+                                    .with(tunnel.sent.clone(), tunnel.received.clone())
+                                    .with_limit(tunnel.limit.clone());
+                                for limit in state.bandwidth.limits_for(&tunnel) {
+                                    meter = meter.with_limit(limit);
+                                }
+                                let timeout = state.heartbeat.timeout();
+                                let res = tokio::select! {
+                                    res = async {
+                                        match (target_channel_info.peer(), &state.node) {
+                                            (Some(server), Some(node)) => {
+                                                relay(
+                                                    node,
+                                                    server,
+                                                    connection_info,
+                                                    buffer,
+                                                    timeout,
+                                                    meter,
+                                                )
+                                                .await
+                                            }
+                                            _ => {
+                                                handler(
+                                                    connection_info,
+                                                    buffer,
+                                                    target_channel_info.clone(),
+                                                    receiver,
+                                                    timeout,
+                                                    start,
+                                                    meter,
+                                                )
+                                                .await
+                                            }
+                                        }
+                                    } => res,
+                                    _ = tunnel.close.notified() => Err("tunnel closed by admin".into()),
+                                    _ = shutdown.recv() => Err("server shut down".into()),
+                                };
+                                info!("tunnel end : {} , {:?}", tag, res);
+                                state.close_tunnel(&tag);
+                                state.bandwidth.release();
+                                if let Some(session_sink) = &state.session_sink {
+                                    session_sink.record(&tunnel.record(&res));
+                                }
+                                let _ = pending.remove(tag).await;
                             });
                             return Ok(());
                         }
                         frame::Frame::TargetConnection(connection_info) => {
-                            let source_channel_info = async_cache
+                            let source_channel_info = pending
                                 .get(connection_info.get_source_tag().to_owned())
                                 .await?
                                 .ok_or(format!(
@@ -127,30 +190,58 @@ impl Channel {
                                 ))?;
                             let _ = buffer.write_frame(&frame::Frame::Ack).await;
                             let _ = source_channel_info.sender.send(Frame::TargetBuffer(buffer));
-                            let _ = async_cache
+                            let _ = pending
                                 .remove(connection_info.get_source_tag().to_owned())
                                 .await;
                             return Ok(());
                         }
-                        frame::Frame::Subscribe(mut subscribe_info) => {
-                            let async_cache_clone = async_cache.clone();
-                            //KeepAlive
-                            tokio::spawn(async move {
-                                loop {
-                                    let addr = async_cache_clone
-                                        .get(subscribe_info.get_target_tag().to_owned())
-                                        .await
-                                        .unwrap();
-                                    let target_sockeraddr =
-                                        addr.map(|channel| channel.net_addr.to_string());
-                                    subscribe_info.set_target_sockeraddr(target_sockeraddr);
-                                    let _ = buffer
-                                        .write_frame(&Frame::Subscribe(subscribe_info.clone()))
-                                        .await;
-                                    tokio::time::sleep(Duration::from_secs(2)).await;
-                                }
-                            });
-                            return Ok(());
+                        frame::Frame::Subscribe(subscribe_info) => {
+                            let (agent_tag, target) =
+                                match state.resolve_subscribe(&subscribe_info, socket_addr.ip()) {
+                                    Ok(resolved) => resolved,
+                                    Err(error) => return reject(buffer, error).await,
+                                };
+                            let id = get_uuid();
+                            state.subscribers.lock().unwrap().insert(
+                                id.clone(),
+                                Subscriber {
+                                    net_addr: socket_addr,
+                                    target_tag: target.to_string(),
+                                    created_at: SystemTime::now(),
+                                },
+                            );
+                            let subscribe_info = subscribe_info.with_token(None);
+                            let res = serve_subscribe(
+                                buffer,
+                                subscribe_info,
+                                &target,
+                                agent_tag.as_deref(),
+                                &state,
+                                shutdown,
+                            )
+                            .await;
+                            state.subscribers.lock().unwrap().remove(&id);
+                            return res;
+                        }
+                        frame::Frame::Peer(peer_info) => {
+                            if let Err(error) = state.admit_peer(&peer_info) {
+                                return reject(buffer, error).await;
+                            }
+                            buffer.write_frame(&frame::Frame::Ack).await?;
+                            peer = Some(peer_info.get_server().to_owned());
+                        }
+                        frame::Frame::Gossip(gossip_info) => {
+                            let Some(server) = peer else {
+                                return Err("gossip from outside the cluster".into());
+                            };
+                            return cluster::serve_gossip(
+                                buffer,
+                                gossip_info,
+                                &server,
+                                &state,
+                                shutdown,
+                            )
+                            .await;
                         }
                         frame::Frame::Ping => {
                             buffer.write_frame(&frame::Frame::Ack).await?;
@@ -168,17 +259,235 @@ impl Channel {
 
 async fn handler(
     connection_info: ConnectionInfo,
-    mut buffer1: QuicBuffer,
+    mut buffer1: StreamBuffer,
     channel_info: Arc<ChannelInfo>,
     mut receiver: UnboundedReceiver<Frame>,
+    timeout: Duration,
+    start: Instant,
+    meter: Meter,
 ) -> Result<(), crate::Error> {
+    let mode = connection_info.get_agent_mode().as_str();
     channel_info
         .sender
         .send(Frame::Connection(connection_info))?;
-    let frame = receiver.recv().await.ok_or("receive error")?;
+    // A live agent dials back well within a heartbeat timeout.
+    let frame = tokio::time::timeout(timeout, receiver.recv())
+        .await
+        .map_err(|_| "target connection time out")?
+        .ok_or("receive error")?;
     let Frame::TargetBuffer(buffer2) = frame else {
         return Err("receive error frame".into());
     };
     let _ = buffer1.write_frame(&Frame::Ack).await;
-    connect_quic_to_quic(buffer1, buffer2).await
+    metrics().observe_setup(mode, start);
+    let _tunnel = metrics().open_tunnel(mode);
+    connect_stream_to_stream(buffer1, buffer2, meter).await
+}
+
+/// Relays a tunnel to the cluster peer at `server`, which its agent is
+/// registered with. The peer answers the initiator from then on.
+async fn relay(
+    node: &Node,
+    server: &str,
+    connection_info: ConnectionInfo,
+    buffer1: StreamBuffer,
+    timeout: Duration,
+    meter: Meter,
+) -> Result<(), crate::Error> {
+    let mode = connection_info.get_agent_mode().as_str();
+    let mut buffer2 = tokio::time::timeout(timeout, node.open(server))
+        .await
+        .map_err(|_| "peer connection time out")??;
+    buffer2
+        .write_frame(&Frame::Connection(connection_info))
+        .await?;
+    let _tunnel = metrics().open_tunnel(mode);
+    connect_stream_to_stream(buffer1, buffer2, meter).await
+}
+
+/// Pushes the addresses of `target` to a subscriber: once right away, then
+/// on every registry change that may concern it, with keepalives in between.
+/// Ends when the subscriber goes away, or sends it away once the server drains.
+async fn serve_subscribe(
+    mut buffer: StreamBuffer,
+    mut subscribe_info: SubscribeInfo,
+    target: &Target,
+    agent_tag: Option<&str>,
+    state: &State,
+    mut shutdown: Shutdown,
+) -> Result<(), crate::Error> {
+    let mut events = state.registry.watch();
+    let mut ticker = tokio::time::interval(state.heartbeat.interval);
+    ticker.tick().await;
+    let mut changed = true;
+    loop {
+        if changed {
+            let agent = state.pick_target(target, agent_tag);
+            subscribe_info
+                .set_target_sockeraddr(agent.as_ref().map(|agent| agent.net_addr().to_string()));
+            subscribe_info.set_target_addrs(
+                agent
+                    .map(|agent| agent.register_info().get_addrs().to_vec())
+                    .unwrap_or_default(),
+            );
+            buffer
+                .write_frame(&Frame::Subscribe(subscribe_info.clone()))
+                .await?;
+        }
+        changed = tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) => target.follows(event.tag()),
+                // Missed some events, so resend whatever the target has now.
+                Err(broadcast::error::RecvError::Lagged(_)) => true,
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            },
+            // The subscriber sends nothing, so this only ends when it leaves.
+            frame = buffer.read_frame() => {
+                frame?;
+                false
+            }
+            _ = ticker.tick() => {
+                buffer.write_frame(&Frame::KeepAlive).await?;
+                false
+            }
+            _ = state.drained() => {
+                buffer.write_frame(&Frame::GoAway).await?;
+                return Ok(());
+            }
+            // Draining comes first, so the subscriber still hears of it.
+            _ = shutdown.recv(), if !state.is_draining() => return Ok(()),
+        };
+    }
+}
+
+/// Sends a draining server's agent elsewhere.
+async fn go_away(mut buffer: StreamBuffer) -> Result<(), crate::Error> {
+    buffer.write_frame(&Frame::GoAway).await?;
+    Err("server draining".into())
+}
+
+/// Refuses a tunnel or subscription, telling the agent why.
+async fn reject(mut buffer: StreamBuffer, error: String) -> Result<(), crate::Error> {
+    info!("tunnel rejected : {}", error);
+    metrics().rejected_tunnels.inc();
+    buffer.write_frame(&Frame::Error(error.clone())).await?;
+    Err(error.into())
+}
+
+/// Serves an agent's register stream, pinging it every heartbeat interval and
+/// treating anything it sends as a sign of life. Once the agent goes away,
+/// misses too many pings or gets kicked its registration is evicted, so new
+/// tunnels to it fail right away instead of waiting on a dead agent. Once the
+/// server drains the agent is sent away to register elsewhere.
+async fn serve_register(
+    mut buffer: StreamBuffer,
+    mut receiver: UnboundedReceiver<Frame>,
+    channel_info: Arc<ChannelInfo>,
+    state: Arc<State>,
+    mut shutdown: Shutdown,
+) -> Result<(), crate::Error> {
+    let heartbeat = state.heartbeat;
+    metrics().agents.inc();
+    let mut ticker = tokio::time::interval(heartbeat.interval);
+    ticker.tick().await;
+    let mut missed = 0;
+    let mut ping_sent: Option<Instant> = None;
+    let res = async {
+        loop {
+            tokio::select! {
+                frame = buffer.read_frame() => {
+                    missed = 0;
+                    channel_info.load.set_missed(missed);
+                    match frame? {
+                        Frame::Ping => buffer.write_frame(&Frame::Ack).await?,
+                        Frame::Ack => {
+                            if let Some(sent) = ping_sent.take() {
+                                channel_info.load.observe_rtt(sent.elapsed());
+                            }
+                        }
+                        _ => (),
+                    }
+                }
+                frame = receiver.recv() => {
+                    let frame = frame.ok_or::<crate::Error>("receiver error".into())?;
+                    buffer.write_frame(&frame).await?;
+                }
+                _ = ticker.tick() => {
+                    missed += 1;
+                    channel_info.load.set_missed(missed);
+                    if missed >= heartbeat.max_missed {
+                        return Err::<(), crate::Error>("agent heartbeat time out".into());
+                    }
+                    // A late answer counts from the ping it answers.
+                    if ping_sent.is_none() {
+                        ping_sent = Some(Instant::now());
+                    }
+                    buffer.write_frame(&Frame::Ping).await?;
+                }
+                _ = channel_info.close.notified() => return Err("agent kicked by admin".into()),
+                _ = state.drained() => {
+                    buffer.write_frame(&Frame::GoAway).await?;
+                    return Ok(());
+                }
+                // Draining comes first, so the agent still hears of it.
+                _ = shutdown.recv(), if !state.is_draining() => return Ok(()),
+            }
+        }
+    }
+    .await;
+    metrics().agents.dec();
+    info!("register conn close : {:?} , {:?}", channel_info, res);
+    // The agent may have registered again on a new connection.
+    if let Err(error) = state.registry.remove(&channel_info) {
+        info!("registry remove err : {:?}", error);
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::RegisterInfo;
+    use crate::server::registry::MemoryRegistry;
+    use crate::server::Heartbeat;
+
+    #[tokio::test(start_paused = true)]
+    async fn silent_agent_is_evicted_after_max_missed_intervals() {
+        let heartbeat = Heartbeat {
+            interval: Duration::from_secs(1),
+            max_missed: 3,
+        };
+        let registry = Arc::new(MemoryRegistry::new());
+        let state = Arc::new(State::new(
+            registry,
+            heartbeat,
+            None,
+            Default::default(),
+            None,
+            None,
+        ));
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let register_info = RegisterInfo::new("server".to_owned(), "agent1".to_owned());
+        let channel_info = Arc::new(ChannelInfo::new(
+            ([10, 0, 0, 1], 4000).into(),
+            register_info,
+            sender,
+        ));
+        state.register(channel_info.clone()).unwrap();
+        // The agent end stays open but never answers.
+        let (stream, _agent) = tokio::io::duplex(1024);
+        let notify_shutdown = broadcast::channel(1).0;
+        let start = tokio::time::Instant::now();
+        let res = serve_register(
+            StreamBuffer::from_stream(stream),
+            receiver,
+            channel_info,
+            state.clone(),
+            Shutdown::new(notify_shutdown.subscribe()),
+        )
+        .await;
+        assert!(res.is_err());
+        assert_eq!(start.elapsed(), heartbeat.timeout());
+        assert!(state.registry.lookup("agent1").is_none());
+    }
 }
@@ -0,0 +1,374 @@
+//! Servers relaying for each other, so that an agent registered with any of
+//! them is reachable through all of them.
+
+use super::state::State;
+use crate::buffer::StreamBuffer;
+use crate::client::backoff::Backoff;
+use crate::frame::{AnnouncedAgent, Frame, GossipInfo, PeerInfo};
+use crate::shutdown::Shutdown;
+use crate::tls::{Identity, Verify};
+use crate::transport::Transport;
+use crate::ChannelInfo;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::{broadcast, mpsc};
+use tracing::info;
+
+/// Room for the agents of one gossip frame, well within the frame length.
+const GOSSIP_CHUNK: usize = 32 * 1024;
+
+/// The servers of a cluster. Every server dials every peer and announces the
+/// agents registered with it, tag by tag, as they come and go. A tunnel to
+/// an agent registered with a peer takes one more hop, from this server to
+/// the peer over QUIC.
+#[derive(Clone, Debug, Default)]
+pub struct Cluster {
+    advertise: String,
+    peers: Vec<String>,
+    token: String,
+    verify: Option<Verify>,
+}
+
+impl Cluster {
+    /// A cluster where the peers reach this server at `advertise`, all of
+    /// them sharing `token`, which must not be empty.
+    pub fn new(advertise: &str, token: &str) -> Self {
+        Cluster {
+            advertise: advertise.to_owned(),
+            token: token.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    /// Announces to and relays through `peers`, the other servers, and only
+    /// lets them in, each advertising the address it is listed under.
+    pub fn with_peers(mut self, peers: Vec<String>) -> Self {
+        self.peers = peers;
+        self
+    }
+
+    /// Verifies the certificates of the peers with `verify`, rather than
+    /// trusting those sharing the identity of this server.
+    pub fn with_verify(mut self, verify: Verify) -> Self {
+        self.verify = Some(verify);
+        self
+    }
+
+    /// How the peers are verified, given the identity of this server.
+    pub(crate) fn verify(&self, identity: Option<&Identity>) -> Result<Verify, crate::Error> {
+        match (&self.verify, identity) {
+            (Some(verify), _) => Ok(verify.clone()),
+            (None, Some(identity)) => Ok(Verify::identity(identity)),
+            (None, None) => Err("cluster needs a tls identity or a way to verify peers".into()),
+        }
+    }
+
+    pub(crate) fn check(&self) -> Result<(), crate::Error> {
+        if self.token.is_empty() {
+            return Err("cluster token must not be empty".into());
+        }
+        Ok(())
+    }
+}
+
+/// This server as a member of its cluster.
+pub(crate) struct Node {
+    cluster: Cluster,
+    transport: Arc<dyn Transport>,
+}
+
+impl Node {
+    pub(crate) fn new(cluster: Cluster, transport: Arc<dyn Transport>) -> Self {
+        Node { cluster, transport }
+    }
+
+    pub(crate) fn peers(&self) -> &[String] {
+        &self.cluster.peers
+    }
+
+    /// Checks that `peer_info` comes from a server of the cluster.
+    pub(crate) fn admit(&self, peer_info: &PeerInfo) -> Result<(), String> {
+        let server = peer_info.get_server();
+        if !self.cluster.peers.iter().any(|peer| peer == server) {
+            return Err(format!("{} is not a peer", server));
+        }
+        if peer_info.get_token() != Some(self.cluster.token.as_str()) {
+            return Err(format!("invalid cluster token from {}", server));
+        }
+        Ok(())
+    }
+
+    /// Opens a stream to the peer at `server`, introduced as a peer.
+    pub(crate) async fn open(&self, server: &str) -> Result<StreamBuffer, crate::Error> {
+        let connection = self.transport.dial(server).await?;
+        let mut buffer = connection.open_stream().await?;
+        let peer_info = PeerInfo::new(
+            self.cluster.advertise.clone(),
+            Some(self.cluster.token.clone()),
+        );
+        buffer.write_frame(&Frame::Peer(peer_info)).await?;
+        match buffer.read_frame().await? {
+            Frame::Error(error) => Err(format!("peer {} refused : {}", server, error).into()),
+            Frame::GoAway => Err(format!("peer {} going away", server).into()),
+            _ => Ok(buffer),
+        }
+    }
+}
+
+/// Keeps announcing the agents registered here to the peer at `server`,
+/// announcing everything again with backoff whenever the stream breaks.
+/// Stops once the server drains, so that the peer forgets its agents.
+pub(crate) async fn announce(state: Arc<State>, server: String, mut shutdown: Shutdown) {
+    let Some(node) = &state.node else {
+        return;
+    };
+    let mut backoff = Backoff::new();
+    loop {
+        let res = tokio::select! {
+            res = announce_session(node, &state, &server, &mut backoff) => res,
+            _ = state.drained() => return,
+            _ = shutdown.recv() => return,
+        };
+        let delay = backoff.next_delay();
+        info!(
+            "announce to {} end : {:?} , try again in {:?}",
+            server, res, delay
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+async fn announce_session(
+    node: &Node,
+    state: &State,
+    server: &str,
+    backoff: &mut Backoff,
+) -> Result<(), crate::Error> {
+    let mut events = state.registry.watch();
+    let mut buffer = node.open(server).await?;
+    backoff.reset();
+    info!("announcing to {}", server);
+    for tag in local_tags(state) {
+        announce_tag(&mut buffer, state, &tag).await?;
+    }
+    let mut ticker = tokio::time::interval(state.heartbeat.interval);
+    ticker.tick().await;
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) => announce_tag(&mut buffer, state, event.tag()).await?,
+                // Missed some changes, so announce everything again.
+                Err(broadcast::error::RecvError::Lagged(_)) => {
+                    for tag in local_tags(state) {
+                        announce_tag(&mut buffer, state, &tag).await?;
+                    }
+                }
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            },
+            // The peer sends nothing, so this only ends when it goes away.
+            frame = buffer.read_frame() => {
+                frame?;
+            }
+            _ = ticker.tick() => buffer.write_frame(&Frame::KeepAlive).await?,
+        }
+    }
+}
+
+/// The tags with agents registered here rather than with a peer.
+fn local_tags(state: &State) -> Vec<String> {
+    state
+        .registry
+        .list()
+        .into_iter()
+        .map(|record| record.tag)
+        .filter(|tag| {
+            state
+                .registry
+                .members(tag)
+                .iter()
+                .any(|agent| agent.peer().is_none())
+        })
+        .collect()
+}
+
+/// Announces the agents registered here under `tag` to a peer.
+async fn announce_tag(
+    buffer: &mut StreamBuffer,
+    state: &State,
+    tag: &str,
+) -> Result<(), crate::Error> {
+    let agents = state
+        .registry
+        .members(tag)
+        .iter()
+        .filter(|agent| agent.peer().is_none())
+        .map(|agent| AnnouncedAgent::new(agent.net_addr(), agent.register_info().clone()))
+        .collect();
+    for gossip_info in gossip(tag, agents) {
+        buffer.write_frame(&Frame::Gossip(gossip_info)).await?;
+    }
+    Ok(())
+}
+
+/// `agents` split over as many announcements as it takes for each to fit in
+/// a frame.
+pub(crate) fn gossip(tag: &str, agents: Vec<AnnouncedAgent>) -> Vec<GossipInfo> {
+    let mut chunks: Vec<Vec<AnnouncedAgent>> = vec![vec![]];
+    let mut size = 0;
+    for agent in agents {
+        let len = serde_json::to_vec(&agent).map_or(0, |json| json.len() + 1);
+        if size + len > GOSSIP_CHUNK && chunks.last().is_some_and(|chunk| !chunk.is_empty()) {
+            chunks.push(vec![]);
+            size = 0;
+        }
+        size += len;
+        chunks.last_mut().unwrap().push(agent);
+    }
+    let last = chunks.len() - 1;
+    chunks
+        .into_iter()
+        .enumerate()
+        .map(|(index, agents)| GossipInfo::new(tag.to_owned(), agents).with_more(index < last))
+        .collect()
+}
+
+/// Puts together the announcements of a tag made in several frames, giving
+/// the whole once the last one is in.
+fn gather(
+    partial: &mut HashMap<String, Vec<AnnouncedAgent>>,
+    gossip_info: GossipInfo,
+) -> Option<GossipInfo> {
+    let tag = gossip_info.get_tag().to_owned();
+    let more = gossip_info.has_more();
+    let mut agents = partial.remove(&tag).unwrap_or_default();
+    agents.extend(gossip_info.into_agents());
+    if more {
+        partial.insert(tag, agents);
+        return None;
+    }
+    Some(GossipInfo::new(tag, agents))
+}
+
+/// Registers the agents the peer at `server` announces, starting with
+/// `gossip_info`, until the stream ends or this server drains. Its agents are
+/// forgotten then, as the peer announces them all again once it is back.
+pub(crate) async fn serve_gossip(
+    mut buffer: StreamBuffer,
+    gossip_info: GossipInfo,
+    server: &str,
+    state: &State,
+    mut shutdown: Shutdown,
+) -> Result<(), crate::Error> {
+    let mut announced = HashMap::new();
+    let mut partial = HashMap::new();
+    let mut next = Some(gossip_info);
+    let res = async {
+        loop {
+            if let Some(gossip_info) = next.take().and_then(|next| gather(&mut partial, next)) {
+                apply(state, server, &mut announced, gossip_info);
+            }
+            tokio::select! {
+                frame = buffer.read_frame() => {
+                    if let Frame::Gossip(gossip_info) = frame? {
+                        next = Some(gossip_info);
+                    }
+                }
+                _ = state.drained() => return Ok::<(), crate::Error>(()),
+                _ = shutdown.recv() => return Ok(()),
+            }
+        }
+    }
+    .await;
+    for agent in announced.into_values().flatten() {
+        let _ = state.registry.remove(&agent);
+    }
+    res
+}
+
+/// Brings the agents of a tag announced by `server` in line with
+/// `gossip_info`, keeping the ones still there as they are.
+fn apply(
+    state: &State,
+    server: &str,
+    announced: &mut HashMap<String, Vec<Arc<ChannelInfo>>>,
+    gossip_info: GossipInfo,
+) {
+    let previous = announced.remove(gossip_info.get_tag()).unwrap_or_default();
+    let (kept, gone): (Vec<_>, Vec<_>) = previous.into_iter().partition(|agent| {
+        gossip_info
+            .get_agents()
+            .iter()
+            .any(|announced| announced.get_net_addr() == agent.net_addr())
+    });
+    for agent in gone {
+        let _ = state.registry.remove(&agent);
+    }
+    let mut agents = kept;
+    for announced in gossip_info.get_agents() {
+        if agents
+            .iter()
+            .any(|agent| agent.net_addr() == announced.get_net_addr())
+        {
+            continue;
+        }
+        let agent = Arc::new(
+            ChannelInfo::new(
+                announced.get_net_addr(),
+                announced.get_register_info().clone(),
+                // Tunnels to the agent go through the peer, never this channel.
+                mpsc::unbounded_channel().0,
+            )
+            .with_peer(server),
+        );
+        if let Err(error) = state.registry.register(agent.clone()) {
+            info!("register {} from {} err : {:?}", agent.tag(), server, error);
+            continue;
+        }
+        agents.push(agent);
+    }
+    if !agents.is_empty() {
+        announced.insert(gossip_info.get_tag().to_owned(), agents);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::RegisterInfo;
+    use crate::MetaData;
+
+    fn agents(count: u16) -> Vec<AnnouncedAgent> {
+        (0..count)
+            .map(|index| {
+                let labels = HashMap::from([("note".to_owned(), "x".repeat(200))]);
+                let register_info = RegisterInfo::new("server".to_owned(), "tag".to_owned())
+                    .with_mate_data(MetaData::from(labels));
+                AnnouncedAgent::new(([10, 0, 0, 1], index).into(), register_info)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn gossip_fits_in_frames_and_gathers_back() {
+        let chunks = gossip("tag", agents(1000));
+        assert!(chunks.len() > 1);
+        let mut partial = HashMap::new();
+        let mut gathered = None;
+        for (index, gossip_info) in chunks.iter().enumerate() {
+            assert!(Frame::Gossip(gossip_info.clone()).serialization().is_ok());
+            assert_eq!(gossip_info.has_more(), index + 1 < chunks.len());
+            gathered = gather(&mut partial, gossip_info.clone());
+            assert_eq!(gathered.is_some(), index + 1 == chunks.len());
+        }
+        assert_eq!(gathered.unwrap().get_agents().len(), 1000);
+        assert!(partial.is_empty());
+    }
+
+    #[test]
+    fn gossip_without_agents_still_announces_the_tag() {
+        let chunks = gossip("tag", vec![]);
+        assert_eq!(chunks.len(), 1);
+        assert!(!chunks[0].has_more());
+        assert!(chunks[0].get_agents().is_empty());
+    }
+}
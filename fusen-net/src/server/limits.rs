@@ -0,0 +1,338 @@
+use super::state::Tunnel;
+use crate::limit::RateLimit;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Bandwidth limits of relayed tunnels in bytes per second, 0 meaning
+/// unlimited. A tunnel is held to the global limit, the limits of its source
+/// and target tags, the limit of its service (target tag and host) and its own
+/// per-tunnel limit all at once. Changes apply to open tunnels too.
+#[derive(Default)]
+pub struct BandwidthLimits {
+    global: Arc<RateLimit>,
+    tunnel: AtomicU64,
+    tags: Mutex<HashMap<String, Arc<RateLimit>>>,
+    services: Mutex<HashMap<(String, String), Arc<RateLimit>>>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct LimitsView {
+    global: u64,
+    tunnel: u64,
+    tags: HashMap<String, u64>,
+    services: HashMap<String, u64>,
+}
+
+impl BandwidthLimits {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_global(self, rate: u64) -> Self {
+        self.set_global(rate);
+        self
+    }
+
+    pub fn with_tunnel(self, rate: u64) -> Self {
+        self.set_tunnel(rate);
+        self
+    }
+
+    pub fn with_tag(self, tag: &str, rate: u64) -> Self {
+        self.set_tag(tag, rate);
+        self
+    }
+
+    pub fn with_service(self, tag: &str, target_host: &str, rate: u64) -> Self {
+        self.set_service(tag, target_host, rate);
+        self
+    }
+
+    pub fn set_global(&self, rate: u64) {
+        self.global.set_rate(rate);
+    }
+
+    /// Sets the limit of each tunnel opened from now on, open tunnels are
+    /// updated by the caller.
+    pub fn set_tunnel(&self, rate: u64) {
+        self.tunnel.store(rate, Ordering::Relaxed);
+    }
+
+    pub fn set_tag(&self, tag: &str, rate: u64) {
+        bucket(&mut self.tags.lock().unwrap(), tag.to_owned()).set_rate(rate);
+    }
+
+    pub fn set_service(&self, tag: &str, target_host: &str, rate: u64) {
+        let key = (tag.to_owned(), target_host.to_owned());
+        bucket(&mut self.services.lock().unwrap(), key).set_rate(rate);
+    }
+
+    pub(crate) fn tunnel_rate(&self) -> u64 {
+        self.tunnel.load(Ordering::Relaxed)
+    }
+
+    /// The buckets `tunnel` has to take its bytes from, besides its own.
+    pub(crate) fn limits_for(&self, tunnel: &Tunnel) -> Vec<Arc<RateLimit>> {
+        let mut limits = vec![self.global.clone()];
+        let mut tags = self.tags.lock().unwrap();
+        limits.push(bucket(&mut tags, tunnel.target_tag.clone()));
+        if let Some(agent_tag) = &tunnel.agent_tag {
+            if agent_tag != &tunnel.target_tag {
+                limits.push(bucket(&mut tags, agent_tag.clone()));
+            }
+        }
+        let key = (tunnel.target_tag.clone(), tunnel.target_host.clone());
+        limits.push(bucket(&mut self.services.lock().unwrap(), key));
+        limits
+    }
+
+    /// Takes over the rates of `other`, removing the tag and service limits it
+    /// doesn't have. Open tunnels keep their buckets and see the new rates.
+    pub(crate) fn apply(&self, other: &BandwidthLimits) {
+        self.set_global(other.global.rate());
+        self.set_tunnel(other.tunnel_rate());
+        replace(&self.tags, &other.tags);
+        replace(&self.services, &other.services);
+        self.release();
+    }
+
+    /// Drops the unlimited buckets no tunnel uses anymore.
+    pub(crate) fn release(&self) {
+        let keep = |limit: &Arc<RateLimit>| !limit.is_unlimited() || Arc::strong_count(limit) > 1;
+        self.tags.lock().unwrap().retain(|_, limit| keep(limit));
+        self.services.lock().unwrap().retain(|_, limit| keep(limit));
+    }
+
+    pub(crate) fn view(&self) -> LimitsView {
+        LimitsView {
+            global: self.global.rate(),
+            tunnel: self.tunnel_rate(),
+            tags: rates(&self.tags.lock().unwrap()).into_iter().collect(),
+            services: rates(&self.services.lock().unwrap())
+                .into_iter()
+                .map(|((tag, target_host), rate)| (format!("{}/{}", tag, target_host), rate))
+                .collect(),
+        }
+    }
+}
+
+/// The bucket at `key`, created unlimited. Buckets are shared with the tunnels
+/// using them so that rate changes reach open tunnels.
+fn replace<K: Clone + Eq + std::hash::Hash>(
+    limits: &Mutex<HashMap<K, Arc<RateLimit>>>,
+    other: &Mutex<HashMap<K, Arc<RateLimit>>>,
+) {
+    let other = other.lock().unwrap();
+    let mut limits = limits.lock().unwrap();
+    for (key, limit) in limits.iter() {
+        if !other.contains_key(key) {
+            limit.set_rate(0);
+        }
+    }
+    for (key, limit) in other.iter() {
+        bucket(&mut limits, key.clone()).set_rate(limit.rate());
+    }
+}
+
+fn rates<K: Clone>(limits: &HashMap<K, Arc<RateLimit>>) -> Vec<(K, u64)> {
+    limits
+        .iter()
+        .filter(|(_, limit)| !limit.is_unlimited())
+        .map(|(key, limit)| (key.clone(), limit.rate()))
+        .collect()
+}
+
+fn bucket<K: Eq + std::hash::Hash>(
+    limits: &mut HashMap<K, Arc<RateLimit>>,
+    key: K,
+) -> Arc<RateLimit> {
+    limits.entry(key).or_default().clone()
+}
+
+/// Caps on the tunnels relayed by the server, `None` meaning no cap. Tunnels
+/// past a cap are refused with [`crate::frame::Frame::Error`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ConnectionLimits {
+    /// Open tunnels in total.
+    pub max_tunnels: Option<usize>,
+    /// Open tunnels asked for by one agent, by its tag within its namespace,
+    /// or by its ip when it names no tag. Tunnels relayed by cluster peers
+    /// don't count, the peers limit them.
+    pub max_tunnels_per_source: Option<usize>,
+    /// Open tunnels to one target tag.
+    pub max_tunnels_per_target: Option<usize>,
+    /// Tunnels one remote ip may ask for each second.
+    pub max_connections_per_ip: Option<u32>,
+}
+
+impl ConnectionLimits {
+    /// Checks whether `tunnel` may open next to the `open` ones.
+    pub(crate) fn admit<'a>(
+        &self,
+        tunnel: &Tunnel,
+        open: impl Iterator<Item = &'a Arc<Tunnel>> + Clone,
+    ) -> Result<(), String> {
+        if let Some(max) = self.max_tunnels {
+            if open.clone().count() >= max {
+                return Err(format!("too many tunnels, max {}", max));
+            }
+        }
+        if let (Some(max), Some(source)) = (self.max_tunnels_per_source, tunnel.source()) {
+            let count = open
+                .clone()
+                .filter(|open| open.source().as_ref() == Some(&source))
+                .count();
+            if count >= max {
+                return Err(format!("too many tunnels from {}, max {}", source, max));
+            }
+        }
+        if let Some(max) = self.max_tunnels_per_target {
+            let count = open
+                .filter(|open| open.target_tag == tunnel.target_tag)
+                .count();
+            if count >= max {
+                return Err(format!(
+                    "too many tunnels to {}, max {}",
+                    tunnel.target_tag, max
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Counts the tunnels each remote ip asks for within one-second windows.
+#[derive(Default)]
+pub(crate) struct ConnectionRate {
+    windows: Mutex<HashMap<IpAddr, (Instant, u32)>>,
+}
+
+impl ConnectionRate {
+    const WINDOW: Duration = Duration::from_secs(1);
+
+    /// Counts a new connection from `ip`, failing when it is over `max` this second.
+    pub(crate) fn check(&self, ip: IpAddr, max: u32) -> Result<(), String> {
+        let now = Instant::now();
+        let mut windows = self.windows.lock().unwrap();
+        if windows.len() > 1024 {
+            windows.retain(|_, (start, _)| now.duration_since(*start) < Self::WINDOW);
+        }
+        let (start, count) = windows.entry(ip).or_insert((now, 0));
+        if now.duration_since(*start) >= Self::WINDOW {
+            *start = now;
+            *count = 0;
+        }
+        *count += 1;
+        if *count > max {
+            return Err(format!("too many connections from {}, max {}/s", ip, max));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::AgentMode;
+    use crate::frame::ConnectionInfo;
+
+    fn connection(agent_tag: Option<&str>, target_tag: &str) -> ConnectionInfo {
+        ConnectionInfo::new(
+            AgentMode::RM,
+            "id".to_owned(),
+            target_tag.to_owned(),
+            "127.0.0.1:8081".to_owned(),
+        )
+        .with_agent_tag(agent_tag.map(str::to_owned))
+    }
+
+    fn tunnel(agent_tag: &str, ip: [u8; 4], target_tag: &str) -> Tunnel {
+        Tunnel::new(
+            &connection(Some(agent_tag), target_tag),
+            (ip, 4000).into(),
+            0,
+        )
+    }
+
+    #[test]
+    fn tunnels_per_source_count_by_tag_whatever_the_ip() {
+        let limits = ConnectionLimits {
+            max_tunnels_per_source: Some(2),
+            ..Default::default()
+        };
+        let open = [
+            Arc::new(tunnel("team-a/agent1", [10, 0, 0, 1], "target")),
+            Arc::new(tunnel("team-a/agent1", [10, 0, 0, 2], "target")),
+        ];
+        let error = limits
+            .admit(
+                &tunnel("team-a/agent1", [10, 0, 0, 3], "target"),
+                open.iter(),
+            )
+            .unwrap_err();
+        assert_eq!(error, "too many tunnels from team-a/agent1, max 2");
+        // Agents behind the same address have budgets of their own, and so
+        // do the same tags in other namespaces.
+        for tag in ["team-a/agent2", "team-b/agent1", "agent1"] {
+            assert!(limits
+                .admit(&tunnel(tag, [10, 0, 0, 1], "target"), open.iter())
+                .is_ok());
+        }
+        // A peer limited the tunnels it relays already.
+        let relayed = tunnel("team-a/agent1", [10, 0, 0, 1], "target").relayed();
+        assert!(limits.admit(&relayed, open.iter()).is_ok());
+    }
+
+    #[test]
+    fn untagged_tunnels_per_source_count_by_ip() {
+        let limits = ConnectionLimits {
+            max_tunnels_per_source: Some(1),
+            ..Default::default()
+        };
+        let untagged = |ip| Tunnel::new(&connection(None, "target"), (ip, 4000).into(), 0);
+        let open = [Arc::new(untagged([10, 0, 0, 1]))];
+        let error = limits
+            .admit(&untagged([10, 0, 0, 1]), open.iter())
+            .unwrap_err();
+        assert_eq!(error, "too many tunnels from 10.0.0.1, max 1");
+        assert!(limits.admit(&untagged([10, 0, 0, 2]), open.iter()).is_ok());
+    }
+
+    #[test]
+    fn tunnels_in_total_and_per_target() {
+        let limits = ConnectionLimits {
+            max_tunnels: Some(3),
+            max_tunnels_per_target: Some(2),
+            ..Default::default()
+        };
+        let mut open = vec![
+            Arc::new(tunnel("agent1", [10, 0, 0, 1], "target1")),
+            Arc::new(tunnel("agent1", [10, 0, 0, 2], "target1")),
+        ];
+        let error = limits
+            .admit(&tunnel("agent1", [10, 0, 0, 3], "target1"), open.iter())
+            .unwrap_err();
+        assert_eq!(error, "too many tunnels to target1, max 2");
+        let next = tunnel("agent1", [10, 0, 0, 3], "target2");
+        assert!(limits.admit(&next, open.iter()).is_ok());
+        open.push(Arc::new(next));
+        let error = limits
+            .admit(&tunnel("agent1", [10, 0, 0, 4], "target3"), open.iter())
+            .unwrap_err();
+        assert_eq!(error, "too many tunnels, max 3");
+    }
+
+    #[test]
+    fn connection_rate_is_per_ip_and_second() {
+        let rate = ConnectionRate::default();
+        let ip = [10, 0, 0, 1].into();
+        assert!(rate.check(ip, 2).is_ok());
+        assert!(rate.check(ip, 2).is_ok());
+        assert!(rate.check(ip, 2).is_err());
+        assert!(rate.check([10, 0, 0, 2].into(), 2).is_ok());
+    }
+}
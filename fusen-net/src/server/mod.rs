@@ -1,69 +1,494 @@
-use crate::quic::support::make_server_endpoint;
+use crate::http;
+use crate::metrics::{self, metrics};
+use crate::quic::QuicTransport;
 use crate::shutdown::Shutdown;
-use crate::ChannelInfo;
-use cache::AsyncCache;
+use crate::socket::host_port;
+use crate::tcp::TcpTransport;
+use crate::tls::{Identity, Verify};
+use crate::transport::{Listener, Transport};
+use crate::ws::WsTransport;
+pub use acl::Acl;
+pub use balance::Balance;
 use channel::Channel;
+pub use cluster::Cluster;
+use cluster::Node;
+pub use limits::{BandwidthLimits, ConnectionLimits};
+pub use namespace::Namespaces;
+use registry::{MemoryRegistry, Registry};
+use session::{JsonLinesSink, SessionSink};
+use state::State;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
 use tokio::signal;
 use tokio::sync::broadcast::Sender;
 use tokio::sync::{broadcast, mpsc};
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
+mod acl;
+mod admin;
+pub(crate) mod balance;
 pub mod cache;
 mod channel;
+pub mod cluster;
+mod limits;
+pub mod namespace;
+pub mod registry;
+pub mod session;
+mod state;
+
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(5);
+
+/// How the server checks that registered agents are still alive.
+#[derive(Clone, Copy, Debug)]
+pub struct Heartbeat {
+    /// Time between pings sent to each agent.
+    pub interval: Duration,
+    /// Intervals an agent may stay silent before it is evicted.
+    pub max_missed: u32,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Heartbeat {
+            interval: Duration::from_secs(3),
+            max_missed: 3,
+        }
+    }
+}
+
+impl Heartbeat {
+    /// How long a silent agent stays registered.
+    pub fn timeout(&self) -> Duration {
+        self.interval * self.max_missed
+    }
+}
+
+/// The parts of the server configuration that can change while it runs.
+#[derive(Default)]
+pub struct Settings {
+    pub bandwidth: BandwidthLimits,
+    pub connection_limits: ConnectionLimits,
+    pub acl: Acl,
+    /// How tunnels spread over the agents sharing a tag.
+    pub balance: Balance,
+    pub namespaces: Namespaces,
+    /// Token of the admin API, which only serves when started `with_admin`.
+    pub admin_token: Option<String>,
+}
+
+/// Produces fresh [`Settings`] when the server is asked to reload.
+pub type Reloader = Arc<dyn Fn() -> Result<Settings, crate::Error> + Send + Sync>;
 
 pub struct Server {
     port: String,
+    bind: String,
+    transport: Option<Arc<dyn Transport>>,
+    tcp: bool,
+    ws_port: Option<String>,
+    identity: Option<Identity>,
+    listeners: Vec<(Arc<dyn Transport>, String)>,
+    heartbeat: Heartbeat,
+    admin_addr: Option<String>,
+    metrics_addr: Option<String>,
+    session_sink: Option<Arc<dyn SessionSink>>,
+    settings: Settings,
+    reloader: Option<Reloader>,
+    registry: Option<Arc<dyn Registry>>,
+    cluster: Option<Cluster>,
+    drain_timeout: Duration,
 }
 
 impl Server {
+    /// A server on `port`. Unless given an identity it serves a self-signed
+    /// certificate, new on every start, whose fingerprint it logs for clients
+    /// to pin, see [`Verify::with_pin`].
     pub fn new(port: &str) -> Self {
-        Self { port: port.into() }
+        Self {
+            port: port.into(),
+            bind: "::".into(),
+            transport: None,
+            tcp: false,
+            ws_port: None,
+            identity: None,
+            listeners: vec![],
+            heartbeat: Default::default(),
+            admin_addr: None,
+            metrics_addr: None,
+            session_sink: None,
+            settings: Default::default(),
+            reloader: None,
+            registry: None,
+            cluster: None,
+            drain_timeout: Duration::from_secs(30),
+        }
+    }
+
+    pub fn with_heartbeat(mut self, heartbeat: Heartbeat) -> Self {
+        self.heartbeat = heartbeat;
+        self
+    }
+
+    /// Serves the admin HTTP API on `addr`, accepting requests that carry `token`
+    /// as a bearer token.
+    pub fn with_admin(mut self, addr: &str, token: &str) -> Self {
+        self.admin_addr = Some(addr.into());
+        self.settings.admin_token = Some(token.into());
+        self
     }
-    pub async fn start(self) -> Result<(), crate::Error> {
-        let bind_addr = format!("0.0.0.0:{}", self.port).parse()?;
-        let endpoint = make_server_endpoint(bind_addr)?.0;
-        let async_cache = AsyncCache::<String, Arc<ChannelInfo>>::new();
+
+    /// Serves Prometheus metrics on `http://{addr}/metrics`.
+    pub fn with_metrics(mut self, addr: &str) -> Self {
+        self.metrics_addr = Some(addr.into());
+        self
+    }
+
+    /// Sends a record of every relayed tunnel to `session_sink` once it closes.
+    pub fn with_session_sink<S: SessionSink + 'static>(mut self, session_sink: S) -> Self {
+        self.session_sink = Some(Arc::new(session_sink));
+        self
+    }
+
+    /// Keeps track of agents in `registry` instead of a [`MemoryRegistry`].
+    pub fn with_registry<R: Registry + 'static>(mut self, registry: R) -> Self {
+        self.registry = Some(Arc::new(registry));
+        self
+    }
+
+    /// Appends a record of every relayed tunnel to the JSON-lines file at `path`.
+    pub fn with_session_log(self, path: &str) -> Result<Self, crate::Error> {
+        Ok(self.with_session_sink(JsonLinesSink::open(path)?))
+    }
+
+    /// Limits the bandwidth of relayed tunnels, adjustable later through the
+    /// admin API.
+    pub fn with_bandwidth(mut self, bandwidth: BandwidthLimits) -> Self {
+        self.settings.bandwidth = bandwidth;
+        self
+    }
+
+    /// Caps the tunnels relayed by the server.
+    pub fn with_connection_limits(mut self, connection_limits: ConnectionLimits) -> Self {
+        self.settings.connection_limits = connection_limits;
+        self
+    }
+
+    /// Lets only the agents `acl` allows reach the tags it has rules for.
+    pub fn with_acl(mut self, acl: Acl) -> Self {
+        self.settings.acl = acl;
+        self
+    }
+
+    /// Keeps the tags of tenants apart in `namespaces`.
+    pub fn with_namespaces(mut self, namespaces: Namespaces) -> Self {
+        self.settings.namespaces = namespaces;
+        self
+    }
+
+    /// Spreads tunnels over the agents sharing a tag with `balance` rather
+    /// than round-robin.
+    pub fn with_balance(mut self, balance: Balance) -> Self {
+        self.settings.balance = balance;
+        self
+    }
+
+    /// Joins `cluster`, relaying tunnels to the agents registered with its
+    /// other servers over the QUIC transport.
+    pub fn with_cluster(mut self, cluster: Cluster) -> Self {
+        self.cluster = Some(cluster);
+        self
+    }
+
+    /// On ctrl-c, waits up to `drain_timeout` rather than 30 seconds for the
+    /// open tunnels to end before closing them.
+    pub fn with_drain_timeout(mut self, drain_timeout: Duration) -> Self {
+        self.drain_timeout = drain_timeout;
+        self
+    }
+
+    /// Reloads the [`Settings`] from `reloader` on SIGHUP and on `POST /reload`
+    /// to the admin API, without touching open tunnels.
+    pub fn with_reloader<F>(mut self, reloader: F) -> Self
+    where
+        F: Fn() -> Result<Settings, crate::Error> + Send + Sync + 'static,
+    {
+        self.reloader = Some(Arc::new(reloader));
+        self
+    }
+
+    /// Binds the server port and the WebSocket port on `bind` instead of `::`,
+    /// which takes both IPv6 and IPv4.
+    pub fn with_bind(mut self, bind: &str) -> Self {
+        self.bind = bind.into();
+        self
+    }
+
+    /// Presents `identity` on the QUIC, TCP and WebSocket listeners instead of a
+    /// self-signed certificate.
+    pub fn with_identity(mut self, identity: Identity) -> Self {
+        self.identity = Some(identity);
+        self
+    }
+
+    /// Serves clients over `transport` on the server port instead of QUIC.
+    pub fn with_transport<T: Transport + 'static>(mut self, transport: T) -> Self {
+        self.transport = Some(Arc::new(transport));
+        self
+    }
+
+    /// Also serves clients over `transport` on `addr`.
+    pub fn listen<T: Transport + 'static>(mut self, transport: T, addr: &str) -> Self {
+        self.listeners.push((Arc::new(transport), addr.into()));
+        self
+    }
+
+    /// Also serves clients over TLS on the same TCP port, for networks that drop UDP.
+    pub fn with_tcp(mut self) -> Self {
+        self.tcp = true;
+        self
+    }
+
+    /// Also serves clients over WebSocket (wss) on `port`, for agents that can
+    /// only get out through HTTP proxies.
+    pub fn with_ws(mut self, port: &str) -> Self {
+        self.ws_port = Some(port.into());
+        self
+    }
+
+    /// Every transport the server listens on, with its address. The first one
+    /// dials the peers, verifying them with `verify`.
+    fn transports(&self, verify: Verify) -> Vec<(Arc<dyn Transport>, String)> {
+        let addr = host_port(&self.bind, &self.port);
+        let quic: Arc<dyn Transport> = match (&self.transport, &self.identity) {
+            (Some(transport), _) => transport.clone(),
+            (None, Some(identity)) => Arc::new(
+                QuicTransport::with_identity(identity.clone()).with_verify(verify),
+            ),
+            (None, None) => Arc::new(QuicTransport::default().with_verify(verify)),
+        };
+        let mut transports = vec![(quic, addr.clone())];
+        if self.tcp {
+            let tcp = match &self.identity {
+                Some(identity) => TcpTransport::with_identity(identity.clone()),
+                None => TcpTransport::default(),
+            };
+            transports.push((Arc::new(tcp), addr));
+        }
+        if let Some(ws_port) = &self.ws_port {
+            let ws = match &self.identity {
+                Some(identity) => WsTransport::with_identity(identity.clone()),
+                None => WsTransport::default(),
+            };
+            transports.push((Arc::new(ws), host_port(&self.bind, ws_port)));
+        }
+        transports.extend(self.listeners.iter().cloned());
+        transports
+    }
+
+    pub async fn start(mut self) -> Result<(), crate::Error> {
+        let mut verify = Verify::default();
+        if let Some(cluster) = &self.cluster {
+            cluster.check()?;
+            verify = cluster.verify(self.identity.as_ref())?;
+        }
+        // One certificate for every listener, so that one pin fits them all.
+        if self.identity.is_none() {
+            self.identity = Some(Identity::self_signed()?);
+        }
+        let transports = self.transports(verify);
+        let registry = self
+            .registry
+            .clone()
+            .unwrap_or_else(|| Arc::new(MemoryRegistry::new()));
+        // Peers are dialed over the first transport, so from the QUIC port.
+        let node = self
+            .cluster
+            .clone()
+            .map(|cluster| Node::new(cluster, transports[0].0.clone()));
+        let state = Arc::new(State::new(
+            registry,
+            self.heartbeat,
+            self.session_sink.clone(),
+            self.settings,
+            self.reloader.clone(),
+            node,
+        ));
         let (shutdown_complete_tx, mut shutdown_complete_rx) = mpsc::channel(1);
         let notify_shutdown: Sender<()> = broadcast::channel(1).0;
-        info!("server start");
-        loop {
-            let udp_stream = tokio::select! {
-                _ = signal::ctrl_c() => {
-                    drop(shutdown_complete_tx);
-                    drop(notify_shutdown);
-                    shutdown_complete_rx.recv().await;
-                    return Ok(());
-                },
-                res = endpoint.accept() => res
-            };
-            match udp_stream {
-                Some(incoming) => {
-                    let async_cache_clone = async_cache.clone();
-                    let shutdown_complete_tx_clone = shutdown_complete_tx.clone();
-                    let notify_shutdown = notify_shutdown.subscribe();
-                    tokio::spawn(async move {
-                        debug!("connect udpStream : {:?}", incoming);
-                        let connection = match incoming.await {
-                            Ok(connection) => connection,
-                            Err(error) => {
-                                info!("erro : {:?}", error);
-                                return;
-                            }
-                        };
-                        let socket_addr = connection.remote_address();
-                        let channel = Channel::new(
-                            connection,
-                            socket_addr,
-                            async_cache_clone,
-                            shutdown_complete_tx_clone,
-                            Shutdown::new(notify_shutdown),
-                        );
-                        let error = channel.run().await;
-                        debug!("udp_stream end : {:?}", error);
-                    });
+        let mut listeners = vec![];
+        for (transport, addr) in transports {
+            listeners.push(transport.listen(&addr).await?);
+        }
+        for listener in listeners {
+            tokio::spawn(accept(
+                listener,
+                state.clone(),
+                shutdown_complete_tx.clone(),
+                Shutdown::new(notify_shutdown.subscribe()),
+            ));
+        }
+        for peer in state.node.iter().flat_map(|node| node.peers()) {
+            tokio::spawn(cluster::announce(
+                state.clone(),
+                peer.clone(),
+                Shutdown::new(notify_shutdown.subscribe()),
+            ));
+        }
+        if let Some(addr) = &self.admin_addr {
+            let listener = TcpListener::bind(addr).await?;
+            let state = state.clone();
+            let handler: http::Handler =
+                Arc::new(move |request| Box::pin(admin::handle(state.clone(), request)));
+            let mut shutdown = Shutdown::new(notify_shutdown.subscribe());
+            tokio::spawn(async move {
+                tokio::select! {
+                    _ = http::serve(listener, handler) => (),
+                    _ = shutdown.recv() => (),
+                }
+            });
+            info!("admin start : {}", addr);
+        }
+        if let Some(addr) = self.metrics_addr.clone() {
+            let mut shutdown = Shutdown::new(notify_shutdown.subscribe());
+            tokio::spawn(async move {
+                tokio::select! {
+                    res = metrics::serve(&addr) => info!("metrics end : {:?}", res),
+                    _ = shutdown.recv() => (),
                 }
-                None => info!("udp connect, err"),
+            });
+        }
+        if let Some(reloader) = self.reloader.clone() {
+            let state = state.clone();
+            let mut shutdown = Shutdown::new(notify_shutdown.subscribe());
+            tokio::spawn(async move {
+                tokio::select! {
+                    res = reload_on_hangup(state, reloader) => info!("reload end : {:?}", res),
+                    _ = shutdown.recv() => (),
+                }
+            });
+        }
+        if let Some(identity) = &self.identity {
+            info!("tls certificate sha-256 : {}", identity.fingerprint());
+        }
+        info!("server start");
+        let _ = signal::ctrl_c().await;
+        // Drain first: agents are sent to other servers while the open tunnels
+        // run on, until they end, the drain times out or ctrl-c comes again.
+        info!("server draining , waiting up to {:?}", self.drain_timeout);
+        state.drain();
+        tokio::select! {
+            _ = state.tunnels_closed() => info!("server drained"),
+            _ = tokio::time::sleep(self.drain_timeout) => info!(
+                "drain time out , closing {} tunnels",
+                state.tunnels.lock().unwrap().len()
+            ),
+            _ = signal::ctrl_c() => info!("drain interrupted"),
+        }
+        drop(notify_shutdown);
+        drop(shutdown_complete_tx);
+        shutdown_complete_rx.recv().await;
+        Ok(())
+    }
+}
+
+/// Reloads the settings every time the process gets SIGHUP.
+#[cfg(unix)]
+async fn reload_on_hangup(state: Arc<State>, reloader: Reloader) -> Result<(), crate::Error> {
+    let mut hangup = signal::unix::signal(signal::unix::SignalKind::hangup())?;
+    while hangup.recv().await.is_some() {
+        match reloader() {
+            Ok(settings) => {
+                state.reload(settings);
+                info!("settings reloaded");
             }
+            Err(error) => info!("reload err : {}", error),
         }
     }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn reload_on_hangup(_state: Arc<State>, _reloader: Reloader) -> Result<(), crate::Error> {
+    std::future::pending().await
+}
+
+/// Accepts connections from `listener` and runs a channel for every stream
+/// the client opens on them. A failing listener is retried with a growing
+/// pause. Once the server drains, new connections are only taken for as long
+/// as agents may still dial back for tunnels being set up.
+async fn accept(
+    mut listener: Box<dyn Listener>,
+    state: Arc<State>,
+    shutdown_complete_tx: mpsc::Sender<()>,
+    mut shutdown: Shutdown,
+) {
+    let closing = async {
+        state.drained().await;
+        tokio::time::sleep(state.heartbeat.timeout()).await;
+    };
+    tokio::pin!(closing);
+    let mut backoff = ACCEPT_BACKOFF;
+    loop {
+        let connecting = tokio::select! {
+            res = listener.accept() => match res {
+                Ok(connecting) => {
+                    backoff = ACCEPT_BACKOFF;
+                    connecting
+                }
+                Err(error) => {
+                    warn!("accept err : {:?}, retrying in {:?}", error, backoff);
+                    tokio::select! {
+                        _ = tokio::time::sleep(backoff) => (),
+                        _ = &mut closing => return,
+                        _ = shutdown.recv() => return,
+                    }
+                    backoff = (backoff * 2).min(MAX_ACCEPT_BACKOFF);
+                    continue;
+                }
+            },
+            _ = &mut closing => return,
+            _ = shutdown.recv() => return,
+        };
+        let state = state.clone();
+        let shutdown_complete_tx = shutdown_complete_tx.clone();
+        let mut shutdown = shutdown.resubscribe();
+        tokio::spawn(async move {
+            let mut connection = match connecting.await {
+                Ok(connection) => connection,
+                Err(error) => {
+                    metrics().handshake_failures.inc();
+                    info!("erro : {:?}", error);
+                    return;
+                }
+            };
+            let socket_addr = connection.remote_addr();
+            debug!("connect : {:?}", socket_addr);
+            loop {
+                let stream = tokio::select! {
+                    res = connection.accept_stream() => res,
+                    _ = shutdown.recv() => return,
+                };
+                let buffer = match stream {
+                    Ok(Some(buffer)) => buffer,
+                    Ok(None) => break,
+                    Err(error) => {
+                        debug!("accept stream err : {:?}", error);
+                        break;
+                    }
+                };
+                let channel = Channel::new(
+                    buffer,
+                    socket_addr,
+                    state.clone(),
+                    shutdown_complete_tx.clone(),
+                    shutdown.resubscribe(),
+                );
+                tokio::spawn(async move {
+                    let error = channel.run().await;
+                    debug!("stream end : {:?}", error);
+                });
+            }
+            debug!("connection end : {:?}", socket_addr);
+        });
+    }
 }
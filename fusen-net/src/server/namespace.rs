@@ -0,0 +1,148 @@
+use std::collections::{HashMap, HashSet};
+
+/// The namespace of agents that don't name one. It is open to every agent.
+pub const DEFAULT_NAMESPACE: &str = "default";
+
+/// Tenants sharing a server. Each namespace has its own token and its own
+/// tags, and agents reach other namespaces only where granted.
+#[derive(Clone, Debug, Default)]
+pub struct Namespaces {
+    tokens: HashMap<String, String>,
+    grants: HashMap<String, HashSet<String>>,
+}
+
+impl Namespaces {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Lets agents that present `token` into `namespace`.
+    pub fn with_namespace(mut self, namespace: &str, token: &str) -> Self {
+        self.tokens.insert(namespace.to_owned(), token.to_owned());
+        self
+    }
+
+    /// Lets agents of `source_namespaces` reach the agents of `target_namespace`.
+    pub fn grant<I, S>(mut self, target_namespace: &str, source_namespaces: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.grants
+            .entry(target_namespace.to_owned())
+            .or_default()
+            .extend(source_namespaces.into_iter().map(Into::into));
+        self
+    }
+
+    /// Checks that `namespace` exists, without looking at credentials.
+    pub(crate) fn check_known(&self, namespace: Option<&str>) -> Result<(), String> {
+        match namespace {
+            Some(namespace) if !is_default(namespace) && !self.tokens.contains_key(namespace) => {
+                Err(format!("unknown namespace {}", namespace))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks that an agent presenting `token` belongs in `namespace`.
+    pub(crate) fn admit(&self, namespace: Option<&str>, token: Option<&str>) -> Result<(), String> {
+        self.check_known(namespace)?;
+        match namespace.and_then(|namespace| self.tokens.get(namespace)) {
+            Some(expected) if Some(expected.as_str()) != token => Err(format!(
+                "invalid token for namespace {}",
+                namespace.unwrap_or_default()
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks that agents of `source` may reach agents of `target`.
+    pub(crate) fn check(&self, source: Option<&str>, target: Option<&str>) -> Result<(), String> {
+        let source = source.unwrap_or(DEFAULT_NAMESPACE);
+        let target = target.unwrap_or(DEFAULT_NAMESPACE);
+        if source == target
+            || self
+                .grants
+                .get(target)
+                .is_some_and(|sources| sources.contains(source))
+        {
+            Ok(())
+        } else {
+            Err(format!("namespace {} may not reach {}", source, target))
+        }
+    }
+}
+
+fn is_default(namespace: &str) -> bool {
+    namespace == DEFAULT_NAMESPACE
+}
+
+/// Whether two namespaces are the same, unset meaning the default one.
+pub(crate) fn same(a: Option<&str>, b: Option<&str>) -> bool {
+    a.unwrap_or(DEFAULT_NAMESPACE) == b.unwrap_or(DEFAULT_NAMESPACE)
+}
+
+/// The name of `tag` on the server: `namespace/tag`, or just the tag in the
+/// default namespace.
+pub fn qualify(namespace: Option<&str>, tag: &str) -> String {
+    match namespace {
+        Some(namespace) if !is_default(namespace) => format!("{}/{}", namespace, tag),
+        _ => tag.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn namespaces() -> Namespaces {
+        Namespaces::new()
+            .with_namespace("team-a", "a-secret")
+            .with_namespace("team-b", "b-secret")
+            .grant("team-b", ["team-a"])
+    }
+
+    #[test]
+    fn admit_checks_the_namespace_token() {
+        let namespaces = namespaces();
+        assert!(namespaces.admit(Some("team-a"), Some("a-secret")).is_ok());
+        assert_eq!(
+            namespaces.admit(Some("team-a"), Some("b-secret")),
+            Err("invalid token for namespace team-a".to_owned())
+        );
+        assert!(namespaces.admit(Some("team-a"), None).is_err());
+        assert_eq!(
+            namespaces.admit(Some("team-c"), Some("a-secret")),
+            Err("unknown namespace team-c".to_owned())
+        );
+    }
+
+    #[test]
+    fn default_namespace_is_open() {
+        let namespaces = namespaces();
+        for namespace in [None, Some(DEFAULT_NAMESPACE)] {
+            assert!(namespaces.admit(namespace, None).is_ok());
+            assert!(namespaces.admit(namespace, Some("anything")).is_ok());
+        }
+    }
+
+    #[test]
+    fn other_namespaces_only_where_granted() {
+        let namespaces = namespaces();
+        assert!(namespaces.check(Some("team-a"), Some("team-a")).is_ok());
+        assert!(namespaces.check(Some("team-a"), Some("team-b")).is_ok());
+        assert!(namespaces.check(Some("team-b"), Some("team-a")).is_err());
+        assert!(namespaces.check(None, Some("team-b")).is_err());
+        assert!(namespaces.check(None, Some(DEFAULT_NAMESPACE)).is_ok());
+    }
+
+    #[test]
+    fn tags_are_qualified_outside_the_default_namespace() {
+        assert_eq!(qualify(Some("team-a"), "agent1"), "team-a/agent1");
+        assert_eq!(qualify(Some(DEFAULT_NAMESPACE), "agent1"), "agent1");
+        assert_eq!(qualify(None, "agent1"), "agent1");
+        assert!(same(None, Some(DEFAULT_NAMESPACE)));
+        assert!(!same(Some("team-a"), None));
+    }
+}
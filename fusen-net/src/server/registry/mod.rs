@@ -0,0 +1,445 @@
+//! The agents known to the server: the ones connected right now and the ones
+//! defined ahead of time, with their credentials and services.
+
+use crate::selector::Selector;
+use crate::{ChannelInfo, MetaData};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteRegistry;
+
+/// An agent defined ahead of time.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct AgentDefinition {
+    #[serde(default)]
+    pub tag: String,
+    /// Token the agent has to register with, any when unset.
+    #[serde(default)]
+    pub token: Option<String>,
+    /// SHA-256 of the token, hex encoded, kept instead of the token by
+    /// registries that persist definitions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_sha256: Option<String>,
+    /// Target hosts other agents may reach through it, any when empty.
+    #[serde(default)]
+    pub services: Vec<String>,
+}
+
+impl AgentDefinition {
+    pub fn new(tag: &str) -> Self {
+        AgentDefinition {
+            tag: tag.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_token(mut self, token: &str) -> Self {
+        self.token = Some(token.to_owned());
+        self
+    }
+
+    pub fn with_services(mut self, services: Vec<String>) -> Self {
+        self.services = services;
+        self
+    }
+
+    /// Replaces the token with its hash.
+    pub fn hashed(mut self) -> Self {
+        if let Some(token) = self.token.take() {
+            self.token_sha256 = Some(sha256(&token));
+        }
+        self
+    }
+
+    /// Whether the agent has to register with a token.
+    pub fn has_token(&self) -> bool {
+        self.token.is_some() || self.token_sha256.is_some()
+    }
+
+    /// Whether an agent registering with `token` is this one.
+    pub fn admits(&self, token: Option<&str>) -> bool {
+        match (&self.token, &self.token_sha256) {
+            (Some(expected), _) => token == Some(expected.as_str()),
+            (None, Some(hash)) => token.is_some_and(|token| sha256(token) == *hash),
+            (None, None) => true,
+        }
+    }
+
+    /// Whether other agents may reach `target_host` through this one.
+    pub fn exposes(&self, target_host: &str) -> bool {
+        self.services.is_empty() || self.services.iter().any(|service| service == target_host)
+    }
+}
+
+/// What the registry knows of a tag, connected or not. Times are
+/// milliseconds since the Unix epoch.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct AgentRecord {
+    pub tag: String,
+    /// The address of the newest agent while one is connected.
+    pub net_addr: Option<SocketAddr>,
+    pub mate_data: MetaData,
+    pub connected_at: Option<u64>,
+    pub last_seen: Option<u64>,
+    pub definition: Option<AgentDefinition>,
+    /// Every agent connected under the tag, oldest first.
+    pub members: Vec<MemberRecord>,
+}
+
+/// One of the agents connected under a tag.
+#[derive(Clone, Debug, Serialize)]
+pub struct MemberRecord {
+    pub net_addr: SocketAddr,
+    pub connected_at: u64,
+    pub tunnels: usize,
+    pub rtt_micros: Option<u64>,
+    pub healthy: bool,
+    /// The cluster peer the agent is registered with, when not this server.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server: Option<String>,
+}
+
+impl From<&Arc<ChannelInfo>> for MemberRecord {
+    fn from(agent: &Arc<ChannelInfo>) -> Self {
+        MemberRecord {
+            net_addr: agent.net_addr(),
+            connected_at: unix_millis(agent.created_at()),
+            tunnels: agent.tunnels(),
+            rtt_micros: agent.rtt().map(|rtt| rtt.as_micros() as u64),
+            healthy: agent.is_healthy(),
+            server: agent.peer().map(str::to_owned),
+        }
+    }
+}
+
+/// A change in whether, or where, a tag is connected.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RegistryEvent {
+    /// The first agent of the tag connected.
+    Online(String),
+    /// An agent joined or left the tag while others stay connected.
+    AddressChanged(String),
+    /// The last agent of the tag went away.
+    Offline(String),
+}
+
+impl RegistryEvent {
+    pub fn tag(&self) -> &str {
+        match self {
+            RegistryEvent::Online(tag)
+            | RegistryEvent::AddressChanged(tag)
+            | RegistryEvent::Offline(tag) => tag,
+        }
+    }
+}
+
+/// Keeps track of agents for the server. Connected agents always live in
+/// memory, implementations may persist everything else.
+pub trait Registry: Send + Sync {
+    /// Adds `agent` to the agents connected under its tag.
+    fn register(&self, agent: Arc<ChannelInfo>) -> Result<(), crate::Error>;
+
+    /// The newest agent connected under `tag`.
+    fn lookup(&self, tag: &str) -> Option<Arc<ChannelInfo>> {
+        self.members(tag).pop()
+    }
+
+    /// Every agent connected under `tag`, oldest first.
+    fn members(&self, tag: &str) -> Vec<Arc<ChannelInfo>>;
+
+    /// Every known agent.
+    fn list(&self) -> Vec<AgentRecord>;
+
+    /// Every connected agent whose labels match `selector`.
+    fn select(&self, selector: &Selector) -> Vec<Arc<ChannelInfo>>;
+
+    /// Drops `agent` from the agents of its tag.
+    fn remove(&self, agent: &Arc<ChannelInfo>) -> Result<(), crate::Error>;
+
+    /// Tags going online, offline or moving, from now on.
+    fn watch(&self) -> broadcast::Receiver<RegistryEvent>;
+
+    fn definition(&self, tag: &str) -> Option<AgentDefinition>;
+
+    fn define(&self, definition: AgentDefinition) -> Result<(), crate::Error>;
+
+    /// Forgets the definition of `tag`, returning whether there was one.
+    fn undefine(&self, tag: &str) -> Result<bool, crate::Error>;
+}
+
+#[derive(Default)]
+struct Entry {
+    agents: Vec<Arc<ChannelInfo>>,
+    mate_data: MetaData,
+    last_seen: Option<u64>,
+    definition: Option<AgentDefinition>,
+}
+
+/// Keeps everything in memory, forgetting it on restart.
+pub struct MemoryRegistry {
+    entries: Mutex<HashMap<String, Entry>>,
+    events: broadcast::Sender<RegistryEvent>,
+}
+
+impl Default for MemoryRegistry {
+    fn default() -> Self {
+        MemoryRegistry {
+            entries: Default::default(),
+            events: broadcast::channel(1024).0,
+        }
+    }
+}
+
+impl MemoryRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_definition(self, definition: AgentDefinition) -> Self {
+        let _ = self.define(definition);
+        self
+    }
+
+    /// Adds what a persistent registry remembered of an agent.
+    #[cfg(feature = "sqlite")]
+    pub(crate) fn restore(&self, record: AgentRecord) {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.entry(record.tag).or_default();
+        entry.mate_data = record.mate_data;
+        entry.last_seen = record.last_seen;
+        entry.definition = record.definition;
+    }
+}
+
+impl Registry for MemoryRegistry {
+    fn register(&self, agent: Arc<ChannelInfo>) -> Result<(), crate::Error> {
+        let tag = agent.tag().to_owned();
+        let event = {
+            let mut entries = self.entries.lock().unwrap();
+            let entry = entries.entry(tag.clone()).or_default();
+            entry.mate_data = agent.register_info().get_mate_data().clone();
+            entry.last_seen = Some(unix_millis(SystemTime::now()));
+            entry.agents.push(agent);
+            match entry.agents.len() {
+                1 => RegistryEvent::Online(tag),
+                _ => RegistryEvent::AddressChanged(tag),
+            }
+        };
+        let _ = self.events.send(event);
+        Ok(())
+    }
+
+    fn members(&self, tag: &str) -> Vec<Arc<ChannelInfo>> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .get(tag)
+            .map(|entry| entry.agents.clone())
+            .unwrap_or_default()
+    }
+
+    fn select(&self, selector: &Selector) -> Vec<Arc<ChannelInfo>> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .values()
+            .flat_map(|entry| &entry.agents)
+            .filter(|agent| selector.matches(agent.register_info().get_mate_data()))
+            .cloned()
+            .collect()
+    }
+
+    fn list(&self) -> Vec<AgentRecord> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .iter()
+            .map(|(tag, entry)| AgentRecord {
+                tag: tag.clone(),
+                net_addr: entry.agents.last().map(|agent| agent.net_addr()),
+                mate_data: entry.mate_data.clone(),
+                connected_at: entry
+                    .agents
+                    .last()
+                    .map(|agent| unix_millis(agent.created_at())),
+                last_seen: entry.last_seen,
+                definition: entry.definition.clone(),
+                members: entry.agents.iter().map(MemberRecord::from).collect(),
+            })
+            .collect()
+    }
+
+    fn remove(&self, agent: &Arc<ChannelInfo>) -> Result<(), crate::Error> {
+        let tag = agent.tag();
+        let event = {
+            let mut entries = self.entries.lock().unwrap();
+            let Some(entry) = entries.get_mut(tag) else {
+                return Ok(());
+            };
+            let Some(index) = entry
+                .agents
+                .iter()
+                .position(|member| Arc::ptr_eq(member, agent))
+            else {
+                return Ok(());
+            };
+            entry.agents.remove(index);
+            entry.last_seen = Some(unix_millis(SystemTime::now()));
+            match entry.agents.len() {
+                0 => RegistryEvent::Offline(tag.to_owned()),
+                _ => RegistryEvent::AddressChanged(tag.to_owned()),
+            }
+        };
+        let _ = self.events.send(event);
+        Ok(())
+    }
+
+    fn watch(&self) -> broadcast::Receiver<RegistryEvent> {
+        self.events.subscribe()
+    }
+
+    fn definition(&self, tag: &str) -> Option<AgentDefinition> {
+        let entries = self.entries.lock().unwrap();
+        entries.get(tag).and_then(|entry| entry.definition.clone())
+    }
+
+    fn define(&self, definition: AgentDefinition) -> Result<(), crate::Error> {
+        let mut entries = self.entries.lock().unwrap();
+        let tag = definition.tag.clone();
+        entries.entry(tag).or_default().definition = Some(definition);
+        Ok(())
+    }
+
+    fn undefine(&self, tag: &str) -> Result<bool, crate::Error> {
+        let mut entries = self.entries.lock().unwrap();
+        Ok(entries
+            .get_mut(tag)
+            .and_then(|entry| entry.definition.take())
+            .is_some())
+    }
+}
+
+/// Hex encoded SHA-256 of `token`.
+fn sha256(token: &str) -> String {
+    ring::digest::digest(&ring::digest::SHA256, token.as_bytes())
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+pub(crate) fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::RegisterInfo;
+    use tokio::sync::mpsc;
+
+    fn agent(tag: &str, port: u16, labels: &[(&str, &str)]) -> Arc<ChannelInfo> {
+        let labels: HashMap<String, String> = labels
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        let register_info =
+            RegisterInfo::new("server".to_owned(), tag.to_owned()).with_mate_data(labels.into());
+        Arc::new(ChannelInfo::new(
+            ([127, 0, 0, 1], port).into(),
+            register_info,
+            mpsc::unbounded_channel().0,
+        ))
+    }
+
+    #[test]
+    fn members_come_and_go_with_events() {
+        let registry = MemoryRegistry::new();
+        let mut events = registry.watch();
+        let (first, second) = (agent("agent1", 4000, &[]), agent("agent1", 4001, &[]));
+        registry.register(first.clone()).unwrap();
+        registry.register(second.clone()).unwrap();
+        assert_eq!(registry.members("agent1").len(), 2);
+        assert!(Arc::ptr_eq(&registry.lookup("agent1").unwrap(), &second));
+
+        registry.remove(&second).unwrap();
+        assert!(Arc::ptr_eq(&registry.lookup("agent1").unwrap(), &first));
+        registry.remove(&first).unwrap();
+        registry.remove(&first).unwrap();
+        assert!(registry.lookup("agent1").is_none());
+
+        let tag = "agent1".to_owned();
+        for expected in [
+            RegistryEvent::Online(tag.clone()),
+            RegistryEvent::AddressChanged(tag.clone()),
+            RegistryEvent::AddressChanged(tag.clone()),
+            RegistryEvent::Offline(tag.clone()),
+        ] {
+            assert_eq!(events.try_recv().unwrap(), expected);
+        }
+        assert!(events.try_recv().is_err());
+        let record = &registry.list()[0];
+        assert!(record.members.is_empty() && record.last_seen.is_some());
+    }
+
+    #[test]
+    fn select_matches_each_agent_by_its_labels() {
+        let registry = MemoryRegistry::new();
+        registry
+            .register(agent("agent1", 4000, &[("env", "prod")]))
+            .unwrap();
+        registry
+            .register(agent("agent1", 4001, &[("env", "test")]))
+            .unwrap();
+        registry
+            .register(agent("agent2", 4002, &[("env", "prod")]))
+            .unwrap();
+        let selected = |selector: &str| {
+            let mut ports: Vec<_> = registry
+                .select(&selector.parse().unwrap())
+                .iter()
+                .map(|agent| agent.net_addr().port())
+                .collect();
+            ports.sort();
+            ports
+        };
+        assert_eq!(selected("env=prod"), [4000, 4002]);
+        assert_eq!(selected("env!=prod"), [4001]);
+        assert!(selected("env=dev").is_empty());
+    }
+
+    #[test]
+    fn definitions_admit_their_token_or_its_hash() {
+        let open = AgentDefinition::new("agent1");
+        assert!(!open.has_token());
+        assert!(open.admits(None) && open.admits(Some("any")));
+
+        let definition = AgentDefinition::new("agent1").with_token("s3cret");
+        let hashed = definition.clone().hashed();
+        assert_eq!(hashed.token, None);
+        for definition in [definition, hashed] {
+            assert!(definition.has_token());
+            assert!(definition.admits(Some("s3cret")));
+            assert!(!definition.admits(Some("s3cre")));
+            assert!(!definition.admits(None));
+        }
+    }
+
+    #[test]
+    fn definitions_outlive_their_agents() {
+        let registry = MemoryRegistry::new()
+            .with_definition(AgentDefinition::new("agent1").with_services(vec!["a:1".to_owned()]));
+        let agent = agent("agent1", 4000, &[]);
+        registry.register(agent.clone()).unwrap();
+        registry.remove(&agent).unwrap();
+        let definition = registry.definition("agent1").unwrap();
+        assert!(definition.exposes("a:1") && !definition.exposes("b:1"));
+        assert!(registry.undefine("agent1").unwrap());
+        assert!(!registry.undefine("agent1").unwrap());
+    }
+}
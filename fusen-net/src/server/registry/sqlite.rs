@@ -0,0 +1,259 @@
+use super::{unix_millis, AgentDefinition, AgentRecord, MemoryRegistry, Registry, RegistryEvent};
+use crate::selector::Selector;
+use crate::{ChannelInfo, MetaData};
+use rusqlite::{params, Connection};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::SystemTime;
+use tokio::sync::broadcast;
+use tracing::warn;
+
+/// Keeps definitions, last-seen times and metadata in a SQLite database, so
+/// they survive restarts. Connected agents live in memory as usual and
+/// answer every read; writes go to the database on a thread of their own.
+/// Tokens are stored as their SHA-256.
+pub struct SqliteRegistry {
+    memory: MemoryRegistry,
+    writes: Mutex<Sender<Write>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+enum Write {
+    Seen {
+        tag: String,
+        mate_data: String,
+        last_seen: u64,
+    },
+    Define {
+        tag: String,
+        token_sha256: Option<String>,
+        services: String,
+    },
+    Undefine(String),
+}
+
+impl Write {
+    fn execute(&self, connection: &Connection) -> rusqlite::Result<usize> {
+        match self {
+            Write::Seen {
+                tag,
+                mate_data,
+                last_seen,
+            } => connection.execute(
+                "INSERT INTO agents (tag, mate_data, last_seen) VALUES (?1, ?2, ?3)
+                 ON CONFLICT(tag) DO UPDATE SET mate_data = ?2, last_seen = ?3",
+                params![tag, mate_data, last_seen],
+            ),
+            Write::Define {
+                tag,
+                token_sha256,
+                services,
+            } => connection.execute(
+                "INSERT INTO agents (tag, token_sha256, services, defined) VALUES (?1, ?2, ?3, 1)
+                 ON CONFLICT(tag) DO UPDATE SET token_sha256 = ?2, services = ?3, defined = 1",
+                params![tag, token_sha256, services],
+            ),
+            Write::Undefine(tag) => connection.execute(
+                "UPDATE agents SET token_sha256 = NULL, services = NULL, defined = 0
+                 WHERE tag = ?1",
+                params![tag],
+            ),
+        }
+    }
+}
+
+/// Applies writes until the registry is dropped.
+fn write(connection: Connection, writes: Receiver<Write>) {
+    for write in writes {
+        if let Err(error) = write.execute(&connection) {
+            warn!("sqlite registry write failed : {}", error);
+        }
+    }
+}
+
+impl SqliteRegistry {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, crate::Error> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS agents (
+                tag TEXT PRIMARY KEY,
+                mate_data TEXT,
+                last_seen INTEGER,
+                token_sha256 TEXT,
+                services TEXT,
+                defined INTEGER NOT NULL DEFAULT 0
+            )",
+        )?;
+        let memory = MemoryRegistry::new();
+        {
+            let mut statement = connection.prepare(
+                "SELECT tag, mate_data, last_seen, token_sha256, services, defined FROM agents",
+            )?;
+            let rows = statement.query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, Option<String>>(1)?,
+                    row.get::<_, Option<u64>>(2)?,
+                    row.get::<_, Option<String>>(3)?,
+                    row.get::<_, Option<String>>(4)?,
+                    row.get::<_, bool>(5)?,
+                ))
+            })?;
+            for row in rows {
+                let (tag, mate_data, last_seen, token_sha256, services, defined) = row?;
+                let definition = defined.then(|| AgentDefinition {
+                    tag: tag.clone(),
+                    token: None,
+                    token_sha256,
+                    services: services
+                        .and_then(|services| serde_json::from_str(&services).ok())
+                        .unwrap_or_default(),
+                });
+                memory.restore(AgentRecord {
+                    tag,
+                    mate_data: mate_data
+                        .and_then(|mate_data| serde_json::from_str(&mate_data).ok())
+                        .unwrap_or_default(),
+                    last_seen,
+                    definition,
+                    ..Default::default()
+                });
+            }
+        }
+        let (writes, receiver) = mpsc::channel();
+        let writer = thread::Builder::new()
+            .name("sqlite-registry".to_owned())
+            .spawn(move || write(connection, receiver))?;
+        Ok(SqliteRegistry {
+            memory,
+            writes: Mutex::new(writes),
+            writer: Some(writer),
+        })
+    }
+
+    fn write(&self, write: Write) -> Result<(), crate::Error> {
+        self.writes
+            .lock()
+            .unwrap()
+            .send(write)
+            .map_err(|_| "sqlite registry writer stopped".into())
+    }
+
+    fn seen(&self, tag: &str, mate_data: &MetaData) -> Result<(), crate::Error> {
+        self.write(Write::Seen {
+            tag: tag.to_owned(),
+            mate_data: serde_json::to_string(mate_data)?,
+            last_seen: unix_millis(SystemTime::now()),
+        })
+    }
+}
+
+impl Registry for SqliteRegistry {
+    fn register(&self, agent: Arc<ChannelInfo>) -> Result<(), crate::Error> {
+        self.seen(agent.tag(), agent.register_info().get_mate_data())?;
+        self.memory.register(agent)
+    }
+
+    fn members(&self, tag: &str) -> Vec<Arc<ChannelInfo>> {
+        self.memory.members(tag)
+    }
+
+    fn select(&self, selector: &Selector) -> Vec<Arc<ChannelInfo>> {
+        self.memory.select(selector)
+    }
+
+    fn list(&self) -> Vec<AgentRecord> {
+        self.memory.list()
+    }
+
+    fn remove(&self, agent: &Arc<ChannelInfo>) -> Result<(), crate::Error> {
+        if self
+            .memory
+            .members(agent.tag())
+            .iter()
+            .any(|member| Arc::ptr_eq(member, agent))
+        {
+            self.seen(agent.tag(), agent.register_info().get_mate_data())?;
+        }
+        self.memory.remove(agent)
+    }
+
+    fn watch(&self) -> broadcast::Receiver<RegistryEvent> {
+        self.memory.watch()
+    }
+
+    fn definition(&self, tag: &str) -> Option<AgentDefinition> {
+        self.memory.definition(tag)
+    }
+
+    fn define(&self, definition: AgentDefinition) -> Result<(), crate::Error> {
+        let definition = definition.hashed();
+        self.write(Write::Define {
+            tag: definition.tag.clone(),
+            token_sha256: definition.token_sha256.clone(),
+            services: serde_json::to_string(&definition.services)?,
+        })?;
+        self.memory.define(definition)
+    }
+
+    fn undefine(&self, tag: &str) -> Result<bool, crate::Error> {
+        self.write(Write::Undefine(tag.to_owned()))?;
+        self.memory.undefine(tag)
+    }
+}
+
+impl Drop for SqliteRegistry {
+    /// Waits for the pending writes.
+    fn drop(&mut self) {
+        drop(std::mem::replace(
+            self.writes.get_mut().unwrap(),
+            mpsc::channel().0,
+        ));
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn definitions_survive_a_restart_with_a_hashed_token() {
+        let path = env::temp_dir().join(format!("fusen-registry-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        {
+            let registry = SqliteRegistry::open(&path).unwrap();
+            let definition = AgentDefinition::new("agent1")
+                .with_token("s3cret")
+                .with_services(vec!["127.0.0.1:8081".to_owned()]);
+            registry.define(definition).unwrap();
+            registry.define(AgentDefinition::new("agent2")).unwrap();
+            assert!(registry.undefine("agent2").unwrap());
+        }
+        let connection = Connection::open(&path).unwrap();
+        let stored: Option<String> = connection
+            .query_row(
+                "SELECT token_sha256 FROM agents WHERE tag = 'agent1'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_ne!(stored.as_deref(), Some("s3cret"));
+
+        let registry = SqliteRegistry::open(&path).unwrap();
+        let definition = registry.definition("agent1").unwrap();
+        assert_eq!(definition.token, None);
+        assert!(definition.admits(Some("s3cret")));
+        assert!(!definition.admits(Some("guess")));
+        assert!(!definition.admits(None));
+        assert!(definition.exposes("127.0.0.1:8081"));
+        assert!(registry.definition("agent2").is_none());
+        drop(registry);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
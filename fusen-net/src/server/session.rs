@@ -0,0 +1,59 @@
+//! Records of the tunnels relayed by the server, for billing and audits.
+
+use crate::client::AgentMode;
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Mutex;
+use tracing::info;
+
+/// One relayed tunnel, from the moment the server was asked for it until it
+/// closed. Timestamps are milliseconds since the Unix epoch, bytes are counted
+/// towards the target host (`bytes_sent`) and back (`bytes_received`).
+#[derive(Clone, Debug, Serialize)]
+pub struct SessionRecord {
+    pub id: String,
+    pub agent_mode: AgentMode,
+    pub source_tag: Option<String>,
+    pub source_addr: SocketAddr,
+    pub target_tag: String,
+    pub target_host: String,
+    pub started_at: u64,
+    pub ended_at: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub close_reason: String,
+}
+
+/// Where session records go once their tunnel closes.
+pub trait SessionSink: Send + Sync {
+    fn record(&self, record: &SessionRecord);
+}
+
+/// Appends every record as a line of JSON to a file.
+pub struct JsonLinesSink {
+    file: Mutex<File>,
+}
+
+impl JsonLinesSink {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, crate::Error> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(JsonLinesSink {
+            file: Mutex::new(file),
+        })
+    }
+}
+
+impl SessionSink for JsonLinesSink {
+    fn record(&self, record: &SessionRecord) {
+        let Ok(mut line) = serde_json::to_vec(record) else {
+            return;
+        };
+        line.push(b'\n');
+        if let Err(error) = self.file.lock().unwrap().write_all(&line) {
+            info!("session record write err : {:?}", error);
+        }
+    }
+}
@@ -0,0 +1,650 @@
+use super::acl::Acl;
+use super::balance::Balancer;
+use super::cache::AsyncCache;
+use super::cluster::Node;
+use super::limits::{BandwidthLimits, ConnectionLimits, ConnectionRate};
+use super::namespace::{self, qualify, Namespaces};
+use super::registry::{unix_millis, AgentDefinition, Registry};
+use super::session::{SessionRecord, SessionSink};
+use super::{Heartbeat, Reloader, Settings};
+use crate::client::AgentMode;
+use crate::frame::{ConnectionInfo, PeerInfo, SubscribeInfo};
+use crate::limit::RateLimit;
+use crate::selector::Selector;
+use crate::ChannelInfo;
+use prometheus::IntCounter;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+use tokio::sync::{watch, Notify};
+
+/// What the server knows about its agents, shared by every channel and the
+/// admin endpoint.
+pub(crate) struct State {
+    pub(crate) registry: Arc<dyn Registry>,
+    /// Tunnels waiting for their target to dial back, by source tag.
+    pub(crate) pending: AsyncCache<String, Arc<ChannelInfo>>,
+    pub(crate) heartbeat: Heartbeat,
+    pub(crate) tunnels: Mutex<HashMap<String, Arc<Tunnel>>>,
+    pub(crate) subscribers: Mutex<HashMap<String, Subscriber>>,
+    pub(crate) session_sink: Option<Arc<dyn SessionSink>>,
+    pub(crate) bandwidth: BandwidthLimits,
+    balancer: Balancer,
+    connection_limits: RwLock<ConnectionLimits>,
+    connection_rate: ConnectionRate,
+    acl: RwLock<Acl>,
+    namespaces: RwLock<Namespaces>,
+    admin_token: RwLock<Option<Arc<str>>>,
+    pub(crate) reloader: Option<Reloader>,
+    /// This server in its cluster, when it is part of one.
+    pub(crate) node: Option<Node>,
+    /// Whether the server is draining, see [`State::drain`].
+    draining: watch::Sender<bool>,
+    tunnel_closed: Notify,
+}
+
+impl State {
+    pub(crate) fn new(
+        registry: Arc<dyn Registry>,
+        heartbeat: Heartbeat,
+        session_sink: Option<Arc<dyn SessionSink>>,
+        settings: Settings,
+        reloader: Option<Reloader>,
+        node: Option<Node>,
+    ) -> Self {
+        State {
+            registry,
+            pending: AsyncCache::new(),
+            heartbeat,
+            tunnels: Default::default(),
+            subscribers: Default::default(),
+            session_sink,
+            bandwidth: settings.bandwidth,
+            balancer: Balancer::new(settings.balance),
+            connection_limits: RwLock::new(settings.connection_limits),
+            connection_rate: Default::default(),
+            acl: RwLock::new(settings.acl),
+            namespaces: RwLock::new(settings.namespaces),
+            admin_token: RwLock::new(settings.admin_token.map(Into::into)),
+            reloader,
+            node,
+            draining: watch::Sender::new(false),
+            tunnel_closed: Notify::new(),
+        }
+    }
+
+    /// Stops taking new work: agents are told to go away while the tunnels
+    /// already open run on.
+    pub(crate) fn drain(&self) {
+        self.draining.send_replace(true);
+    }
+
+    pub(crate) fn is_draining(&self) -> bool {
+        *self.draining.borrow()
+    }
+
+    /// Resolves once the server drains.
+    pub(crate) async fn drained(&self) {
+        let _ = self
+            .draining
+            .subscribe()
+            .wait_for(|draining| *draining)
+            .await;
+    }
+
+    /// Applies `settings` to the running server. Open tunnels keep running
+    /// under the new limits.
+    pub(crate) fn reload(&self, settings: Settings) {
+        self.bandwidth.apply(&settings.bandwidth);
+        self.set_tunnel_limit(settings.bandwidth.tunnel_rate());
+        *self.connection_limits.write().unwrap() = settings.connection_limits;
+        *self.acl.write().unwrap() = settings.acl;
+        *self.namespaces.write().unwrap() = settings.namespaces;
+        self.balancer.set(settings.balance);
+        *self.admin_token.write().unwrap() = settings.admin_token.map(Into::into);
+    }
+
+    pub(crate) fn admin_token(&self) -> Option<Arc<str>> {
+        self.admin_token.read().unwrap().clone()
+    }
+
+    /// Checks that the agent tagged `source_tag` may reach `target_tag`.
+    pub(crate) fn check_acl(
+        &self,
+        source_tag: Option<&str>,
+        target_tag: &str,
+    ) -> Result<(), String> {
+        self.acl.read().unwrap().check(source_tag, target_tag)
+    }
+
+    /// Counts a tunnel asked for from `ip` against the per-ip rate.
+    pub(crate) fn check_rate(&self, ip: IpAddr) -> Result<(), String> {
+        let max_connections_per_ip = self
+            .connection_limits
+            .read()
+            .unwrap()
+            .max_connections_per_ip;
+        match max_connections_per_ip {
+            Some(max) => self.connection_rate.check(ip, max),
+            None => Ok(()),
+        }
+    }
+
+    /// Adds `tunnel` to the open tunnels unless that breaks a connection limit.
+    pub(crate) fn open_tunnel(&self, tunnel: Arc<Tunnel>) -> Result<(), String> {
+        let connection_limits = *self.connection_limits.read().unwrap();
+        let mut tunnels = self.tunnels.lock().unwrap();
+        connection_limits.admit(&tunnel, tunnels.values())?;
+        tunnels.insert(tunnel.id.clone(), tunnel);
+        Ok(())
+    }
+
+    pub(crate) fn close_tunnel(&self, id: &str) {
+        self.tunnels.lock().unwrap().remove(id);
+        self.tunnel_closed.notify_waiters();
+    }
+
+    /// Resolves once no tunnel is open, which is when a draining server is
+    /// done, however long idle agents keep their connections.
+    pub(crate) async fn tunnels_closed(&self) {
+        loop {
+            let closed = self.tunnel_closed.notified();
+            if self.tunnels.lock().unwrap().is_empty() {
+                return;
+            }
+            closed.await;
+        }
+    }
+
+    /// Sets the per-tunnel limit of new and open tunnels.
+    pub(crate) fn set_tunnel_limit(&self, rate: u64) {
+        self.bandwidth.set_tunnel(rate);
+        for tunnel in self.tunnels.lock().unwrap().values() {
+            tunnel.limit.set_rate(rate);
+        }
+    }
+
+    /// Registers `agent` if its definition admits it, or else its namespace
+    /// when the definition has no token.
+    pub(crate) fn register(&self, agent: Arc<ChannelInfo>) -> Result<(), String> {
+        let register_info = agent.register_info();
+        self.admit(
+            Some(agent.tag()),
+            register_info.get_namespace(),
+            register_info.get_token(),
+        )?;
+        self.registry
+            .register(agent)
+            .map_err(|error| error.to_string())
+    }
+
+    /// Checks `token` against the definition of `tag` when it has a token,
+    /// or else against `namespace`.
+    fn admit(
+        &self,
+        tag: Option<&str>,
+        namespace: Option<&str>,
+        token: Option<&str>,
+    ) -> Result<(), String> {
+        let definition = tag
+            .and_then(|tag| self.registry.definition(tag))
+            .filter(AgentDefinition::has_token);
+        let namespaces = self.namespaces.read().unwrap();
+        match definition {
+            Some(definition) => {
+                namespaces.check_known(namespace)?;
+                if !definition.admits(token) {
+                    return Err(format!("invalid token for {}", tag.unwrap_or_default()));
+                }
+                Ok(())
+            }
+            None => namespaces.admit(namespace, token),
+        }
+    }
+
+    /// Checks the credentials of an agent asking for a tunnel or an address,
+    /// and that the tag it claims, which the ACL goes by, is registered from
+    /// `ip`.
+    fn authenticate(
+        &self,
+        agent_tag: Option<&str>,
+        namespace: Option<&str>,
+        token: Option<&str>,
+        ip: IpAddr,
+    ) -> Result<(), String> {
+        self.admit(agent_tag, namespace, token)?;
+        let Some(agent_tag) = agent_tag else {
+            return Ok(());
+        };
+        let ip = ip.to_canonical();
+        if self
+            .registry
+            .members(agent_tag)
+            .iter()
+            .any(|agent| agent.net_addr().ip().to_canonical() == ip)
+        {
+            Ok(())
+        } else {
+            Err(format!("{} is not registered from {}", agent_tag, ip))
+        }
+    }
+
+    /// The agent connected under `tag` to relay the next tunnel through,
+    /// one registered here while there is one.
+    pub(crate) fn pick(&self, tag: &str) -> Option<Arc<ChannelInfo>> {
+        self.balancer.pick(prefer_local(self.registry.members(tag)))
+    }
+
+    /// Checks a connection a cluster peer relays, which the peer resolved
+    /// already, picking an agent registered here for it.
+    pub(crate) fn resolve_relayed(
+        &self,
+        connection_info: ConnectionInfo,
+    ) -> Result<(ConnectionInfo, Arc<ChannelInfo>), String> {
+        let target_tag = connection_info.get_target_tag();
+        self.check_service(target_tag, connection_info.get_target_host())?;
+        let agents = self
+            .registry
+            .members(target_tag)
+            .into_iter()
+            .filter(|agent| agent.peer().is_none())
+            .collect();
+        let target = self
+            .balancer
+            .pick(agents)
+            .ok_or_else(|| format!("{} is not connected", target_tag))?;
+        Ok((connection_info, target))
+    }
+
+    /// Checks that `peer_info` comes from a server of this server's cluster.
+    pub(crate) fn admit_peer(&self, peer_info: &PeerInfo) -> Result<(), String> {
+        match &self.node {
+            Some(node) => node.admit(peer_info),
+            None => Err("not part of a cluster".to_owned()),
+        }
+    }
+
+    /// The agent matching `selector` in `namespace` to relay the next tunnel
+    /// through, among the ones `agent_tag` may reach and that expose
+    /// `target_host`, when given.
+    pub(crate) fn pick_selected(
+        &self,
+        selector: &Selector,
+        namespace: Option<&str>,
+        agent_tag: Option<&str>,
+        target_host: Option<&str>,
+    ) -> Option<Arc<ChannelInfo>> {
+        let agents = self
+            .registry
+            .select(selector)
+            .into_iter()
+            .filter(|agent| {
+                namespace::same(agent.register_info().get_namespace(), namespace)
+                    && self.check_acl(agent_tag, agent.tag()).is_ok()
+                    && target_host.is_none_or(|target_host| {
+                        self.check_service(agent.tag(), target_host).is_ok()
+                    })
+            })
+            .collect();
+        self.balancer.pick(prefer_local(agents))
+    }
+
+    /// Checks that a connection asked for from `ip` may go ahead and picks
+    /// the agent to relay it through, returning the connection named as on
+    /// the server.
+    pub(crate) fn resolve_connection(
+        &self,
+        connection_info: ConnectionInfo,
+        ip: IpAddr,
+    ) -> Result<(ConnectionInfo, Arc<ChannelInfo>), String> {
+        let namespace = connection_info.get_namespace();
+        self.authenticate(
+            connection_info
+                .get_agent_tag()
+                .map(|agent_tag| qualify(namespace, agent_tag))
+                .as_deref(),
+            namespace,
+            connection_info.get_token(),
+            ip,
+        )?;
+        self.namespaces
+            .read()
+            .unwrap()
+            .check(namespace, connection_info.get_target_namespace())?;
+        let target_namespace = connection_info.get_target_namespace().map(str::to_owned);
+        let connection_info = connection_info.qualified();
+        let agent_tag = connection_info.get_agent_tag();
+        let target_host = connection_info.get_target_host();
+        let target = match connection_info.get_selector() {
+            Some(selector) => {
+                let selector = selector.parse()?;
+                self.pick_selected(
+                    &selector,
+                    target_namespace.as_deref(),
+                    agent_tag,
+                    Some(target_host),
+                )
+                .ok_or_else(|| format!("no agent matches {}", selector))?
+            }
+            None => {
+                let target_tag = connection_info.get_target_tag();
+                self.check_acl(agent_tag, target_tag)?;
+                self.check_service(target_tag, target_host)?;
+                self.pick(target_tag)
+                    .ok_or_else(|| format!("{} is not connected", target_tag))?
+            }
+        };
+        Ok((connection_info.routed_to(target.tag()), target))
+    }
+
+    /// Checks that a subscription from `ip` may go ahead, returning the name
+    /// of the subscriber on the server and what it follows.
+    pub(crate) fn resolve_subscribe(
+        &self,
+        subscribe_info: &SubscribeInfo,
+        ip: IpAddr,
+    ) -> Result<(Option<String>, Target), String> {
+        let agent_tag = subscribe_info
+            .get_agent_tag()
+            .map(|agent_tag| qualify(subscribe_info.get_namespace(), agent_tag));
+        self.authenticate(
+            agent_tag.as_deref(),
+            subscribe_info.get_namespace(),
+            subscribe_info.get_token(),
+            ip,
+        )?;
+        self.namespaces.read().unwrap().check(
+            subscribe_info.get_namespace(),
+            subscribe_info.get_target_namespace(),
+        )?;
+        let namespace = subscribe_info.get_target_namespace();
+        let target = match subscribe_info.get_selector() {
+            Some(selector) => Target::Selector(selector.parse()?, namespace.map(str::to_owned)),
+            None => {
+                let target_tag = qualify(namespace, subscribe_info.get_target_tag());
+                self.check_acl(agent_tag.as_deref(), &target_tag)?;
+                Target::Tag(target_tag)
+            }
+        };
+        Ok((agent_tag, target))
+    }
+
+    /// The agent a subscriber of `target` should reach right now.
+    pub(crate) fn pick_target(
+        &self,
+        target: &Target,
+        agent_tag: Option<&str>,
+    ) -> Option<Arc<ChannelInfo>> {
+        match target {
+            Target::Tag(tag) => self.pick(tag),
+            Target::Selector(selector, namespace) => {
+                self.pick_selected(selector, namespace.as_deref(), agent_tag, None)
+            }
+        }
+    }
+
+    /// Checks that the agent tagged `target_tag` exposes `target_host`.
+    pub(crate) fn check_service(&self, target_tag: &str, target_host: &str) -> Result<(), String> {
+        match self.registry.definition(target_tag) {
+            Some(definition) if !definition.exposes(target_host) => Err(format!(
+                "{} is not a service of {}",
+                target_host, target_tag
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The agents registered here, or the ones registered with cluster peers
+/// when there are none, saving tunnels a hop.
+fn prefer_local(agents: Vec<Arc<ChannelInfo>>) -> Vec<Arc<ChannelInfo>> {
+    let (local, remote): (Vec<_>, Vec<_>) =
+        agents.into_iter().partition(|agent| agent.peer().is_none());
+    if local.is_empty() {
+        remote
+    } else {
+        local
+    }
+}
+
+/// A tunnel relayed by the server, keyed by its source tag.
+#[derive(Debug)]
+pub(crate) struct Tunnel {
+    pub(crate) id: String,
+    pub(crate) agent_mode: AgentMode,
+    pub(crate) agent_tag: Option<String>,
+    pub(crate) source_addr: SocketAddr,
+    pub(crate) target_tag: String,
+    pub(crate) target_host: String,
+    pub(crate) created_at: SystemTime,
+    /// Bytes relayed towards the target host and back.
+    pub(crate) sent: IntCounter,
+    pub(crate) received: IntCounter,
+    /// The bandwidth limit of this tunnel alone.
+    pub(crate) limit: Arc<RateLimit>,
+    pub(crate) close: Notify,
+    /// Whether a peer relayed the tunnel, having limited it already.
+    relayed: bool,
+}
+
+impl Tunnel {
+    pub(crate) fn new(
+        connection_info: &ConnectionInfo,
+        source_addr: SocketAddr,
+        rate: u64,
+    ) -> Self {
+        Tunnel {
+            id: connection_info.get_source_tag().to_owned(),
+            agent_mode: connection_info.get_agent_mode().clone(),
+            agent_tag: connection_info.get_agent_tag().map(str::to_owned),
+            source_addr,
+            target_tag: connection_info.get_target_tag().to_owned(),
+            target_host: connection_info.get_target_host().to_owned(),
+            created_at: SystemTime::now(),
+            sent: IntCounter::new("sent", "sent").unwrap(),
+            received: IntCounter::new("received", "received").unwrap(),
+            limit: Arc::new(RateLimit::new(rate)),
+            close: Default::default(),
+            relayed: false,
+        }
+    }
+
+    pub(crate) fn relayed(self) -> Self {
+        Tunnel {
+            relayed: true,
+            ..self
+        }
+    }
+
+    /// Who asked for the tunnel: its agent by tag, qualified by namespace,
+    /// which was checked to be registered from the same ip, or else the ip.
+    /// None when a peer relayed the tunnel.
+    pub(crate) fn source(&self) -> Option<String> {
+        if self.relayed {
+            return None;
+        }
+        Some(match &self.agent_tag {
+            Some(agent_tag) => agent_tag.clone(),
+            None => self.source_addr.ip().to_string(),
+        })
+    }
+
+    /// The record of the tunnel, closed with `res`.
+    pub(crate) fn record(&self, res: &Result<(), crate::Error>) -> SessionRecord {
+        SessionRecord {
+            id: self.id.clone(),
+            agent_mode: self.agent_mode.clone(),
+            source_tag: self.agent_tag.clone(),
+            source_addr: self.source_addr,
+            target_tag: self.target_tag.clone(),
+            target_host: self.target_host.clone(),
+            started_at: unix_millis(self.created_at),
+            ended_at: unix_millis(SystemTime::now()),
+            bytes_sent: self.sent.get(),
+            bytes_received: self.received.get(),
+            close_reason: match res {
+                Ok(()) => "closed".to_owned(),
+                Err(error) => error.to_string(),
+            },
+        }
+    }
+}
+
+/// What a subscription follows: a tag, or the agents matching a selector in
+/// a namespace.
+#[derive(Debug)]
+pub(crate) enum Target {
+    Tag(String),
+    Selector(Selector, Option<String>),
+}
+
+impl Target {
+    /// Whether a registry change of `tag` may change the agent to reach.
+    pub(crate) fn follows(&self, tag: &str) -> bool {
+        match self {
+            Target::Tag(target_tag) => target_tag == tag,
+            Target::Selector(..) => true,
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::Tag(tag) => f.write_str(tag),
+            Target::Selector(selector, namespace) => {
+                f.write_str(&qualify(namespace.as_deref(), &selector.to_string()))
+            }
+        }
+    }
+}
+
+/// A DM agent subscribed to the address of a tag or selector.
+#[derive(Debug)]
+pub(crate) struct Subscriber {
+    pub(crate) net_addr: SocketAddr,
+    pub(crate) target_tag: String,
+    pub(crate) created_at: SystemTime,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::RegisterInfo;
+    use crate::server::registry::{AgentDefinition, MemoryRegistry};
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+
+    fn state(settings: Settings) -> State {
+        let registry = Arc::new(MemoryRegistry::new());
+        State::new(registry, Heartbeat::default(), None, settings, None, None)
+    }
+
+    fn register(state: &State, tag: &str, ip: [u8; 4], token: Option<&str>) {
+        let register_info = RegisterInfo::new("server".to_owned(), tag.to_owned())
+            .with_token(token.map(str::to_owned));
+        let agent = ChannelInfo::new(
+            (ip, 4000).into(),
+            register_info,
+            mpsc::unbounded_channel().0,
+        );
+        state.register(Arc::new(agent)).unwrap();
+    }
+
+    fn connection(agent_tag: &str, token: Option<&str>) -> ConnectionInfo {
+        ConnectionInfo::new(
+            AgentMode::RM,
+            "id".to_owned(),
+            "agent1".to_owned(),
+            "127.0.0.1:8081".to_owned(),
+        )
+        .with_agent_tag(Some(agent_tag.to_owned()))
+        .with_token(token.map(str::to_owned))
+    }
+
+    fn acl_state() -> State {
+        let state = state(Settings {
+            acl: Acl::new().allow("agent1", ["agent2"]),
+            ..Default::default()
+        });
+        register(&state, "agent1", [10, 0, 0, 1], None);
+        register(&state, "agent2", [10, 0, 0, 2], None);
+        state
+    }
+
+    #[test]
+    fn claimed_tag_must_be_registered_from_the_same_ip() {
+        let state = acl_state();
+        let (connection_info, target) = state
+            .resolve_connection(connection("agent2", None), [10, 0, 0, 2].into())
+            .unwrap();
+        assert_eq!(target.tag(), "agent1");
+        assert_eq!(connection_info.get_target_tag(), "agent1");
+        let error = state
+            .resolve_connection(connection("agent2", None), [10, 0, 0, 9].into())
+            .unwrap_err();
+        assert_eq!(error, "agent2 is not registered from 10.0.0.9");
+    }
+
+    #[test]
+    fn acl_applies_to_registered_tags() {
+        let state = acl_state();
+        register(&state, "agent3", [10, 0, 0, 3], None);
+        let error = state
+            .resolve_connection(connection("agent3", None), [10, 0, 0, 3].into())
+            .unwrap_err();
+        assert_eq!(error, "agent3 may not reach agent1");
+    }
+
+    #[test]
+    fn subscriber_tag_must_be_registered_from_the_same_ip() {
+        let state = acl_state();
+        let subscribe_info =
+            SubscribeInfo::new("agent1".to_owned()).with_agent_tag(Some("agent2".to_owned()));
+        assert!(state
+            .resolve_subscribe(&subscribe_info, [10, 0, 0, 2].into())
+            .is_ok());
+        assert!(state
+            .resolve_subscribe(&subscribe_info, [10, 0, 0, 9].into())
+            .is_err());
+    }
+
+    #[test]
+    fn defined_tag_needs_its_token() {
+        let state = acl_state();
+        let mut definition = AgentDefinition::new("agent4");
+        definition.token = Some("s3cret".to_owned());
+        state.registry.define(definition).unwrap();
+        register(&state, "agent4", [10, 0, 0, 4], Some("s3cret"));
+        let ip = [10, 0, 0, 4].into();
+        let error = state
+            .resolve_connection(connection("agent4", Some("wrong")), ip)
+            .unwrap_err();
+        assert_eq!(error, "invalid token for agent4");
+        // Reaches agent1 only if the ACL lets it, but the token is fine.
+        let error = state
+            .resolve_connection(connection("agent4", Some("s3cret")), ip)
+            .unwrap_err();
+        assert_eq!(error, "agent4 may not reach agent1");
+    }
+
+    #[tokio::test]
+    async fn drain_ends_with_the_last_tunnel() {
+        let state = Arc::new(acl_state());
+        let tunnel = Tunnel::new(&connection("agent2", None), ([10, 0, 0, 2], 4000).into(), 0);
+        let id = tunnel.id.clone();
+        state.open_tunnel(Arc::new(tunnel)).unwrap();
+        state.drain();
+        let closing = state.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            closing.close_tunnel(&id);
+        });
+        // Done long before any drain timeout, though the agents stay registered.
+        let drained = tokio::time::timeout(Duration::from_secs(5), state.tunnels_closed()).await;
+        assert!(drained.is_ok());
+        assert_eq!(state.registry.members("agent1").len(), 1);
+    }
+}
@@ -19,6 +19,13 @@ impl Shutdown {
         self.shutdown
     }
 
+    pub(crate) fn resubscribe(&self) -> Shutdown {
+        Shutdown {
+            shutdown: self.shutdown,
+            notify: self.notify.resubscribe(),
+        }
+    }
+
     pub(crate) fn _shutdown(&mut self) {
         self.shutdown = true;
     }
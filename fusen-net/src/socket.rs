@@ -1,4 +1,7 @@
-use tokio::net::TcpStream;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use tokio::net::{TcpListener, TcpStream};
 
 pub async fn get_tcp_stream(ip: String, port: u16) -> Result<TcpStream, crate::Error> {
     let addr = format!("{}:{}", ip, port);
@@ -8,3 +11,103 @@ pub async fn get_tcp_stream(ip: String, port: u16) -> Result<TcpStream, crate::E
 pub async fn get_tcp_stream_by_host(host: String) -> Result<TcpStream, crate::Error> {
     Ok(TcpStream::connect(host).await?)
 }
+
+/// Resolves `addr`, a socket address or `host:port`, preferring IPv4 like
+/// the rest of the network usually does.
+pub async fn resolve(addr: &str) -> Result<SocketAddr, crate::Error> {
+    if let Ok(addr) = addr.parse() {
+        return Ok(addr);
+    }
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host(addr).await?.collect();
+    addrs
+        .iter()
+        .find(|addr| addr.is_ipv4())
+        .or(addrs.first())
+        .copied()
+        .ok_or_else(|| format!("no address for {}", addr).into())
+}
+
+/// Joins `host` and `port`, putting IPv6 hosts in brackets.
+pub fn host_port(host: &str, port: &str) -> String {
+    if host.contains(':') && !host.starts_with('[') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+/// Turns IPv4-mapped IPv6 addresses, as seen on dual-stack sockets, back into
+/// IPv4 ones.
+pub fn canonical(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+/// The wildcard address of the family used to reach `addr`.
+pub fn unspecified_for(addr: &SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    }
+}
+
+/// Binds a UDP socket on `addr`. `[::]` accepts IPv4 as well, falling back
+/// to `0.0.0.0` on hosts without IPv6.
+pub fn bind_udp(addr: SocketAddr) -> io::Result<UdpSocket> {
+    if !is_dual_stack(&addr) {
+        return UdpSocket::bind(addr);
+    }
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP)).and_then(|socket| {
+        socket.set_only_v6(false)?;
+        socket.bind(&addr.into())?;
+        Ok(socket)
+    });
+    match socket {
+        Ok(socket) => Ok(socket.into()),
+        Err(_) => UdpSocket::bind((Ipv4Addr::UNSPECIFIED, addr.port())),
+    }
+}
+
+/// Binds a TCP listener on `addr`, dual-stack like [`bind_udp`].
+pub async fn bind_tcp(addr: &str) -> io::Result<TcpListener> {
+    let addr: SocketAddr = match addr.parse() {
+        Ok(addr) if is_dual_stack(&addr) => addr,
+        _ => return TcpListener::bind(addr).await,
+    };
+    let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP)).and_then(|socket| {
+        socket.set_only_v6(false)?;
+        socket.set_reuse_address(true)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&addr.into())?;
+        socket.listen(1024)?;
+        Ok(socket)
+    });
+    match socket {
+        Ok(socket) => TcpListener::from_std(socket.into()),
+        Err(_) => TcpListener::bind((Ipv4Addr::UNSPECIFIED, addr.port())).await,
+    }
+}
+
+fn is_dual_stack(addr: &SocketAddr) -> bool {
+    addr.ip() == IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+}
+
+/// The global IPv6 address this host would use to reach the internet, if it
+/// has one. Nothing is sent: connecting a UDP socket only picks a route.
+pub fn global_ipv6() -> Option<Ipv6Addr> {
+    let socket = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).ok()?;
+    socket.connect("[2001:4860:4860::8888]:53").ok()?;
+    match socket.local_addr().ok()?.ip() {
+        IpAddr::V6(ip) if is_global(&ip) => Some(ip),
+        _ => None,
+    }
+}
+
+fn is_global(ip: &Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    !ip.is_loopback()
+        && !ip.is_unspecified()
+        && ip.to_ipv4_mapped().is_none()
+        // link-local fe80::/10 and unique local fc00::/7
+        && first & 0xffc0 != 0xfe80
+        && first & 0xfe00 != 0xfc00
+}
@@ -0,0 +1,151 @@
+use crate::mux::{self, MuxConnection, Verified};
+use crate::socket::{bind_tcp, canonical, resolve};
+use crate::tls::{self, Identity, Verify};
+use crate::transport::{Connecting, Connection, Listener, Transport};
+use crate::ws::WsStream;
+use futures::future::BoxFuture;
+use std::net::SocketAddr;
+use std::time::Duration;
+use support::{make_client_connector, make_server_acceptor};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::TlsAcceptor;
+use tracing::warn;
+pub mod support;
+
+/// Whether a TCP session to `addr` is currently open. Sessions are kept by
+/// resolved address, so host names are resolved first.
+pub async fn is_connected(addr: &str) -> bool {
+    resolve(addr)
+        .await
+        .is_ok_and(|addr| mux::is_connected(&addr.to_string()))
+}
+
+/// TLS over TCP, with the streams of a connection multiplexed by yamux.
+#[derive(Default)]
+pub struct TcpTransport {
+    identity: Option<Identity>,
+    verify: Verify,
+}
+
+impl TcpTransport {
+    /// Listens presenting `identity` instead of a self-signed certificate.
+    pub fn with_identity(identity: Identity) -> Self {
+        TcpTransport {
+            identity: Some(identity),
+            ..Default::default()
+        }
+    }
+
+    /// Verifies the servers it dials with `verify` rather than the system's CAs.
+    pub fn with_verify(mut self, verify: Verify) -> Self {
+        self.verify = verify;
+        self
+    }
+}
+
+impl Transport for TcpTransport {
+    fn listen<'a>(
+        &'a self,
+        addr: &'a str,
+    ) -> BoxFuture<'a, Result<Box<dyn Listener>, crate::Error>> {
+        Box::pin(async move {
+            let listener = TlsListener::bind(addr, false, self.identity.as_ref()).await?;
+            Ok(Box::new(listener) as Box<dyn Listener>)
+        })
+    }
+
+    fn dial<'a>(
+        &'a self,
+        addr: &'a str,
+    ) -> BoxFuture<'a, Result<Box<dyn Connection>, crate::Error>> {
+        Box::pin(async move {
+            let target_host = resolve(addr).await?;
+            let key = target_host.to_string();
+            let server_name = tls::server_name(addr);
+            let verified = Verified::new(&self.verify, server_name);
+            if let Some(connection) = mux::session(&key, &verified) {
+                return Ok(Box::new(connection) as Box<dyn Connection>);
+            }
+            let tcp_stream = TcpStream::connect(target_host).await?;
+            tcp_stream.set_nodelay(true)?;
+            let tls_stream = make_client_connector(&self.verify)
+                .connect(server_name.try_into()?, tcp_stream)
+                .await?;
+            let connection = mux::start_session(key, verified, tls_stream, target_host);
+            Ok(Box::new(connection) as Box<dyn Connection>)
+        })
+    }
+}
+
+/// Accepts TLS connections, upgrading them to WebSocket first when `websocket`
+/// is set.
+pub(crate) struct TlsListener {
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    websocket: bool,
+}
+
+impl TlsListener {
+    pub(crate) async fn bind(
+        addr: &str,
+        websocket: bool,
+        identity: Option<&Identity>,
+    ) -> Result<Self, crate::Error> {
+        Ok(TlsListener {
+            listener: bind_tcp(addr).await?,
+            acceptor: make_server_acceptor(identity)?,
+            websocket,
+        })
+    }
+}
+
+impl Listener for TlsListener {
+    fn local_addr(&self) -> Result<SocketAddr, crate::Error> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    fn accept(&mut self) -> BoxFuture<'_, Result<Connecting, crate::Error>> {
+        Box::pin(async move {
+            let (tcp_stream, socket_addr) = loop {
+                match self.listener.accept().await {
+                    Ok((tcp_stream, socket_addr)) => break (tcp_stream, canonical(socket_addr)),
+                    Err(error) => {
+                        // Out of file descriptors, say: give others a moment to close.
+                        warn!("tcp accept err : {:?}", error);
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                    }
+                }
+            };
+            let acceptor = self.acceptor.clone();
+            let websocket = self.websocket;
+            let connecting: Connecting = Box::pin(async move {
+                tcp_stream.set_nodelay(true)?;
+                let tls_stream = acceptor.accept(tcp_stream).await?;
+                let connection = if websocket {
+                    let ws_stream = tokio_tungstenite::accept_async(tls_stream).await?;
+                    MuxConnection::server(WsStream::new(ws_stream), socket_addr)
+                } else {
+                    MuxConnection::server(tls_stream, socket_addr)
+                };
+                Ok(Box::new(connection) as Box<dyn Connection>)
+            });
+            Ok(connecting)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn host_names_find_sessions_to_their_address() {
+        let (stream, _server) = tokio::io::duplex(1024);
+        let addr: SocketAddr = "127.0.0.1:7".parse().unwrap();
+        let verified = Verified::new(&Verify::insecure(), "127.0.0.1");
+        let _connection = mux::start_session(addr.to_string(), verified, stream, addr);
+        assert!(is_connected("127.0.0.1:7").await);
+        assert!(is_connected("localhost:7").await);
+        assert!(!is_connected("localhost:8").await);
+    }
+}
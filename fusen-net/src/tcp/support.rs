@@ -0,0 +1,24 @@
+//! TLS setup for the TCP transport.
+
+use crate::tls::{Identity, Verify};
+use std::sync::Arc;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+/// Builds a TLS acceptor presenting `identity`, or a freshly generated
+/// self-signed certificate without one.
+pub fn make_server_acceptor(identity: Option<&Identity>) -> Result<TlsAcceptor, crate::Error> {
+    let identity = match identity {
+        Some(identity) => identity.clone(),
+        None => Identity::self_signed()?,
+    };
+    let server_config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(identity.cert_chain, identity.key)?;
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+/// Builds a TLS connector verifying the server certificate with `verify`.
+pub fn make_client_connector(verify: &Verify) -> TlsConnector {
+    TlsConnector::from(Arc::new(verify.client_config()))
+}
@@ -0,0 +1,314 @@
+//! The certificate the server presents on its QUIC, TLS and wss listeners, and
+//! how clients verify it.
+
+use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use rustls::{Certificate, RootCertStore, ServerName};
+use std::io::BufReader;
+use std::sync::{Arc, OnceLock};
+use std::time::SystemTime;
+
+/// A certificate chain with its private key. Without one the listeners make up
+/// a self-signed certificate, new on every start, which clients accept by
+/// its fingerprint, see [`Verify::with_pin`], or with [`Verify::insecure`].
+#[derive(Clone)]
+pub struct Identity {
+    pub(crate) cert_chain: Vec<Certificate>,
+    pub(crate) key: rustls::PrivateKey,
+}
+
+impl Identity {
+    pub fn self_signed() -> Result<Self, crate::Error> {
+        let cert = rcgen::generate_simple_self_signed(vec!["fusen-net".into()])?;
+        Ok(Identity {
+            cert_chain: vec![Certificate(cert.serialize_der()?)],
+            key: rustls::PrivateKey(cert.serialize_private_key_der()),
+        })
+    }
+
+    /// Reads a PEM certificate chain and a PEM private key (PKCS#8, PKCS#1 or SEC1).
+    pub fn from_pem(cert: &[u8], key: &[u8]) -> Result<Self, crate::Error> {
+        let cert_chain = certs_from_pem(cert)?;
+        let key = rustls_pemfile::read_all(&mut BufReader::new(key))?
+            .into_iter()
+            .find_map(|item| match item {
+                rustls_pemfile::Item::PKCS8Key(key)
+                | rustls_pemfile::Item::RSAKey(key)
+                | rustls_pemfile::Item::ECKey(key) => Some(rustls::PrivateKey(key)),
+                _ => None,
+            })
+            .ok_or("no private key found")?;
+        Ok(Identity { cert_chain, key })
+    }
+
+    pub fn from_pem_files(cert_path: &str, key_path: &str) -> Result<Self, crate::Error> {
+        let cert = std::fs::read(cert_path)
+            .map_err(|error| format!("read {} err : {}", cert_path, error))?;
+        let key = std::fs::read(key_path)
+            .map_err(|error| format!("read {} err : {}", key_path, error))?;
+        Self::from_pem(&cert, &key)
+    }
+
+    /// The SHA-256 fingerprint of the certificate, for clients to pin.
+    pub fn fingerprint(&self) -> String {
+        fingerprint(&self.cert_chain[0])
+    }
+}
+
+/// How a client verifies the certificate of the server it dials: by default
+/// against the system's CAs, for the host it dials.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Verify {
+    cas: Vec<Certificate>,
+    pins: Vec<[u8; 32]>,
+    insecure: bool,
+}
+
+impl Verify {
+    /// Accepts any certificate, so the token goes to whoever answers. Only
+    /// for servers with a self-signed certificate on a trusted network.
+    pub fn insecure() -> Self {
+        Verify {
+            insecure: true,
+            ..Default::default()
+        }
+    }
+
+    /// Trusts the CAs of `identity` and its very certificate, as presented by
+    /// servers sharing the identity or its CA.
+    pub fn identity(identity: &Identity) -> Self {
+        Verify {
+            cas: identity.cert_chain[1..].to_vec(),
+            pins: vec![digest(&identity.cert_chain[0])],
+            insecure: false,
+        }
+    }
+
+    /// Trusts the CAs in the PEM `cert` instead of the system's.
+    pub fn with_ca_pem(mut self, cert: &[u8]) -> Result<Self, crate::Error> {
+        self.cas.extend(certs_from_pem(cert)?);
+        Ok(self)
+    }
+
+    pub fn with_ca_file(self, path: &str) -> Result<Self, crate::Error> {
+        let cert = std::fs::read(path).map_err(|error| format!("read {} err : {}", path, error))?;
+        self.with_ca_pem(&cert)
+    }
+
+    /// Accepts the certificate with the SHA-256 `fingerprint`, hex with or
+    /// without `:`, whoever issued it and whatever host it is for. With pins
+    /// and no CAs, only the pinned certificates are accepted.
+    pub fn with_pin(mut self, fingerprint: &str) -> Result<Self, crate::Error> {
+        let hex: String = fingerprint.chars().filter(|c| *c != ':').collect();
+        let pin = (0..hex.len())
+            .step_by(2)
+            .map(|index| {
+                hex.get(index..index + 2)
+                    .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+            })
+            .collect::<Option<Vec<u8>>>()
+            .and_then(|pin| pin.try_into().ok())
+            .ok_or_else(|| format!("invalid sha-256 fingerprint : {}", fingerprint))?;
+        self.pins.push(pin);
+        Ok(self)
+    }
+
+    pub(crate) fn client_config(&self) -> rustls::ClientConfig {
+        rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(Arc::new(Verifier::new(self)))
+            .with_no_client_auth()
+    }
+}
+
+struct Verifier {
+    insecure: bool,
+    pins: Vec<[u8; 32]>,
+    /// Checks the chain and the host, unless only pins are trusted.
+    webpki: Option<WebPkiVerifier>,
+}
+
+impl Verifier {
+    fn new(verify: &Verify) -> Self {
+        let webpki = (!verify.cas.is_empty() || verify.pins.is_empty()).then(|| {
+            let mut roots = RootCertStore::empty();
+            if verify.cas.is_empty() {
+                roots.add_parsable_certificates(system_roots());
+            } else {
+                let cas: Vec<Vec<u8>> = verify.cas.iter().map(|ca| ca.0.clone()).collect();
+                roots.add_parsable_certificates(&cas);
+            }
+            WebPkiVerifier::new(roots, None)
+        });
+        Verifier {
+            insecure: verify.insecure,
+            pins: verify.pins.clone(),
+            webpki,
+        }
+    }
+}
+
+impl ServerCertVerifier for Verifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if self.insecure || self.pins.contains(&digest(end_entity)) {
+            return Ok(ServerCertVerified::assertion());
+        }
+        match &self.webpki {
+            Some(webpki) => webpki.verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                scts,
+                ocsp_response,
+                now,
+            ),
+            None => Err(rustls::Error::General(format!(
+                "certificate {} is not pinned",
+                fingerprint(end_entity)
+            ))),
+        }
+    }
+}
+
+/// The host of `addr` (`host:port` or a bare host) to check the certificate
+/// against.
+pub(crate) fn server_name(addr: &str) -> &str {
+    let host = match addr.rsplit_once(':') {
+        Some((host, port)) if port.parse::<u16>().is_ok() => host,
+        _ => addr,
+    };
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+fn system_roots() -> &'static [Vec<u8>] {
+    static ROOTS: OnceLock<Vec<Vec<u8>>> = OnceLock::new();
+    ROOTS.get_or_init(|| {
+        rustls_native_certs::load_native_certs()
+            .map(|certs| certs.into_iter().map(|cert| cert.0).collect())
+            .unwrap_or_default()
+    })
+}
+
+fn certs_from_pem(cert: &[u8]) -> Result<Vec<Certificate>, crate::Error> {
+    let certs: Vec<Certificate> = rustls_pemfile::certs(&mut BufReader::new(cert))?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    if certs.is_empty() {
+        return Err("no certificate found".into());
+    }
+    Ok(certs)
+}
+
+fn digest(cert: &Certificate) -> [u8; 32] {
+    let digest = ring::digest::digest(&ring::digest::SHA256, &cert.0);
+    digest.as_ref().try_into().expect("sha-256 is 32 bytes")
+}
+
+fn fingerprint(cert: &Certificate) -> String {
+    digest(cert)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(verify: &Verify, identity: &Identity, host: &str) -> bool {
+        Verifier::new(verify)
+            .verify_server_cert(
+                &identity.cert_chain[0],
+                &identity.cert_chain[1..],
+                &ServerName::try_from(host).unwrap(),
+                &mut std::iter::empty(),
+                &[],
+                SystemTime::now(),
+            )
+            .is_ok()
+    }
+
+    /// A CA and an identity it issued for `host`.
+    fn issued(host: &str) -> (Certificate, Identity) {
+        let mut params = rcgen::CertificateParams::new(vec![]);
+        params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        let ca = rcgen::Certificate::from_params(params).unwrap();
+        let leaf = rcgen::generate_simple_self_signed(vec![host.into()]).unwrap();
+        let identity = Identity {
+            cert_chain: vec![Certificate(leaf.serialize_der_with_signer(&ca).unwrap())],
+            key: rustls::PrivateKey(leaf.serialize_private_key_der()),
+        };
+        (Certificate(ca.serialize_der().unwrap()), identity)
+    }
+
+    #[test]
+    fn pinned_certificate_is_accepted_for_any_host() {
+        let identity = Identity::self_signed().unwrap();
+        let verify = Verify::default().with_pin(&identity.fingerprint()).unwrap();
+        assert!(check(&verify, &identity, "127.0.0.1"));
+        assert!(!check(
+            &verify,
+            &Identity::self_signed().unwrap(),
+            "127.0.0.1"
+        ));
+    }
+
+    #[test]
+    fn self_signed_certificate_needs_a_pin_or_insecure() {
+        let identity = Identity::self_signed().unwrap();
+        assert!(!check(&Verify::default(), &identity, "fusen-net"));
+        assert!(check(&Verify::insecure(), &identity, "fusen-net"));
+    }
+
+    #[test]
+    fn ca_verifies_the_host() {
+        let (ca, identity) = issued("relay.example.com");
+        let verify = Verify {
+            cas: vec![ca],
+            ..Default::default()
+        };
+        assert!(check(&verify, &identity, "relay.example.com"));
+        assert!(!check(&verify, &identity, "other.example.com"));
+        let (_, stranger) = issued("relay.example.com");
+        assert!(!check(&verify, &stranger, "relay.example.com"));
+    }
+
+    #[test]
+    fn identity_trusts_itself() {
+        let identity = Identity::self_signed().unwrap();
+        assert!(check(&Verify::identity(&identity), &identity, "127.0.0.1"));
+        let other = Identity::self_signed().unwrap();
+        assert!(!check(&Verify::identity(&identity), &other, "127.0.0.1"));
+    }
+
+    #[test]
+    fn pins_parse_with_or_without_colons() {
+        let identity = Identity::self_signed().unwrap();
+        let fingerprint = identity.fingerprint();
+        assert_eq!(fingerprint.len(), 32 * 3 - 1);
+        let bare = fingerprint.replace(':', "").to_uppercase();
+        assert_eq!(
+            Verify::default().with_pin(&bare).unwrap().pins,
+            vec![digest(&identity.cert_chain[0])]
+        );
+        assert!(Verify::default().with_pin("ab:cd").is_err());
+        assert!(Verify::default().with_pin(&format!("{}zz", bare)).is_err());
+    }
+
+    #[test]
+    fn server_name_is_the_host() {
+        assert_eq!(server_name("relay.example.com:8089"), "relay.example.com");
+        assert_eq!(server_name("[::1]:8089"), "::1");
+        assert_eq!(server_name("127.0.0.1:8089"), "127.0.0.1");
+        assert_eq!(server_name("relay.example.com"), "relay.example.com");
+    }
+}
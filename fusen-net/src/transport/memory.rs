@@ -0,0 +1,187 @@
+//! An in-memory transport, for running a server and its clients in one process
+//! without any sockets.
+
+use super::{Connecting, Connection, Listener, Transport};
+use crate::buffer::StreamBuffer;
+use futures::future::BoxFuture;
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+const STREAM_BUFFER_SIZE: usize = 64 * 1024;
+
+#[derive(Default)]
+struct Network {
+    listeners: HashMap<SocketAddr, UnboundedSender<MemoryConnection>>,
+    next_port: u16,
+}
+
+impl Network {
+    fn allocate_port(&mut self) -> u16 {
+        loop {
+            self.next_port = self.next_port.wrapping_add(1).max(10000);
+            let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, self.next_port));
+            if !self.listeners.contains_key(&addr) {
+                return self.next_port;
+            }
+        }
+    }
+
+    /// Maps `addr` to the address it is known by on the network, loopback for
+    /// unspecified ips and a fresh port for port 0.
+    fn resolve(&mut self, addr: &str) -> Result<SocketAddr, crate::Error> {
+        let mut addr: SocketAddr = addr.parse()?;
+        if addr.ip().is_unspecified() {
+            addr.set_ip(Ipv4Addr::LOCALHOST.into());
+        }
+        if addr.port() == 0 {
+            addr.set_port(self.allocate_port());
+        }
+        Ok(addr)
+    }
+}
+
+/// A host on an in-memory network. Clones are the same host, [`Self::host`]
+/// adds another one to the network. Like the QUIC transport, a host that
+/// listens dials from its listening address.
+#[derive(Clone, Default)]
+pub struct MemoryTransport {
+    network: Arc<Mutex<Network>>,
+    local_addr: Arc<Mutex<Option<SocketAddr>>>,
+}
+
+impl MemoryTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn host(&self) -> Self {
+        MemoryTransport {
+            network: self.network.clone(),
+            local_addr: Default::default(),
+        }
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn listen<'a>(
+        &'a self,
+        addr: &'a str,
+    ) -> BoxFuture<'a, Result<Box<dyn Listener>, crate::Error>> {
+        Box::pin(async move {
+            let mut network = self.network.lock().unwrap();
+            let addr = network.resolve(addr)?;
+            if network.listeners.contains_key(&addr) {
+                return Err(format!("address in use : {}", addr).into());
+            }
+            let (sender, receiver) = mpsc::unbounded_channel();
+            network.listeners.insert(addr, sender);
+            self.local_addr.lock().unwrap().get_or_insert(addr);
+            let listener = MemoryListener {
+                network: self.network.clone(),
+                local_addr: addr,
+                receiver,
+            };
+            Ok(Box::new(listener) as Box<dyn Listener>)
+        })
+    }
+
+    fn dial<'a>(
+        &'a self,
+        addr: &'a str,
+    ) -> BoxFuture<'a, Result<Box<dyn Connection>, crate::Error>> {
+        Box::pin(async move {
+            let mut network = self.network.lock().unwrap();
+            let target_addr = network.resolve(addr)?;
+            let local_addr = match *self.local_addr.lock().unwrap() {
+                Some(local_addr) => local_addr,
+                None => network.resolve("127.0.0.1:0")?,
+            };
+            let listener = network
+                .listeners
+                .get(&target_addr)
+                .ok_or(format!("connection refused : {}", target_addr))?;
+            let (local, remote) = MemoryConnection::pair(local_addr, target_addr);
+            listener
+                .send(remote)
+                .map_err(|_| format!("connection refused : {}", target_addr))?;
+            Ok(Box::new(local) as Box<dyn Connection>)
+        })
+    }
+}
+
+struct MemoryListener {
+    network: Arc<Mutex<Network>>,
+    local_addr: SocketAddr,
+    receiver: UnboundedReceiver<MemoryConnection>,
+}
+
+impl Listener for MemoryListener {
+    fn local_addr(&self) -> Result<SocketAddr, crate::Error> {
+        Ok(self.local_addr)
+    }
+
+    fn accept(&mut self) -> BoxFuture<'_, Result<Connecting, crate::Error>> {
+        Box::pin(async move {
+            let connection = self.receiver.recv().await.ok_or("listener closed")?;
+            let connecting: Connecting =
+                Box::pin(async move { Ok(Box::new(connection) as Box<dyn Connection>) });
+            Ok(connecting)
+        })
+    }
+}
+
+impl Drop for MemoryListener {
+    fn drop(&mut self) {
+        self.network
+            .lock()
+            .unwrap()
+            .listeners
+            .remove(&self.local_addr);
+    }
+}
+
+struct MemoryConnection {
+    remote_addr: SocketAddr,
+    sender: UnboundedSender<StreamBuffer>,
+    receiver: UnboundedReceiver<StreamBuffer>,
+}
+
+impl MemoryConnection {
+    fn pair(addr1: SocketAddr, addr2: SocketAddr) -> (Self, Self) {
+        let (sender1, receiver1) = mpsc::unbounded_channel();
+        let (sender2, receiver2) = mpsc::unbounded_channel();
+        let connection1 = MemoryConnection {
+            remote_addr: addr2,
+            sender: sender1,
+            receiver: receiver2,
+        };
+        let connection2 = MemoryConnection {
+            remote_addr: addr1,
+            sender: sender2,
+            receiver: receiver1,
+        };
+        (connection1, connection2)
+    }
+}
+
+impl Connection for MemoryConnection {
+    fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+
+    fn open_stream(&self) -> BoxFuture<'_, Result<StreamBuffer, crate::Error>> {
+        Box::pin(async move {
+            let (stream1, stream2) = tokio::io::duplex(STREAM_BUFFER_SIZE);
+            self.sender
+                .send(StreamBuffer::from_stream(stream2))
+                .map_err(|_| "connection closed")?;
+            Ok(StreamBuffer::from_stream(stream1))
+        })
+    }
+
+    fn accept_stream(&mut self) -> BoxFuture<'_, Result<Option<StreamBuffer>, crate::Error>> {
+        Box::pin(async move { Ok(self.receiver.recv().await) })
+    }
+}
@@ -0,0 +1,112 @@
+use crate::buffer::StreamBuffer;
+use crate::quic::QuicTransport;
+use crate::tcp::TcpTransport;
+use crate::tls::Verify;
+use crate::ws::WsTransport;
+use crate::FusenFuture;
+use futures::future::BoxFuture;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tracing::debug;
+pub mod memory;
+
+/// A handshake that completes into a connection, returned by
+/// [`Listener::accept`] so that slow handshakes don't hold up the accept loop.
+pub type Connecting = FusenFuture<Result<Box<dyn Connection>, crate::Error>>;
+
+/// The way server and clients reach each other. Addresses are transport
+/// specific, e.g. `ip:port` for QUIC and TCP or a `wss://` url for WebSocket.
+pub trait Transport: Send + Sync {
+    fn listen<'a>(
+        &'a self,
+        addr: &'a str,
+    ) -> BoxFuture<'a, Result<Box<dyn Listener>, crate::Error>>;
+
+    fn dial<'a>(
+        &'a self,
+        addr: &'a str,
+    ) -> BoxFuture<'a, Result<Box<dyn Connection>, crate::Error>>;
+
+    /// Dials the DM listener of another agent rather than a server.
+    fn dial_agent<'a>(
+        &'a self,
+        addr: &'a str,
+    ) -> BoxFuture<'a, Result<Box<dyn Connection>, crate::Error>> {
+        self.dial(addr)
+    }
+}
+
+pub trait Listener: Send {
+    fn local_addr(&self) -> Result<SocketAddr, crate::Error>;
+
+    fn accept(&mut self) -> BoxFuture<'_, Result<Connecting, crate::Error>>;
+}
+
+pub trait Connection: Send + Sync {
+    fn remote_addr(&self) -> SocketAddr;
+
+    fn open_stream(&self) -> BoxFuture<'_, Result<StreamBuffer, crate::Error>>;
+
+    /// Waits for the next stream opened by the peer, `None` once the
+    /// connection is closed.
+    fn accept_stream(&mut self) -> BoxFuture<'_, Result<Option<StreamBuffer>, crate::Error>>;
+}
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// The client transport: `wss://` urls go over WebSocket, socket addresses over
+/// QUIC, falling back to TCP when the QUIC handshake doesn't complete. Once a
+/// TCP session to an address exists, later dials go straight to it.
+#[derive(Default)]
+pub struct DefaultTransport {
+    quic: QuicTransport,
+    tcp: TcpTransport,
+    ws: WsTransport,
+}
+
+impl DefaultTransport {
+    /// Verifies the servers it dials with `verify` rather than the system's CAs.
+    pub fn with_verify(self, verify: Verify) -> Self {
+        DefaultTransport {
+            quic: self.quic.with_verify(verify.clone()),
+            tcp: self.tcp.with_verify(verify.clone()),
+            ws: self.ws.with_verify(verify),
+        }
+    }
+}
+
+impl Transport for DefaultTransport {
+    fn listen<'a>(
+        &'a self,
+        addr: &'a str,
+    ) -> BoxFuture<'a, Result<Box<dyn Listener>, crate::Error>> {
+        self.quic.listen(addr)
+    }
+
+    fn dial<'a>(
+        &'a self,
+        addr: &'a str,
+    ) -> BoxFuture<'a, Result<Box<dyn Connection>, crate::Error>> {
+        Box::pin(async move {
+            if crate::ws::is_websocket_url(addr) {
+                return self.ws.dial(addr).await;
+            }
+            if !crate::tcp::is_connected(addr).await {
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, self.quic.dial(addr)).await {
+                    Ok(Ok(connection)) => return Ok(connection),
+                    Ok(Err(error)) => debug!("quic connect fail : {:?}, fallback to tcp", error),
+                    Err(_) => debug!("quic connect time out, fallback to tcp"),
+                }
+            }
+            self.tcp.dial(addr).await
+        })
+    }
+
+    /// Agents only listen over QUIC.
+    fn dial_agent<'a>(
+        &'a self,
+        addr: &'a str,
+    ) -> BoxFuture<'a, Result<Box<dyn Connection>, crate::Error>> {
+        self.quic.dial_agent(addr)
+    }
+}
@@ -0,0 +1,206 @@
+use crate::mux::{self, Verified};
+use crate::tcp::support::make_client_connector;
+use crate::tcp::TlsListener;
+use crate::tls::{Identity, Verify};
+use crate::transport::{Connection, Listener, Transport};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use bytes::{Buf, Bytes};
+use futures::future::BoxFuture;
+use futures::{ready, Sink, Stream};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::{http::Uri, Message};
+use tokio_tungstenite::WebSocketStream;
+
+pub fn is_websocket_url(addr: &str) -> bool {
+    addr.starts_with("wss://")
+}
+
+/// WebSocket (wss) over TLS, with the streams of a connection multiplexed by yamux.
+/// Dials tunnel through the HTTP proxy `proxy` (`http://[user:password@]host:port`)
+/// with `CONNECT`, or the one from `HTTPS_PROXY` when not set.
+#[derive(Default)]
+pub struct WsTransport {
+    proxy: Option<String>,
+    identity: Option<Identity>,
+    verify: Verify,
+}
+
+impl WsTransport {
+    pub fn with_proxy(proxy: &str) -> Self {
+        WsTransport {
+            proxy: Some(proxy.to_owned()),
+            ..Default::default()
+        }
+    }
+
+    /// Listens presenting `identity` instead of a self-signed certificate.
+    pub fn with_identity(identity: Identity) -> Self {
+        WsTransport {
+            identity: Some(identity),
+            ..Default::default()
+        }
+    }
+
+    /// Verifies the servers it dials with `verify` rather than the system's CAs.
+    pub fn with_verify(mut self, verify: Verify) -> Self {
+        self.verify = verify;
+        self
+    }
+}
+
+impl Transport for WsTransport {
+    fn listen<'a>(
+        &'a self,
+        addr: &'a str,
+    ) -> BoxFuture<'a, Result<Box<dyn Listener>, crate::Error>> {
+        Box::pin(async move {
+            let listener = TlsListener::bind(addr, true, self.identity.as_ref()).await?;
+            Ok(Box::new(listener) as Box<dyn Listener>)
+        })
+    }
+
+    fn dial<'a>(
+        &'a self,
+        url: &'a str,
+    ) -> BoxFuture<'a, Result<Box<dyn Connection>, crate::Error>> {
+        Box::pin(async move {
+            let uri: Uri = url.parse()?;
+            if uri.scheme_str() != Some("wss") {
+                return Err(format!("not websocket url : {}", url).into());
+            }
+            let host = uri.host().ok_or("websocket url without host")?;
+            let server_name = host.trim_start_matches('[').trim_end_matches(']');
+            let verified = Verified::new(&self.verify, server_name);
+            if let Some(connection) = mux::session(url, &verified) {
+                return Ok(Box::new(connection) as Box<dyn Connection>);
+            }
+            let proxy = self.proxy.clone().or_else(|| {
+                ["HTTPS_PROXY", "https_proxy"]
+                    .iter()
+                    .find_map(|key| std::env::var(key).ok())
+                    .filter(|proxy| !proxy.is_empty())
+            });
+            let port = uri.port_u16().unwrap_or(443);
+            let authority = format!("{}:{}", host, port);
+            let tcp_stream = match proxy {
+                Some(proxy) => http_connect(&proxy, &authority).await?,
+                None => TcpStream::connect(&authority).await?,
+            };
+            tcp_stream.set_nodelay(true)?;
+            let remote_addr = tcp_stream.peer_addr()?;
+            let tls_stream = make_client_connector(&self.verify)
+                .connect(server_name.try_into()?, tcp_stream)
+                .await?;
+            let (ws_stream, _) = tokio_tungstenite::client_async(url, tls_stream).await?;
+            let connection = mux::start_session(
+                url.to_owned(),
+                verified,
+                WsStream::new(ws_stream),
+                remote_addr,
+            );
+            Ok(Box::new(connection) as Box<dyn Connection>)
+        })
+    }
+}
+
+async fn http_connect(proxy: &str, authority: &str) -> Result<TcpStream, crate::Error> {
+    let proxy: Uri = proxy.parse()?;
+    let proxy_authority = proxy.authority().ok_or("proxy url without host")?;
+    let proxy_host = format!(
+        "{}:{}",
+        proxy_authority.host(),
+        proxy_authority.port_u16().unwrap_or(80)
+    );
+    let mut stream = TcpStream::connect(proxy_host).await?;
+    let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", authority);
+    if let Some((userinfo, _)) = proxy_authority.as_str().rsplit_once('@') {
+        request.push_str(&format!(
+            "Proxy-Authorization: Basic {}\r\n",
+            BASE64_STANDARD.encode(userinfo)
+        ));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+    // Read the response head byte by byte so nothing after it gets consumed.
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() > 8 * 1024 {
+            return Err("proxy response too large".into());
+        }
+        head.push(stream.read_u8().await?);
+    }
+    let head = String::from_utf8_lossy(&head);
+    let status_line = head.lines().next().unwrap_or_default();
+    if status_line.split_whitespace().nth(1) != Some("200") {
+        return Err(format!("proxy connect fail : {}", status_line).into());
+    }
+    Ok(stream)
+}
+
+/// Exposes the binary messages of a WebSocket as a byte stream.
+pub struct WsStream<S> {
+    inner: WebSocketStream<S>,
+    read_buf: Bytes,
+}
+
+impl<S> WsStream<S> {
+    pub fn new(inner: WebSocketStream<S>) -> Self {
+        WsStream {
+            inner,
+            read_buf: Bytes::new(),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WsStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            if !self.read_buf.is_empty() {
+                let len = self.read_buf.len().min(buf.remaining());
+                buf.put_slice(&self.read_buf[..len]);
+                self.read_buf.advance(len);
+                return Poll::Ready(Ok(()));
+            }
+            match ready!(Pin::new(&mut self.inner).poll_next(cx)) {
+                Some(Ok(Message::Binary(data))) => self.read_buf = data,
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                Some(Ok(_)) => continue,
+                Some(Err(error)) => return Poll::Ready(Err(io::Error::other(error))),
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WsStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        ready!(Pin::new(&mut self.inner).poll_ready(cx)).map_err(io::Error::other)?;
+        Pin::new(&mut self.inner)
+            .start_send(Message::Binary(Bytes::copy_from_slice(buf)))
+            .map_err(io::Error::other)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner)
+            .poll_flush(cx)
+            .map_err(io::Error::other)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner)
+            .poll_close(cx)
+            .map_err(io::Error::other)
+    }
+}